@@ -4,22 +4,56 @@ use crossterm::{
     queue,
     terminal::{Clear, ClearType, DisableLineWrap, EnableLineWrap},
 };
+use ropey::Rope;
 use std::{
     env, fs,
     io::{self, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
 /// Core application logic, state, and text editing structures.
 pub mod core {
     use super::*;
+    use self::fileformat::{Encoding, FileFormat, LineEnding};
+    use similar::TextDiff;
+    use self::history::{Edit, EditKind, History};
+    use self::save::{DiskStamp, DiskStatus, SaveOptions};
+    use self::clipboard::{Clipboard, ClipboardMode};
+    use self::keymap::{Key, Keymap, Lookup, Scope};
+    use self::registers::{Register, Registers, UNNAMED};
+    use self::search::{Match, SearchOptions, Substitute};
+    use self::settings::Settings;
+    use self::syntax::{DEFAULT_PAIRS, Highlighter, Registry, Span};
+    use self::theme::{ColorSupport, Theme};
 
-    // Zipper remains unchanged as its logic for line editing is solid.
+    /// How often modified buffers are copied to their swap files.
+    const SWAP_INTERVAL: Duration = Duration::from_secs(4);
+
+    /// The most hits `:grep` collects before giving up.
+    const GREP_LIMIT: usize = 1000;
+
+    /// How many lines of the highlighted file the file picker previews.
+    const PREVIEW_LINES: usize = 200;
+
+    /// Rows taken by the file picker's list, title included; the preview
+    /// gets the rest of the editor area.
+    pub fn picker_list_height(term_height: u16) -> u16 {
+        term_height.saturating_sub(2) / 2
+    }
+
+    /// A single line being edited, split at the cursor.
     pub struct Zipper {
         before: Vec<char>,
         after: Vec<char>,
     }
 
+    impl Default for Zipper {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
     impl Zipper {
         pub fn new() -> Self {
             Zipper {
@@ -28,13 +62,6 @@ pub mod core {
             }
         }
 
-        pub fn from_str(text: &str) -> Self {
-            Zipper {
-                before: Vec::new(),
-                after: text.chars().rev().collect(),
-            }
-        }
-
         pub fn move_left(&mut self) {
             if let Some(c) = self.before.pop() {
                 self.after.push(c);
@@ -66,1019 +93,7997 @@ pub mod core {
             self.before = before.to_vec();
             self.after = after.iter().rev().cloned().collect();
         }
+    }
+
+    impl std::str::FromStr for Zipper {
+        type Err = std::convert::Infallible;
+
+        fn from_str(text: &str) -> Result<Self, Self::Err> {
+            Ok(Zipper {
+                before: Vec::new(),
+                after: text.chars().rev().collect(),
+            })
+        }
+    }
 
-        pub fn to_string(&self) -> String {
+    impl std::fmt::Display for Zipper {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
             let mut result = String::new();
             result.extend(self.before.iter());
             result.extend(self.after.iter().rev());
-            result
+            f.write_str(&result)
         }
     }
 
     /// Represents the state of a single open file buffer (a "tab").
+    ///
+    /// The text lives in a rope so that cursor movement, edits and viewport
+    /// reads cost O(log n) regardless of file size. Lines are separated by
+    /// `\n` only; the cursor is a (row, column) pair counted in chars.
+    /// Every change goes through `perform`, which records it in `history`.
     pub struct Page {
-        pub before: Vec<String>,
-        pub current: Zipper,
-        pub after: Vec<String>,
+        text: Rope,
+        cursor_row: usize,
+        cursor_col: usize,
+        /// The fixed end of the selection, if there is one; the cursor is
+        /// the end that moves. Any edit clears it.
+        anchor: Option<(usize, usize)>,
+        /// Whether the selection covers whole lines.
+        linewise: bool,
+        /// The display column vertical moves aim for, kept while moving
+        /// through lines too short to reach it.
+        desired_col: Option<usize>,
+        history: History,
+        saved_revision: u64,
+        saved_format: FileFormat,
+        pub format: FileFormat,
+        /// Set for buffers whose contents could not be decoded as text.
+        pub read_only: bool,
+        /// The file as it was on disk when last loaded or saved.
+        pub disk_stamp: Option<DiskStamp>,
+        /// Name shown for buffers not backed by a file, such as diffs.
+        pub title: Option<String>,
+        /// The revision last written to this buffer's swap file.
+        pub swap_revision: Option<u64>,
+        /// Highlighting for the buffer's language, if it has a known one.
+        pub syntax: Option<Highlighter>,
         pub file_path: Option<PathBuf>,
         pub scroll_offset: usize,
         pub horizontal_scroll_offset: usize,
+        /// The width lines are wrapped to when soft wrap is on. Up and Down
+        /// then move by screen rows.
+        pub wrap_width: Option<usize>,
+        /// Cells between tab stops, from the `tab_width` setting.
+        pub tab_width: usize,
+    }
+
+    impl Default for Page {
+        fn default() -> Self {
+            Self::new()
+        }
     }
 
     impl Page {
         pub fn new() -> Self {
             Page {
-                before: Vec::new(),
-                current: Zipper::new(),
-                after: Vec::new(),
+                text: Rope::new(),
+                cursor_row: 0,
+                cursor_col: 0,
+                anchor: None,
+                linewise: false,
+                desired_col: None,
+                history: History::new(),
+                saved_revision: 0,
+                saved_format: FileFormat::default(),
+                format: FileFormat::default(),
+                read_only: false,
+                disk_stamp: None,
+                title: None,
+                swap_revision: None,
+                syntax: None,
                 file_path: None,
                 scroll_offset: 0,
                 horizontal_scroll_offset: 0,
+                wrap_width: None,
+                tab_width: 4,
             }
         }
 
-        pub fn from_file(path: Option<PathBuf>) -> Self {
+        /// Opens the file at `path`. A missing file yields an empty buffer
+        /// that will be created on save; binary content opens read-only.
+        pub fn open(path: PathBuf) -> io::Result<Self> {
             let mut page = Self::new();
-            page.file_path = path;
-            if let Some(p) = &page.file_path {
-                if let Ok(contents) = fs::read_to_string(p) {
-                    page.load_from_string(&contents);
+            match fs::read(&path) {
+                Ok(bytes) => {
+                    let decoded = fileformat::decode(&bytes);
+                    page.load_from_string(&decoded.text);
+                    page.format.encoding = decoded.encoding;
+                    page.read_only = decoded.binary;
+                    page.disk_stamp = DiskStamp::capture(&path, &bytes);
+                    page.mark_saved();
                 }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+            page.file_path = Some(path);
+            Ok(page)
+        }
+
+        /// Re-reads the buffer's file from disk as a single undoable step.
+        pub fn reload(&mut self) -> io::Result<()> {
+            let Some(path) = self.file_path.clone() else {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "buffer has no file"));
+            };
+            let bytes = fs::read(&path)?;
+            let decoded = fileformat::decode(&bytes);
+            self.read_only = false;
+            self.replace_contents(&decoded.text);
+            self.format.encoding = decoded.encoding;
+            self.read_only = decoded.binary;
+            self.disk_stamp = DiskStamp::capture(&path, &bytes);
+            self.mark_saved();
+            Ok(())
+        }
+
+        /// Whether the file has been changed by something else since it was
+        /// last loaded or saved.
+        pub fn disk_status(&self) -> DiskStatus {
+            match (&self.file_path, &self.disk_stamp) {
+                (Some(path), Some(stamp)) => stamp.status(path),
+                _ => DiskStatus::Unchanged,
             }
-            page
         }
 
+        /// True if the text or file format differs from what was last
+        /// loaded or saved. Undoing back to that point makes it false again.
+        pub fn is_modified(&self) -> bool {
+            self.history.revision() != self.saved_revision || self.format != self.saved_format
+        }
+
+        /// Identifies the current text; see `History::revision`.
+        pub fn revision(&self) -> u64 {
+            self.history.revision()
+        }
+
+        /// Records the current state as matching the file on disk.
+        pub fn mark_saved(&mut self) {
+            self.history.break_group();
+            self.saved_revision = self.history.revision();
+            self.saved_format = self.format;
+        }
+
+        /// The file name shown in tabs and prompts.
+        pub fn display_name(&self) -> String {
+            self.file_path
+                .as_ref()
+                .and_then(|p| p.file_name())
+                .map(|f| f.to_string_lossy().to_string())
+                .or_else(|| self.title.clone())
+                .unwrap_or_else(|| "[No Name]".to_string())
+        }
+
+        /// The label drawn for this page in the tab bar, with a `*` marker
+        /// when it has unsaved changes.
+        pub fn tab_title(&self) -> String {
+            let marker = if self.is_modified() { "*" } else { "" };
+            format!(" {}{} ", self.display_name(), marker)
+        }
+
+        /// Replaces the buffer with `contents` and forgets all undo history.
         pub fn load_from_string(&mut self, contents: &str) {
-            let mut lines: Vec<String> = contents.lines().map(String::from).collect();
-            if lines.is_empty() {
-                self.before = Vec::new();
-                self.current = Zipper::new();
-                self.after = Vec::new();
-            } else {
-                self.current = Zipper::from_str(&lines.remove(0));
-                self.before = Vec::new();
-                self.after = lines;
+            let (format, body) = FileFormat::detect(contents);
+            self.format = format;
+            self.text = Rope::from_str(&body);
+            self.cursor_row = 0;
+            self.cursor_col = 0;
+            self.anchor = None;
+            self.history.clear();
+            if let Some(syntax) = &self.syntax {
+                syntax.invalidate_from(0);
             }
         }
 
-        pub fn move_up(&mut self) {
-            if !self.before.is_empty() {
-                let cursor_pos = self.current.cursor_position();
-                let prev_line = self.before.pop().unwrap();
-                self.after.insert(0, self.current.to_string());
-                self.current = Zipper::from_str(&prev_line);
-                self.current.set_cursor_position(cursor_pos);
+        /// Replaces the buffer with `contents` as a single undoable step.
+        pub fn replace_contents(&mut self, contents: &str) {
+            let (format, text) = FileFormat::detect(contents);
+            self.replace_text(&text);
+            self.format = format;
+        }
+
+        /// Replaces the buffer with `new_text`, which is already in buffer
+        /// form, as a single undoable step. The file format is kept.
+        pub fn replace_text(&mut self, new_text: &str) {
+            let cursor = self.cursor();
+            self.history.begin_transaction(cursor);
+            let old_text = self.text();
+            if !old_text.is_empty() {
+                self.perform(
+                    Edit::Delete { at: 0, text: old_text },
+                    EditKind::Other,
+                    (0, 0),
+                );
+            }
+            if !new_text.is_empty() {
+                self.perform(
+                    Edit::Insert { at: 0, text: new_text.to_string() },
+                    EditKind::Other,
+                    (0, 0),
+                );
             }
+            self.history.end_transaction();
+            self.move_cursor_to(cursor.0, cursor.1);
         }
 
-        pub fn move_down(&mut self) {
-            if !self.after.is_empty() {
-                let cursor_pos = self.current.cursor_position();
-                let next_line = self.after.remove(0);
-                self.before.push(self.current.to_string());
-                self.current = Zipper::from_str(&next_line);
-                self.current.set_cursor_position(cursor_pos);
+        /// Replaces the chars `start..end` with `text`, leaving the cursor
+        /// after the inserted text.
+        pub fn replace_range(&mut self, start: usize, end: usize, text: &str) {
+            self.begin_transaction();
+            if end > start {
+                let removed = self.text.slice(start..end).to_string();
+                let at = self.char_to_pos(start);
+                self.perform(Edit::Delete { at: start, text: removed }, EditKind::Other, at);
+            }
+            if !text.is_empty() {
+                let (row, col) = self.char_to_pos(start);
+                let after = match text.rsplit_once('\n') {
+                    Some((before, last)) => (row + before.matches('\n').count() + 1, last.chars().count()),
+                    None => (row, col + text.chars().count()),
+                };
+                self.perform(
+                    Edit::Insert { at: start, text: text.to_string() },
+                    EditKind::Other,
+                    after,
+                );
             }
+            self.end_transaction();
         }
 
-        pub fn insert_newline(&mut self) {
-            let current_line = self.current.to_string();
-            let (left, right) = current_line.split_at(self.current.cursor_position());
-            self.current = Zipper::from_str(left);
-            self.after.insert(0, right.to_string());
-            self.move_down();
-            self.current.set_cursor_position(0);
+        /// Groups all edits until the matching `end_transaction` into one undo step.
+        pub fn begin_transaction(&mut self) {
+            let cursor = self.cursor();
+            self.history.begin_transaction(cursor);
         }
 
-        pub fn delete(&mut self) {
-            if self.current.cursor_position() == 0 && !self.before.is_empty() {
-                let prev_line = self.before.pop().unwrap();
-                let prev_line_len = prev_line.len();
-                let current_line = self.current.to_string();
-                let merged_line = prev_line + &current_line;
-                self.current = Zipper::from_str(&merged_line);
-                self.current.set_cursor_position(prev_line_len);
-            } else {
-                self.current.delete();
+        pub fn end_transaction(&mut self) {
+            self.history.end_transaction();
+        }
+
+        /// Returns the whole buffer as a single string, lines joined by `\n`.
+        pub fn text(&self) -> String {
+            self.text.to_string()
+        }
+
+        /// Returns the bytes to write to disk, restoring the encoding, line
+        /// endings, final newline and BOM the buffer was loaded with.
+        pub fn to_file_bytes(&self) -> io::Result<Vec<u8>> {
+            if self.read_only {
+                return Err(io::Error::new(
+                    io::ErrorKind::PermissionDenied,
+                    "buffer is read-only",
+                ));
             }
+            self.format.encode(&self.text())
         }
 
-        pub fn move_cursor_to(&mut self, row: usize, col: usize) {
-            let mut lines = self.get_all_lines();
-            let target_row = row.min(lines.len().saturating_sub(1));
+        pub fn line_count(&self) -> usize {
+            self.text.len_lines()
+        }
 
-            let after_lines = lines.split_off(target_row + 1);
-            let current_line = lines.pop().unwrap_or_default();
-            let before_lines = lines;
+        /// The syntax highlighting of `row`, empty without a language.
+        pub fn highlights(&self, row: usize) -> Vec<Span> {
+            match &self.syntax {
+                Some(syntax) => syntax.highlight(row, |r| self.line(r)),
+                None => Vec::new(),
+            }
+        }
 
-            self.before = before_lines;
-            self.after = after_lines;
-            self.current = Zipper::from_str(&current_line);
+        /// Returns the contents of `row` without its line terminator.
+        pub fn line(&self, row: usize) -> String {
+            if row >= self.line_count() {
+                return String::new();
+            }
+            let mut line = self.text.line(row).to_string();
+            if line.ends_with('\n') {
+                line.pop();
+            }
+            line
+        }
 
-            self.current.set_cursor_position(col);
+        /// Iterates over every line in the buffer, without line terminators.
+        pub fn lines(&self) -> impl Iterator<Item = String> + '_ {
+            (0..self.line_count()).map(move |row| self.line(row))
         }
 
-        pub fn get_all_lines(&self) -> Vec<String> {
-            let mut lines = self.before.clone();
-            lines.push(self.current.to_string());
-            lines.extend(self.after.clone());
-            lines
+        /// Length of `row` in chars, excluding the line terminator.
+        pub fn line_len(&self, row: usize) -> usize {
+            if row >= self.line_count() {
+                return 0;
+            }
+            let line = self.text.line(row);
+            let len = line.len_chars();
+            if len > 0 && line.char(len - 1) == '\n' {
+                len - 1
+            } else {
+                len
+            }
         }
 
         pub fn cursor_row(&self) -> usize {
-            self.before.len()
+            self.cursor_row
         }
-    }
 
-    /// Represents the state of the file tree view.
-    pub struct DirectoryView {
-        pub path: PathBuf,
-        pub entries: Vec<fs::DirEntry>,
-        pub selected_index: usize,
-        pub scroll_offset: usize,
-    }
+        pub fn cursor_col(&self) -> usize {
+            self.cursor_col
+        }
 
-    impl DirectoryView {
-        pub fn new(path: PathBuf) -> io::Result<Self> {
-            let mut entries = fs::read_dir(&path)?.filter_map(Result::ok).collect::<Vec<_>>();
-            entries.sort_by_key(|a| {
-                (
-                    !a.path().is_dir(),
-                    a.path().file_name().unwrap_or_default().to_ascii_lowercase(),
-                )
-            });
-            Ok(Self {
-                path,
-                entries,
-                selected_index: 0,
-                scroll_offset: 0,
-            })
+        pub fn cursor(&self) -> (usize, usize) {
+            (self.cursor_row, self.cursor_col)
+        }
+
+        /// Display column of the cursor on screen, before horizontal scrolling.
+        pub fn cursor_display_col(&self) -> usize {
+            text::display_col(&self.line(self.cursor_row), self.cursor_col, self.tab_width)
+        }
+
+        /// Absolute char index of the cursor within the rope.
+        fn cursor_char_index(&self) -> usize {
+            self.text.line_to_char(self.cursor_row) + self.cursor_col
+        }
+
+        /// Converts a (row, column) pair into an absolute char index.
+        pub fn pos_to_char(&self, row: usize, col: usize) -> usize {
+            let row = row.min(self.line_count() - 1);
+            self.text.line_to_char(row) + col.min(self.line_len(row))
+        }
+
+        /// Converts an absolute char index into a (row, column) pair.
+        pub fn char_to_pos(&self, idx: usize) -> (usize, usize) {
+            let idx = idx.min(self.text.len_chars());
+            let row = self.text.char_to_line(idx);
+            (row, idx - self.text.line_to_char(row))
+        }
+
+        /// Applies `edit` to the rope, records it for undo and leaves the
+        /// cursor at `cursor_after`.
+        fn perform(&mut self, edit: Edit, kind: EditKind, cursor_after: (usize, usize)) {
+            if self.read_only {
+                return;
+            }
+            let cursor_before = self.cursor();
+            self.anchor = None;
+            self.desired_col = None;
+            self.apply(&edit);
+            self.cursor_row = cursor_after.0;
+            self.cursor_col = cursor_after.1;
+            self.history.record(edit, kind, cursor_before, cursor_after);
+        }
+
+        fn apply(&mut self, edit: &Edit) {
+            if let Some(syntax) = &self.syntax {
+                let at = match edit {
+                    Edit::Insert { at, .. } | Edit::Delete { at, .. } => *at,
+                };
+                syntax.invalidate_from(self.text.char_to_line(at));
+            }
+            match edit {
+                Edit::Insert { at, text } => self.text.insert(*at, text),
+                Edit::Delete { at, text } => {
+                    self.text.remove(*at..*at + text.chars().count())
+                }
+            }
+        }
+
+        /// Reverts the most recent undo step. Returns false if there was none.
+        pub fn undo(&mut self) -> bool {
+            let Some(transaction) = self.history.undo() else {
+                return false;
+            };
+            self.anchor = None;
+            for edit in transaction.edits.iter().rev() {
+                self.apply(&edit.inverse());
+            }
+            let (row, col) = transaction.cursor_before;
+            self.move_cursor_to(row, col);
+            true
+        }
+
+        /// Re-applies the most recently undone step. Returns false if there was none.
+        pub fn redo(&mut self) -> bool {
+            let Some(transaction) = self.history.redo() else {
+                return false;
+            };
+            self.anchor = None;
+            for edit in &transaction.edits {
+                self.apply(edit);
+            }
+            let (row, col) = transaction.cursor_after;
+            self.move_cursor_to(row, col);
+            true
+        }
+
+        pub fn move_left(&mut self) {
+            self.history.break_group();
+            self.desired_col = None;
+            self.cursor_col = text::prev_boundary(&self.line(self.cursor_row), self.cursor_col);
+        }
+
+        pub fn move_right(&mut self) {
+            self.history.break_group();
+            self.desired_col = None;
+            self.cursor_col = text::next_boundary(&self.line(self.cursor_row), self.cursor_col);
         }
 
         pub fn move_up(&mut self) {
-            self.selected_index = self.selected_index.saturating_sub(1);
+            if self.cursor_row > 0 {
+                self.move_vertically_to(self.cursor_row - 1);
+            }
         }
 
         pub fn move_down(&mut self) {
-            if !self.entries.is_empty() {
-                self.selected_index = (self.selected_index + 1).min(self.entries.len() - 1);
+            if self.cursor_row + 1 < self.line_count() {
+                self.move_vertically_to(self.cursor_row + 1);
             }
         }
-    }
 
-    /// Global application modes.
-    #[derive(PartialEq, Eq, Clone, Copy)]
-    pub enum Mode {
-        Command,
-        Edit,
-        FileTree,
-        PromptSave,
-        PromptSaveAndQuit,
-        Find,
-        ConfirmDelete,
-        PromptNewFile,
-        PromptNewDirectory,
-        PromptRename,
-    }
+        /// Char columns at which the screen rows of `row` start.
+        pub fn wrap_starts(&self, row: usize) -> Vec<usize> {
+            match self.wrap_width {
+                Some(width) => text::wrap_starts(&self.line(row), width, self.tab_width),
+                None => vec![0],
+            }
+        }
 
-    /// The currently focused UI pane.
-    #[derive(PartialEq, Eq)]
-    pub enum ActivePane {
-        FileTree,
-        Editor,
-    }
+        /// How many screen rows `row` takes.
+        pub fn visual_rows(&self, row: usize) -> usize {
+            self.wrap_starts(row).len()
+        }
 
-    /// The main struct holding all application state.
-    pub struct App {
-        pub tabs: Vec<Page>,
-        pub active_tab_index: usize,
-        pub directory_view: DirectoryView,
-        pub active_pane: ActivePane,
-        pub mode: Mode,
-        pub command_buffer: String,
-        pub status_message: String,
-        pub should_quit: bool,
-        pub find_query: String,
-        pub find_matches: Vec<(usize, usize)>,
-        pub current_match_index: usize,
-        pub path_to_delete: Option<PathBuf>,
-        pub path_to_rename: Option<PathBuf>,
-        pub find_navigation_active: bool,
-    }
+        /// The screen row of the cursor within its line, and its display
+        /// column within that row.
+        pub fn cursor_visual(&self) -> (usize, usize) {
+            let line = self.line(self.cursor_row);
+            let starts = self.wrap_starts(self.cursor_row);
+            let sub_row = text::wrap_row(&starts, self.cursor_col);
+            let segment = text::slice_cols(&line, starts[sub_row], self.cursor_col);
+            (sub_row, text::line_width(segment, self.tab_width))
+        }
 
-    impl App {
-        pub fn new(initial_path: Option<PathBuf>) -> io::Result<Self> {
-            let directory_view = DirectoryView::new(env::current_dir()?)?;
-            let mut tabs = Vec::new();
-            let mut active_pane = ActivePane::FileTree;
-            let mut mode = Mode::FileTree;
+        /// Moves the cursor to whatever is drawn at display column
+        /// `display_col` of screen row `sub_row` of `row`.
+        pub fn move_cursor_to_visual(&mut self, row: usize, sub_row: usize, display_col: usize) {
+            let row = row.min(self.line_count().saturating_sub(1));
+            let line = self.line(row);
+            let starts = self.wrap_starts(row);
+            let sub_row = sub_row.min(starts.len() - 1);
+            let end = starts.get(sub_row + 1).copied().unwrap_or(usize::MAX);
+            let segment = text::slice_cols(&line, starts[sub_row], end);
+            let mut col = starts[sub_row] + text::col_at_display(segment, display_col, self.tab_width);
+            // Past the end of a wrapped row, stay on its last grapheme.
+            if col >= end {
+                col = text::prev_boundary(&line, end);
+            }
+            self.move_cursor_to(row, col);
+        }
 
-            if let Some(path) = initial_path {
-                tabs.push(Page::from_file(Some(path)));
-                active_pane = ActivePane::Editor;
-                mode = Mode::Edit; // Default to Edit mode when opening a file from CLI
+        /// The line and screen row within it drawn `screen_row` rows below
+        /// the top of the view, or the last row of the last line.
+        pub fn line_at_screen_row(&self, screen_row: usize) -> (usize, usize) {
+            let mut remaining = screen_row;
+            let last = self.line_count().saturating_sub(1);
+            for row in self.scroll_offset..=last {
+                let rows = self.visual_rows(row);
+                if remaining < rows || row == last {
+                    return (row, remaining.min(rows - 1));
+                }
+                remaining -= rows;
             }
-            // If no path, tabs vec remains empty, showing the logo.
+            (last, 0)
+        }
 
-            Ok(Self {
-                tabs,
-                active_tab_index: 0,
-                directory_view,
-                active_pane,
-                mode,
-                command_buffer: String::new(),
-                status_message: String::new(),
-                should_quit: false,
-                find_query: String::new(),
-                find_matches: Vec::new(),
-                current_match_index: 0,
-                path_to_delete: None,
-                path_to_rename: None,
-                find_navigation_active: false,
-            })
+        /// Scrolls so that the cursor's screen row is at least `margin` rows
+        /// inside a view `height` rows tall, counting wrapped rows.
+        pub fn scroll_wrapped_to_cursor(&mut self, height: usize, margin: usize) {
+            let (sub_row, _) = self.cursor_visual();
+            let row = self.cursor_row;
+            // Every line takes at least one row, so lines further up than
+            // the view is tall are off screen anyway.
+            self.scroll_offset = self.scroll_offset.clamp(row.saturating_sub(height), row);
+            let mut above =
+                sub_row + (self.scroll_offset..row).map(|r| self.visual_rows(r)).sum::<usize>();
+            while above < margin && self.scroll_offset > 0 {
+                self.scroll_offset -= 1;
+                above += self.visual_rows(self.scroll_offset);
+            }
+            let mut below = self.visual_rows(row) - sub_row - 1;
+            let mut next = row + 1;
+            while below < margin && next < self.line_count() {
+                below += self.visual_rows(next);
+                next += 1;
+            }
+            let below = below.min(margin);
+            while above + 1 + below > height && self.scroll_offset < row {
+                above -= self.visual_rows(self.scroll_offset);
+                self.scroll_offset += 1;
+            }
         }
 
-        /// Central event handler for the entire application.
-        pub fn handle_event(&mut self, event: Event, term_width: u16, term_height: u16) {
-            self.status_message.clear();
-            match event {
-                Event::Key(key_event) => self.handle_key_event(key_event, term_width, term_height),
-                Event::Mouse(mouse_event) => {
-                    self.handle_mouse_event(mouse_event, term_width, term_height)
+        /// Moves one screen row up or down through wrapped lines, aiming
+        /// for the desired display column within the row.
+        fn move_visually(&mut self, down: bool) {
+            let (sub_row, display_col) = self.cursor_visual();
+            let display_col = self.desired_col.unwrap_or(display_col);
+            let row = self.cursor_row;
+            let target = if down {
+                if sub_row + 1 < self.visual_rows(row) {
+                    (row, sub_row + 1)
+                } else if row + 1 < self.line_count() {
+                    (row + 1, 0)
+                } else {
+                    return;
                 }
-                _ => {}
-            }
+            } else if sub_row > 0 {
+                (row, sub_row - 1)
+            } else if row > 0 {
+                (row - 1, self.visual_rows(row - 1) - 1)
+            } else {
+                return;
+            };
+            self.history.break_group();
+            self.move_cursor_to_visual(target.0, target.1, display_col);
+            self.desired_col = Some(display_col);
         }
 
-        fn handle_mouse_event(&mut self, event: MouseEvent, term_width: u16, term_height: u16) {
-            let MouseEvent { kind, column, row, .. } = event;
-            let file_tree_width = (term_width as f32 * 0.25).round() as u16;
+        /// Moves to `row`, aiming for the desired display column.
+        fn move_vertically_to(&mut self, row: usize) {
+            let display_col = self.desired_col.unwrap_or_else(|| self.cursor_display_col());
+            self.move_cursor_to_display(row, display_col);
+            self.desired_col = Some(display_col);
+        }
 
-            match kind {
-                MouseEventKind::ScrollUp => {
-                    if column < file_tree_width {
-                        self.directory_view.scroll_offset =
-                            self.directory_view.scroll_offset.saturating_sub(1);
-                    } else if let Some(page) = self.get_active_page() {
-                        page.scroll_offset = page.scroll_offset.saturating_sub(1);
+        /// Where `motion` would put the cursor, as a (row, column) pair.
+        /// Vertical motions are resolved by `apply_motion` instead, since
+        /// they depend on the desired column; here they keep the column.
+        pub fn motion_target(&self, motion: Motion) -> (usize, usize) {
+            let (row, col) = self.cursor();
+            let last_row = self.line_count() - 1;
+            let line = self.line(row);
+            match motion {
+                Motion::Left => (row, text::prev_boundary(&line, col)),
+                Motion::Right => (row, text::next_boundary(&line, col)),
+                Motion::Up => (row.saturating_sub(1), col),
+                Motion::Down => ((row + 1).min(last_row), col),
+                Motion::PageUp(n) => (row.saturating_sub(n), col),
+                Motion::PageDown(n) => ((row + n).min(last_row), col),
+                Motion::WordForward => self.char_to_pos(self.word_forward()),
+                Motion::WordBackward => self.char_to_pos(self.word_backward()),
+                Motion::WordEnd => self.char_to_pos(self.word_end()),
+                Motion::LineStart => (row, 0),
+                Motion::LineEnd => (row, self.line_len(row)),
+                Motion::FirstNonBlank => (row, text::first_non_blank(&line)),
+                Motion::Home => {
+                    let first = text::first_non_blank(&line);
+                    (row, if col == first { 0 } else { first })
+                }
+                Motion::ParagraphForward => {
+                    let mut r = row;
+                    while r < last_row && self.is_blank(r) {
+                        r += 1;
+                    }
+                    while r < last_row && !self.is_blank(r) {
+                        r += 1;
                     }
+                    if self.is_blank(r) { (r, 0) } else { (r, self.line_len(r)) }
                 }
-                MouseEventKind::ScrollDown => {
-                    if column < file_tree_width {
-                        let view_height = term_height.saturating_sub(2) as usize;
-                        if self.directory_view.entries.len() > view_height {
-                            self.directory_view.scroll_offset = (self.directory_view.scroll_offset + 1)
-                                .min(self.directory_view.entries.len() - view_height);
-                        }
-                    } else if let Some(page) = self.get_active_page() {
-                        let total_lines = page.get_all_lines().len();
-                        let view_height = term_height.saturating_sub(2) as usize;
-                        if total_lines > view_height {
-                            page.scroll_offset =
-                                (page.scroll_offset + 1).min(total_lines - view_height);
-                        }
+                Motion::ParagraphBackward => {
+                    let mut r = row;
+                    while r > 0 && self.is_blank(r) {
+                        r -= 1;
+                    }
+                    while r > 0 && !self.is_blank(r) {
+                        r -= 1;
                     }
+                    (r, 0)
                 }
-                MouseEventKind::Down(_) => {
-                    // 1. Check for File Tree Click
-                    if column < file_tree_width {
-                        self.active_pane = ActivePane::FileTree;
-                        self.mode = Mode::FileTree;
-                        // row 0 is header. Clicks are relative to view + scroll offset.
-                        let target_index = row.saturating_sub(1) as usize + self.directory_view.scroll_offset;
-                        if !self.directory_view.entries.is_empty() {
-                            let max_index = self.directory_view.entries.len().saturating_sub(1);
-                            self.directory_view.selected_index = target_index.min(max_index);
+                Motion::DocumentStart => (0, 0),
+                Motion::DocumentEnd => (last_row, self.line_len(last_row)),
+                Motion::MatchingBracket => {
+                    // As in Vim, only the first bracket ahead on the line is
+                    // tried, so this is a single scan to its partner.
+                    let ahead = || {
+                        let start = self.cursor_char_index();
+                        let end = self.pos_to_char(row, usize::MAX);
+                        let pairs = self.pairs();
+                        let offset = self.text.chars_at(start).take(end - start).position(|c| {
+                            pairs.iter().any(|&(open, close)| open != close && (c == open || c == close))
+                        })?;
+                        self.bracket_partner(start + offset)
+                    };
+                    match self.matching_bracket().map(|(_, partner)| partner).or_else(ahead) {
+                        Some(partner) => self.char_to_pos(partner),
+                        None => (row, col),
+                    }
+                }
+            }
+        }
+
+        /// Moves the cursor by `motion`. Vertical motions keep aiming for the
+        /// desired column; page motions scroll the view along with the cursor.
+        pub fn apply_motion(&mut self, motion: Motion) {
+            match motion {
+                Motion::Up | Motion::Down if self.wrap_width.is_some() => {
+                    self.move_visually(motion == Motion::Down);
+                }
+                Motion::Up | Motion::Down | Motion::PageUp(_) | Motion::PageDown(_) => {
+                    let (row, _) = self.motion_target(motion);
+                    self.history.break_group();
+                    self.move_vertically_to(row);
+                    match motion {
+                        Motion::PageUp(n) => self.scroll_offset = self.scroll_offset.saturating_sub(n),
+                        Motion::PageDown(n) => {
+                            // Stop once the last line reaches the bottom of the view.
+                            let max_scroll = self.line_count().saturating_sub(n).max(self.scroll_offset);
+                            self.scroll_offset = (self.scroll_offset + n).min(max_scroll);
                         }
-                        return;
+                        _ => {}
                     }
+                }
+                _ => {
+                    let (row, col) = self.motion_target(motion);
+                    self.move_cursor_to(row, col);
+                }
+            }
+        }
 
-                    let editor_start_col = file_tree_width + 1;
-
-                    // 2. Check for Tab Bar Click
-                    if row == 0 && column >= editor_start_col && !self.tabs.is_empty() {
-                        let mut current_col = editor_start_col;
-                        for (i, page) in self.tabs.iter().enumerate() {
-                            let file_name = page
-                                .file_path
-                                .as_ref()
-                                .and_then(|p| p.file_name())
-                                .and_then(|f| f.to_str())
-                                .unwrap_or("[No Name]");
-                            let tab_text = format!(" {} ", file_name);
-                            let tab_width = tab_text.len() as u16;
-
-                            if column >= current_col && column < current_col + tab_width {
-                                self.active_tab_index = i;
-                                break;
-                            }
-                            current_col += tab_width;
-                        }
-                        return;
-                    }
+        fn is_blank(&self, row: usize) -> bool {
+            self.line(row).trim().is_empty()
+        }
 
-                    // 3. Check for Editor Content Click
-                    if row > 0 && column >= editor_start_col && !self.tabs.is_empty() {
-                        self.active_pane = ActivePane::Editor;
-                        self.mode = Mode::Edit;
+        fn class_at(&self, idx: usize) -> text::CharClass {
+            text::char_class(self.text.char(idx))
+        }
 
-                        if let Some(page) = self.get_active_page() {
-                            let line_gutter_width = page.get_all_lines().len().to_string().len() + 2;
-                            let adjusted_row = row.saturating_sub(1) as usize + page.scroll_offset;
-                            let adjusted_col = column
-                                .saturating_sub(editor_start_col + line_gutter_width as u16)
-                                as usize;
-                            page.move_cursor_to(adjusted_row, adjusted_col);
-                        }
+        /// Char index of the start of the next word, crossing lines.
+        fn word_forward(&self) -> usize {
+            let len = self.text.len_chars();
+            let mut i = self.cursor_char_index();
+            if i < len {
+                let class = self.class_at(i);
+                if class != text::CharClass::Whitespace {
+                    while i < len && self.class_at(i) == class {
+                        i += 1;
                     }
                 }
-                _ => {}
             }
+            while i < len && self.class_at(i) == text::CharClass::Whitespace {
+                i += 1;
+            }
+            i
         }
 
-        fn scroll_to_cursor(&mut self, term_width: u16, term_height: u16) {
-            let file_tree_width = (term_width as f32 * 0.25).round() as u16;
-            let view_height = term_height as usize;
+        /// Char index of the start of the current or previous word.
+        fn word_backward(&self) -> usize {
+            let mut i = self.cursor_char_index();
+            while i > 0 && self.class_at(i - 1) == text::CharClass::Whitespace {
+                i -= 1;
+            }
+            if i > 0 {
+                let class = self.class_at(i - 1);
+                while i > 0 && self.class_at(i - 1) == class {
+                    i -= 1;
+                }
+            }
+            i
+        }
 
-            match self.active_pane {
-                ActivePane::Editor => {
-                    if let Some(page) = self.get_active_page() {
-                        let cursor_row = page.cursor_row();
-                        let scroll_offset = page.scroll_offset;
-                        let editor_view_height = view_height.saturating_sub(2);
+        /// Char index of the last char of the current or next word.
+        fn word_end(&self) -> usize {
+            let len = self.text.len_chars();
+            let mut i = self.cursor_char_index() + 1;
+            while i < len && self.class_at(i) == text::CharClass::Whitespace {
+                i += 1;
+            }
+            if i < len {
+                let class = self.class_at(i);
+                while i + 1 < len && self.class_at(i + 1) == class {
+                    i += 1;
+                }
+            }
+            i.min(len)
+        }
 
-                        // Vertical scroll logic
-                        if cursor_row < scroll_offset {
-                            page.scroll_offset = cursor_row;
-                        } else if cursor_row >= scroll_offset + editor_view_height {
-                            page.scroll_offset = cursor_row - editor_view_height + 1;
-                        }
+        pub fn insert_char(&mut self, c: char) {
+            let at = self.cursor_char_index();
+            self.perform(
+                Edit::Insert { at, text: c.to_string() },
+                EditKind::Typing,
+                (self.cursor_row, self.cursor_col + 1),
+            );
+        }
 
-                        // Horizontal scroll logic
-                        let cursor_col = page.current.cursor_position();
-                        let h_scroll_offset = page.horizontal_scroll_offset;
-                        let line_gutter_width = page.get_all_lines().len().to_string().len() + 2;
-                        let editor_width = term_width.saturating_sub(file_tree_width).saturating_sub(1);
-                        let editor_text_area_width = editor_width.saturating_sub(line_gutter_width as u16) as usize;
+        pub fn insert_newline(&mut self) {
+            let at = self.cursor_char_index();
+            self.perform(
+                Edit::Insert { at, text: "\n".to_string() },
+                EditKind::Newline,
+                (self.cursor_row + 1, 0),
+            );
+        }
 
-                        if cursor_col < h_scroll_offset {
-                            page.horizontal_scroll_offset = cursor_col;
-                        } else if cursor_col >= h_scroll_offset + editor_text_area_width {
-                            page.horizontal_scroll_offset = cursor_col - editor_text_area_width + 1;
-                        }
-                    }
-                }
-                ActivePane::FileTree => {
-                    let selected_index = self.directory_view.selected_index;
-                    let scroll_offset = self.directory_view.scroll_offset;
-                    let file_tree_view_height = view_height.saturating_sub(2);
+        /// Breaks the line at the cursor, starting the new line with the
+        /// current line's indentation, plus `unit` when the text before the
+        /// cursor ends with `{`, `(` or `:`. Between an empty pair of
+        /// brackets, the closing one goes on a line of its own below.
+        /// Returns what was inserted.
+        pub fn insert_newline_indented(&mut self, unit: &str) -> String {
+            let line = self.line(self.cursor_row);
+            let before = text::slice_cols(&line, 0, self.cursor_col);
+            let indent = &before[..before.len() - before.trim_start_matches([' ', '\t']).len()];
+            let mut inserted = format!("\n{}", indent);
+            if before.trim_end().ends_with(['{', '(', ':']) {
+                inserted.push_str(unit);
+            }
+            let col = inserted.chars().count() - 1;
+            let prev = before.chars().next_back();
+            let next = line.chars().nth(self.cursor_col);
+            if let (Some(prev), Some(next)) = (prev, next)
+                && prev != next
+                && self.pairs().contains(&(prev, next))
+            {
+                inserted.push('\n');
+                inserted.push_str(indent);
+            }
+            let at = self.cursor_char_index();
+            self.perform(
+                Edit::Insert { at, text: inserted.clone() },
+                EditKind::Newline,
+                (self.cursor_row + 1, col),
+            );
+            inserted
+        }
 
-                    if selected_index < scroll_offset {
-                        self.directory_view.scroll_offset = selected_index;
-                    } else if selected_index >= scroll_offset + file_tree_view_height {
-                        self.directory_view.scroll_offset = selected_index - file_tree_view_height + 1;
-                    }
-                }
+        /// The brackets and quotes typed as pairs in this buffer.
+        pub fn pairs(&self) -> &[(char, char)] {
+            match &self.syntax {
+                Some(highlighter) => &highlighter.language.pairs,
+                None => DEFAULT_PAIRS,
             }
         }
 
-        fn handle_key_event(&mut self, event: KeyEvent, term_width: u16, term_height: u16) {
-            if self.mode == Mode::ConfirmDelete {
-                self.handle_delete_confirm_event(event.code);
+        /// Types `c`, keeping pairs balanced: a closing char is typed over
+        /// when it is already next, and an opening one gets its closer too
+        /// unless the text after the cursor would be swallowed. Quotes are
+        /// only doubled away from words, so `don't` stays as typed.
+        pub fn insert_paired(&mut self, c: char) {
+            let line = self.line(self.cursor_row);
+            let prev = self.cursor_col.checked_sub(1).and_then(|col| line.chars().nth(col));
+            let next = line.chars().nth(self.cursor_col);
+            let pairs = self.pairs();
+            if next == Some(c) && pairs.iter().any(|&(_, close)| close == c) {
+                self.move_cursor_to(self.cursor_row, self.cursor_col + 1);
+                return;
+            }
+            let Some(&(open, close)) = pairs.iter().find(|&&(open, _)| open == c) else {
+                self.insert_char(c);
+                return;
+            };
+            let next_free = next.is_none_or(|n| n.is_whitespace() || pairs.iter().any(|&(_, close)| close == n));
+            let prev_free = open != close || prev.is_none_or(|p| !p.is_alphanumeric() && p != c);
+            if !(next_free && prev_free) {
+                self.insert_char(c);
                 return;
             }
+            let at = self.cursor_char_index();
+            self.perform(
+                Edit::Insert { at, text: format!("{}{}", open, close) },
+                EditKind::Typing,
+                (self.cursor_row, self.cursor_col + 1),
+            );
+        }
 
-            if self.mode == Mode::PromptNewFile
-                || self.mode == Mode::PromptNewDirectory
-                || self.mode == Mode::PromptRename
-            {
-                self.handle_prompt_input_event(event.code);
+        /// Deletes an empty pair around the cursor, as in `(|)`, returning
+        /// whether there was one.
+        pub fn delete_pair(&mut self) -> bool {
+            let line = self.line(self.cursor_row);
+            let prev = self.cursor_col.checked_sub(1).and_then(|col| line.chars().nth(col));
+            let next = line.chars().nth(self.cursor_col);
+            let (Some(prev), Some(next)) = (prev, next) else {
+                return false;
+            };
+            if !self.pairs().contains(&(prev, next)) {
+                return false;
+            }
+            let at = self.cursor_char_index() - 1;
+            self.perform(
+                Edit::Delete { at, text: format!("{}{}", prev, next) },
+                EditKind::Deleting,
+                (self.cursor_row, self.cursor_col - 1),
+            );
+            true
+        }
+
+        /// Deletes the grapheme before the cursor, joining lines at column 0.
+        pub fn delete(&mut self) {
+            let idx = self.cursor_char_index();
+            if idx == 0 {
                 return;
             }
-            
-            match self.active_pane {
-                ActivePane::Editor => self.handle_editor_event(event),
-                ActivePane::FileTree => self.handle_file_tree_event(event.code),
+            let (start, kind) = if self.cursor_col > 0 {
+                let line = self.line(self.cursor_row);
+                let prev = text::prev_boundary(&line, self.cursor_col);
+                (idx - (self.cursor_col - prev), EditKind::Deleting)
+            } else {
+                (idx - 1, EditKind::MergeLines)
             };
-            self.scroll_to_cursor(term_width, term_height);
+            let removed = self.text.slice(start..idx).to_string();
+            let cursor_after = self.char_to_pos(start);
+            self.perform(
+                Edit::Delete { at: start, text: removed },
+                kind,
+                cursor_after,
+            );
         }
 
-        fn handle_file_tree_event(&mut self, key_code: KeyCode) {
-            match key_code {
-                // Navigation
-                KeyCode::Up | KeyCode::Char('k') => self.directory_view.move_up(),
-                KeyCode::Down | KeyCode::Char('j') => self.directory_view.move_down(),
+        /// Moves the cursor to char column `col` of `row`, clamped to the
+        /// buffer and snapped to a grapheme boundary.
+        pub fn move_cursor_to(&mut self, row: usize, col: usize) {
+            self.history.break_group();
+            self.desired_col = None;
+            self.cursor_row = row.min(self.line_count().saturating_sub(1));
+            let line = self.line(self.cursor_row);
+            self.cursor_col = text::snap_to_boundary(&line, col.min(self.line_len(self.cursor_row)));
+        }
 
-                // Actions that clear buffer
-                KeyCode::Left => {
-                    self.go_up_directory();
-                    self.command_buffer.clear();
-                },
-                KeyCode::Right | KeyCode::Char('l') => {
-                    self.open_selected_entry();
-                    self.command_buffer.clear();
-                },
-                KeyCode::Enter => {
-                    if self.command_buffer.is_empty() {
-                        self.open_selected_entry();
-                    } else {
-                        let cmd = self.command_buffer.clone();
-                        self.command_buffer.clear();
-                        match cmd.as_str() {
-                            "d" => self.prompt_for_delete(),
-                            "nf" => self.mode = Mode::PromptNewFile,
-                            "nd" => self.mode = Mode::PromptNewDirectory,
-                            "rn" => self.prompt_for_rename(),
-                            _ => {
-                                self.status_message = format!("Unknown command: {}", cmd);
-                            }
-                        }
-                    }
-                },
+        /// Moves the cursor to whatever is drawn at display column
+        /// `display_col` of `row`.
+        pub fn move_cursor_to_display(&mut self, row: usize, display_col: usize) {
+            let row = row.min(self.line_count().saturating_sub(1));
+            let col = text::col_at_display(&self.line(row), display_col, self.tab_width);
+            self.move_cursor_to(row, col);
+        }
 
-                // Mode Switching
-                KeyCode::Esc => {
-                    self.active_pane = ActivePane::Editor;
-                    self.mode = Mode::Command;
-                    self.command_buffer.clear();
-                },
-                KeyCode::Tab => {
-                    self.active_pane = ActivePane::Editor;
-                    if self.tabs.is_empty() {
-                        self.mode = Mode::Command;
-                    } else {
-                        self.mode = Mode::Edit;
-                    }
-                    self.command_buffer.clear();
-                },
-                
-                // Command Input
-                KeyCode::Char(c) => {
-                    self.command_buffer.push(c);
-                },
-                KeyCode::Backspace => {
-                    self.command_buffer.pop();
-                },
+        /// Starts a selection anchored at the cursor.
+        pub fn start_selection(&mut self, linewise: bool) {
+            self.anchor = Some(self.cursor());
+            self.linewise = linewise;
+        }
 
-                _ => {}
+        /// Starts a char-wise selection at the cursor unless one is already
+        /// active, so that the next cursor movement extends it.
+        pub fn extend_selection(&mut self) {
+            if self.anchor.is_none() {
+                self.start_selection(false);
             }
         }
 
+        pub fn clear_selection(&mut self) {
+            self.anchor = None;
+        }
 
-        fn prompt_for_delete(&mut self) {
-            if let Some(entry) = self.directory_view.entries.get(self.directory_view.selected_index) {
-                let path = entry.path();
-                self.path_to_delete = Some(path);
-                self.mode = Mode::ConfirmDelete;
+        pub fn has_selection(&self) -> bool {
+            self.anchor.is_some()
+        }
+
+        pub fn is_linewise_selection(&self) -> bool {
+            self.anchor.is_some() && self.linewise
+        }
+
+        /// First and last row touched by the selection.
+        pub fn selection_rows(&self) -> Option<(usize, usize)> {
+            let (anchor_row, _) = self.anchor?;
+            Some((anchor_row.min(self.cursor_row), anchor_row.max(self.cursor_row)))
+        }
+
+        /// The selected text as a char range `start..end` of the buffer. A
+        /// line-wise selection includes the line break after its last line.
+        pub fn selection_range(&self) -> Option<(usize, usize)> {
+            let anchor = self.anchor?;
+            if self.linewise {
+                let (first, last) = self.selection_rows()?;
+                let start = self.text.line_to_char(first);
+                let end = if last + 1 < self.line_count() {
+                    self.text.line_to_char(last + 1)
+                } else {
+                    self.text.len_chars()
+                };
+                return Some((start, end));
             }
+            let a = self.pos_to_char(anchor.0, anchor.1);
+            let b = self.cursor_char_index();
+            Some((a.min(b), a.max(b)))
         }
 
-        fn handle_delete_confirm_event(&mut self, key_code: KeyCode) {
-            match key_code {
-                KeyCode::Char('y') | KeyCode::Char('Y') => {
-                    if let Some(path) = self.path_to_delete.take() {
-                        let result = if path.is_dir() {
-                            fs::remove_dir_all(&path)
-                        } else {
-                            fs::remove_file(&path)
-                        };
+        /// The selected columns of `row` as a char range, for highlighting.
+        pub fn selection_on_row(&self, row: usize) -> Option<(usize, usize)> {
+            let (first, last) = self.selection_rows()?;
+            if row < first || row > last {
+                return None;
+            }
+            let (start, end) = self.selection_range()?;
+            let line_start = self.text.line_to_char(row);
+            let line_end = line_start + self.line_len(row);
+            Some((start.max(line_start) - line_start, end.min(line_end) - line_start))
+        }
 
-                        match result {
-                            Ok(_) => {
-                                self.status_message = format!("Deleted {}", path.display());
-                                self.tabs.retain(|page| {
-                                    if let Some(page_path) = &page.file_path {
-                                        !page_path.starts_with(&path)
-                                    } else {
-                                        true
-                                    }
-                                });
-                                if self.tabs.is_empty() {
-                                    self.mode = Mode::Command;
-                                    self.active_tab_index = 0;
-                                } else if self.active_tab_index >= self.tabs.len() {
-                                    self.active_tab_index = self.tabs.len() - 1;
-                                }
+        /// The selected text. Line-wise selections always end in a line break.
+        pub fn selected_text(&self) -> Option<String> {
+            let (start, end) = self.selection_range()?;
+            let mut selected = self.text.slice(start..end).to_string();
+            if self.linewise && !selected.ends_with('\n') {
+                selected.push('\n');
+            }
+            Some(selected)
+        }
+
+        /// Deletes the selection as one undo step and returns its text.
+        pub fn delete_selection(&mut self) -> Option<String> {
+            let selected = self.selected_text()?;
+            let (mut start, end) = self.selection_range()?;
+            let first_row = self.selection_rows()?.0;
+            // Removing the last lines of the buffer takes the line break
+            // before them too, so no empty line is left behind.
+            if self.linewise && end == self.text.len_chars() && start > 0 {
+                start -= 1;
+            }
+            self.replace_range(start, end, "");
+            if self.linewise {
+                self.move_cursor_to(first_row.min(self.line_count() - 1), 0);
+            }
+            Some(selected)
+        }
+
+        /// Inserts `text` at the cursor as one undo step, leaving the cursor
+        /// after it.
+        pub fn insert_text(&mut self, text: &str) {
+            let at = self.cursor_char_index();
+            self.replace_range(at, at, text);
+        }
+
+        /// Pastes `text` as one undo step. Line-wise text goes in as whole
+        /// lines above the cursor line, or below it if `after` is set;
+        /// char-wise text goes in at the cursor, or after the grapheme under
+        /// it if `after` is set.
+        pub fn paste(&mut self, text: &str, linewise: bool, after: bool) {
+            if !linewise {
+                if after {
+                    self.move_right();
+                }
+                self.insert_text(text);
+                return;
+            }
+            let lines = text.strip_suffix('\n').unwrap_or(text);
+            let row = self.cursor_row + usize::from(after);
+            if row < self.line_count() {
+                let at = self.text.line_to_char(row);
+                self.replace_range(at, at, &format!("{}\n", lines));
+            } else {
+                let at = self.text.len_chars();
+                self.replace_range(at, at, &format!("\n{}", lines));
+            }
+            self.move_cursor_to(row, 0);
+        }
+
+        /// Selects the chars `start..end` of the buffer, leaving the cursor
+        /// at `start`.
+        pub fn select_chars(&mut self, start: usize, end: usize) {
+            self.anchor = Some(self.char_to_pos(end));
+            (self.cursor_row, self.cursor_col) = self.char_to_pos(start);
+            self.linewise = false;
+        }
+
+        /// Selects rows `first..=last` line-wise, leaving the cursor on `first`.
+        pub fn select_rows(&mut self, first: usize, last: usize) {
+            let last = last.min(self.line_count() - 1);
+            let first = first.min(last);
+            self.anchor = Some((last, 0));
+            self.cursor_row = first;
+            self.cursor_col = self.cursor_col.min(self.line_len(first));
+            self.linewise = true;
+        }
 
-                                let current_dir = self.directory_view.path.clone();
-                                if let Ok(new_view) = DirectoryView::new(current_dir) {
-                                    self.directory_view = new_view;
+        /// Selects `object` around the cursor. Returns false if there is no
+        /// such object there.
+        pub fn select_object(&mut self, object: vim::TextObject) -> bool {
+            let row = self.cursor_row;
+            let line_start = self.text.line_to_char(row);
+            let line: Vec<char> = self.line(row).chars().collect();
+            match object {
+                vim::TextObject::Word { inner } => {
+                    if line.is_empty() {
+                        return false;
+                    }
+                    let col = self.cursor_col.min(line.len() - 1);
+                    let class = text::char_class(line[col]);
+                    let same = |c: char| text::char_class(c) == class;
+                    let blank = |c: char| text::char_class(c) == text::CharClass::Whitespace;
+                    let mut start = col;
+                    while start > 0 && same(line[start - 1]) {
+                        start -= 1;
+                    }
+                    let mut end = col + 1;
+                    while end < line.len() && same(line[end]) {
+                        end += 1;
+                    }
+                    if !inner {
+                        if blank(line[col]) {
+                            // Around whitespace means the whitespace and the word after it.
+                            if let Some(&next) = line.get(end) {
+                                let next_class = text::char_class(next);
+                                while end < line.len() && text::char_class(line[end]) == next_class {
+                                    end += 1;
                                 }
                             }
-                            Err(e) => {
-                                self.status_message = format!("Error deleting: {}", e);
+                        } else if end < line.len() && blank(line[end]) {
+                            while end < line.len() && blank(line[end]) {
+                                end += 1;
+                            }
+                        } else {
+                            while start > 0 && blank(line[start - 1]) {
+                                start -= 1;
                             }
                         }
                     }
-                    self.mode = Mode::FileTree;
+                    self.select_chars(line_start + start, line_start + end);
                 }
-                KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => {
-                    self.path_to_delete = None;
-                    self.status_message = "Delete cancelled.".to_string();
-                    self.mode = Mode::FileTree;
+                vim::TextObject::Paragraph { inner } => {
+                    let last_row = self.line_count() - 1;
+                    let blank = self.is_blank(row);
+                    let (mut first, mut last) = (row, row);
+                    while first > 0 && self.is_blank(first - 1) == blank {
+                        first -= 1;
+                    }
+                    while last < last_row && self.is_blank(last + 1) == blank {
+                        last += 1;
+                    }
+                    if !inner {
+                        // Take the lines of the other kind that follow, or for a
+                        // paragraph at the end of the buffer, the blank lines before it.
+                        if last < last_row {
+                            last += 1;
+                            while last < last_row && self.is_blank(last + 1) != blank {
+                                last += 1;
+                            }
+                        } else if !blank {
+                            while first > 0 && self.is_blank(first - 1) {
+                                first -= 1;
+                            }
+                        }
+                    }
+                    self.select_rows(first, last);
+                }
+                vim::TextObject::Bracket { open, close, inner } => {
+                    let Some((start, end)) = self.enclosing_pair(open, close) else {
+                        return false;
+                    };
+                    if inner {
+                        self.select_chars(start + 1, end);
+                    } else {
+                        self.select_chars(start, end + 1);
+                    }
+                }
+                vim::TextObject::Quote { quote, inner } => {
+                    let quotes: Vec<usize> = (0..line.len())
+                        .filter(|&i| line[i] == quote && (i == 0 || line[i - 1] != '\\'))
+                        .collect();
+                    // The quoted string the cursor is in, or else the next one.
+                    let Some(pair) = quotes.chunks_exact(2).find(|pair| self.cursor_col <= pair[1]) else {
+                        return false;
+                    };
+                    if inner {
+                        self.select_chars(line_start + pair[0] + 1, line_start + pair[1]);
+                    } else {
+                        self.select_chars(line_start + pair[0], line_start + pair[1] + 1);
+                    }
                 }
-                _ => {}
             }
+            true
         }
 
-        fn handle_prompt_input_event(&mut self, key_code: KeyCode) {
-            match key_code {
-                KeyCode::Esc => {
-                    self.status_message = "Cancelled.".to_string();
-                    self.command_buffer.clear();
-                    self.mode = Mode::FileTree;
+        /// Char indices of the `open` and `close` brackets enclosing the
+        /// cursor, skipping nested pairs. The cursor may be on either one.
+        pub fn enclosing_pair(&self, open: char, close: char) -> Option<(usize, usize)> {
+            let len = self.text.len_chars();
+            if len == 0 {
+                return None;
+            }
+            let cursor = self.cursor_char_index().min(len - 1);
+            let mut depth = 0;
+            let mut start = None;
+            for i in (0..=cursor).rev() {
+                let c = self.text.char(i);
+                if c == close && i != cursor {
+                    depth += 1;
+                } else if c == open {
+                    if depth == 0 {
+                        start = Some(i);
+                        break;
+                    }
+                    depth -= 1;
                 }
-                KeyCode::Char(c) => self.command_buffer.push(c),
-                KeyCode::Backspace => {
-                    self.command_buffer.pop();
+            }
+            let start = start?;
+            depth = 0;
+            for (i, c) in self.text.chars_at(start + 1).enumerate() {
+                if c == open {
+                    depth += 1;
+                } else if c == close {
+                    if depth == 0 {
+                        return Some((start, start + 1 + i));
+                    }
+                    depth -= 1;
                 }
-                KeyCode::Enter => {
-                    if !self.command_buffer.is_empty() {
-                        let name = self.command_buffer.clone();
-                        let current_mode = self.mode;
-                        self.command_buffer.clear();
+            }
+            None
+        }
 
-                        if current_mode == Mode::PromptRename {
-                           self.execute_rename(name);
-                        } else {
-                            self.execute_new_item(name, current_mode);
-                        }
+        /// The char index of the bracket paired with the one at `at`,
+        /// scanning across lines and skipping nested pairs. Quotes, which
+        /// open and close alike, have no partner.
+        fn bracket_partner(&self, at: usize) -> Option<usize> {
+            let c = self.text.char(at);
+            let &(open, close) = self
+                .pairs()
+                .iter()
+                .find(|&&(open, close)| open != close && (c == open || c == close))?;
+            let (mut chars, forward) = if c == open {
+                (self.text.chars_at(at + 1), true)
+            } else {
+                (self.text.chars_at(at), false)
+            };
+            let (inner, outer) = if forward { (open, close) } else { (close, open) };
+            let mut depth = 0;
+            let mut i = at;
+            loop {
+                let c = if forward { chars.next()? } else { chars.prev()? };
+                i = if forward { i + 1 } else { i - 1 };
+                if c == inner {
+                    depth += 1;
+                } else if c == outer {
+                    if depth == 0 {
+                        return Some(i);
                     }
+                    depth -= 1;
                 }
-                _ => {}
             }
         }
 
-        fn execute_rename(&mut self, new_name: String) {
-            if let Some(old_path) = self.path_to_rename.take() {
-                let mut new_path = old_path.clone();
-                new_path.set_file_name(new_name);
+        /// The bracket under the cursor, or else just before it, and the one
+        /// it pairs with, as char indices.
+        pub fn matching_bracket(&self) -> Option<(usize, usize)> {
+            let cursor = self.cursor_char_index();
+            let under = (cursor < self.text.len_chars())
+                .then(|| self.bracket_partner(cursor).map(|partner| (cursor, partner)))
+                .flatten();
+            under.or_else(|| {
+                let before = cursor.checked_sub(1).filter(|_| self.cursor_col > 0)?;
+                self.bracket_partner(before).map(|partner| (before, partner))
+            })
+        }
 
-                match fs::rename(&old_path, &new_path) {
-                    Ok(_) => {
-                        self.status_message = format!("Renamed to {}", new_path.display());
-                        for tab in self.tabs.iter_mut() {
-                            if tab.file_path.as_ref() == Some(&old_path) {
-                                tab.file_path = Some(new_path.clone());
-                            }
-                        }
-                        let current_dir = self.directory_view.path.clone();
-                        if let Ok(new_view) = DirectoryView::new(current_dir) {
-                            self.directory_view = new_view;
+        /// Prefixes each non-blank row in `first..=last` with `unit`, as one
+        /// undo step.
+        pub fn indent_rows(&mut self, first: usize, last: usize, unit: &str) {
+            self.begin_transaction();
+            for row in first..=last.min(self.line_count() - 1) {
+                if !self.is_blank(row) {
+                    let at = self.text.line_to_char(row);
+                    self.replace_range(at, at, unit);
+                }
+            }
+            self.end_transaction();
+            self.move_cursor_to(first, 0);
+            self.apply_motion(Motion::FirstNonBlank);
+        }
+
+        /// Removes up to `width` columns of indentation from each row in
+        /// `first..=last`, as one undo step. A tab counts as a whole level.
+        pub fn dedent_rows(&mut self, first: usize, last: usize, width: usize) {
+            self.begin_transaction();
+            for row in first..=last.min(self.line_count() - 1) {
+                let mut removed = 0;
+                for c in self.line(row).chars().take(width) {
+                    match c {
+                        ' ' => removed += 1,
+                        '\t' => {
+                            removed += 1;
+                            break;
                         }
-                    },
-                    Err(e) => {
-                        self.status_message = format!("Error: {}", e);
+                        _ => break,
                     }
                 }
+                if removed > 0 {
+                    let at = self.text.line_to_char(row);
+                    self.replace_range(at, at + removed, "");
+                }
             }
-            self.mode = Mode::FileTree;
+            self.end_transaction();
+            self.move_cursor_to(first, 0);
+            self.apply_motion(Motion::FirstNonBlank);
         }
+    }
+
+    /// A cursor movement.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Motion {
+        Left,
+        Right,
+        Up,
+        Down,
+        /// Start of the next word.
+        WordForward,
+        /// Start of the current or previous word.
+        WordBackward,
+        /// Last char of the current or next word.
+        WordEnd,
+        LineStart,
+        LineEnd,
+        FirstNonBlank,
+        /// First non-blank char, or the line start if already there.
+        Home,
+        /// The next blank line after a paragraph.
+        ParagraphForward,
+        ParagraphBackward,
+        PageUp(usize),
+        PageDown(usize),
+        DocumentStart,
+        DocumentEnd,
+        /// The bracket paired with the one at the cursor, or with the first
+        /// one after it on the line.
+        MatchingBracket,
+    }
+
+    impl Motion {
+        /// The motion an editor key stands for, with `page` rows per page.
+        pub fn from_key(event: &KeyEvent, page: usize) -> Option<Self> {
+            let ctrl = event.modifiers.contains(KeyModifiers::CONTROL);
+            Some(match (event.code, ctrl) {
+                (KeyCode::Left, false) => Motion::Left,
+                (KeyCode::Right, false) => Motion::Right,
+                (KeyCode::Up, false) => Motion::Up,
+                (KeyCode::Down, false) => Motion::Down,
+                (KeyCode::Left, true) => Motion::WordBackward,
+                (KeyCode::Right, true) => Motion::WordForward,
+                (KeyCode::Up, true) => Motion::ParagraphBackward,
+                (KeyCode::Down, true) => Motion::ParagraphForward,
+                (KeyCode::Home, false) => Motion::Home,
+                (KeyCode::End, false) => Motion::LineEnd,
+                (KeyCode::Home, true) => Motion::DocumentStart,
+                (KeyCode::End, true) => Motion::DocumentEnd,
+                (KeyCode::PageUp, _) => Motion::PageUp(page),
+                (KeyCode::PageDown, _) => Motion::PageDown(page),
+                _ => return None,
+            })
+        }
+
+        /// The motion a vi-style movement key stands for.
+        pub fn from_vi_key(c: char) -> Option<Self> {
+            Some(match c {
+                'h' => Motion::Left,
+                'l' => Motion::Right,
+                'k' => Motion::Up,
+                'j' => Motion::Down,
+                'w' => Motion::WordForward,
+                'b' => Motion::WordBackward,
+                'e' => Motion::WordEnd,
+                '0' => Motion::LineStart,
+                '$' => Motion::LineEnd,
+                '^' => Motion::FirstNonBlank,
+                '}' => Motion::ParagraphForward,
+                '{' => Motion::ParagraphBackward,
+                'G' => Motion::DocumentEnd,
+                '%' => Motion::MatchingBracket,
+                _ => return None,
+            })
+        }
+    }
+
+    /// Represents the state of the file tree view.
+    pub struct DirectoryView {
+        pub path: PathBuf,
+        pub entries: Vec<fs::DirEntry>,
+        pub selected_index: usize,
+        pub scroll_offset: usize,
+    }
+
+    impl DirectoryView {
+        pub fn new(path: PathBuf) -> io::Result<Self> {
+            let mut entries = fs::read_dir(&path)?.filter_map(Result::ok).collect::<Vec<_>>();
+            entries.sort_by_key(|a| {
+                (
+                    !a.path().is_dir(),
+                    a.path().file_name().unwrap_or_default().to_ascii_lowercase(),
+                )
+            });
+            Ok(Self {
+                path,
+                entries,
+                selected_index: 0,
+                scroll_offset: 0,
+            })
+        }
+
+        pub fn move_up(&mut self) {
+            self.selected_index = self.selected_index.saturating_sub(1);
+        }
+
+        pub fn move_down(&mut self) {
+            if !self.entries.is_empty() {
+                self.selected_index = (self.selected_index + 1).min(self.entries.len() - 1);
+            }
+        }
+    }
+
+    /// A scrollable list of text items with one selected, shown as an
+    /// overlay over the editor.
+    #[derive(Default)]
+    pub struct ListView {
+        pub items: Vec<String>,
+        pub selected_index: usize,
+        pub scroll_offset: usize,
+    }
+
+    impl ListView {
+        pub fn new(items: Vec<String>) -> Self {
+            Self {
+                items,
+                ..Self::default()
+            }
+        }
+
+        pub fn move_up(&mut self, count: usize) {
+            self.selected_index = self.selected_index.saturating_sub(count);
+        }
+
+        pub fn move_down(&mut self, count: usize) {
+            if !self.items.is_empty() {
+                self.selected_index = (self.selected_index + count).min(self.items.len() - 1);
+            }
+        }
+
+        /// Adjusts `scroll_offset` so the selected item is within `height` rows.
+        pub fn scroll_into_view(&mut self, height: usize) {
+            if self.selected_index < self.scroll_offset {
+                self.scroll_offset = self.selected_index;
+            } else if height > 0 && self.selected_index >= self.scroll_offset + height {
+                self.scroll_offset = self.selected_index - height + 1;
+            }
+        }
+    }
+
+    /// The results of the last `:grep`.
+    pub struct GrepResults {
+        pub query: String,
+        pub hits: Vec<grep::Hit>,
+        pub truncated: bool,
+        pub list: ListView,
+    }
+
+    /// The fuzzy file picker while it is open.
+    pub struct FilePicker {
+        pub query: String,
+        /// Every file under the project root, relative to it.
+        pub files: Vec<String>,
+        /// Indices into `files` of the files matching `query`, best first.
+        pub matches: Vec<usize>,
+        pub list: ListView,
+        /// The first lines of the highlighted file.
+        pub preview: Vec<String>,
+        pub resume_mode: Mode,
+    }
+
+    /// Global application modes.
+    #[derive(PartialEq, Eq, Clone, Copy)]
+    pub enum Mode {
+        Command,
+        Edit,
+        FileTree,
+        PromptSave,
+        PromptSaveAndQuit,
+        Find,
+        ConfirmDelete,
+        PromptNewFile,
+        PromptNewDirectory,
+        PromptRename,
+        ConfirmDiscard,
+        ConfirmReload,
+        ConfirmRecover,
+        PromptReplace,
+        ConfirmReplace,
+        GrepResults,
+        FilePicker,
+        Visual,
+        /// Vim-style normal mode, where keys act immediately.
+        Normal,
+    }
+
+    /// What to do once a save succeeds.
+    #[derive(PartialEq, Eq, Clone, Copy)]
+    pub enum AfterSave {
+        Nothing,
+        CloseTab,
+        Quit,
+    }
+
+    /// A file that changed on disk while open, awaiting the user's decision.
+    pub struct DiskConflict {
+        pub tab_index: usize,
+        pub deleted: bool,
+        /// The save that was interrupted by noticing the change, if any.
+        pub pending_save: Option<AfterSave>,
+        pub resume_mode: Mode,
+    }
+
+    /// A swap file found when opening a file, awaiting the user's decision.
+    pub struct Recovery {
+        pub tab_index: usize,
+        /// The buffer contents saved in the swap file.
+        pub text: String,
+        pub resume_mode: Mode,
+    }
+
+    /// A search-and-replace in progress on the tab at `tab_index`.
+    /// `pending` runs parallel to `App::find_matches` and holds each match
+    /// as a char range in the buffer together with its replacement text.
+    /// The buffer is left alone until the session finishes, so the ranges
+    /// stay valid while the user answers.
+    pub struct ReplaceSession {
+        pub tab_index: usize,
+        pub pending: Vec<(usize, usize, String)>,
+        /// The answer for each match, once it has been given.
+        pub accepted: Vec<Option<bool>>,
+    }
+
+    /// One edit made in insert mode, recorded so `.` can make it again the
+    /// same way.
+    #[derive(Clone, Debug)]
+    enum Typed {
+        Text(String),
+        /// A char typed through `Page::insert_paired`.
+        Paired(char),
+        Backspace,
+        /// A Backspace that deleted an empty pair.
+        DeletePair,
+    }
+
+    /// An action waiting for the user to confirm that unsaved changes may be lost.
+    #[derive(PartialEq, Eq, Clone, Copy)]
+    pub enum PendingAction {
+        CloseTab,
+        Quit,
+        /// Deleting `App::path_to_delete`, which open tabs have unsaved changes under.
+        Delete,
+    }
+
+    /// The currently focused UI pane.
+    #[derive(PartialEq, Eq)]
+    pub enum ActivePane {
+        FileTree,
+        Editor,
+    }
+
+    /// The main struct holding all application state.
+    pub struct App {
+        pub tabs: Vec<Page>,
+        pub active_tab_index: usize,
+        pub directory_view: DirectoryView,
+        pub active_pane: ActivePane,
+        pub mode: Mode,
+        pub command_buffer: String,
+        pub status_message: String,
+        pub should_quit: bool,
+        pub find_query: String,
+        pub find_matches: Vec<Match>,
+        pub find_options: SearchOptions,
+        /// Why the current query could not be compiled, if it could not.
+        pub find_error: Option<String>,
+        pub current_match_index: usize,
+        pub path_to_delete: Option<PathBuf>,
+        pub path_to_rename: Option<PathBuf>,
+        pub find_navigation_active: bool,
+        /// Set by `:replace`: pressing Enter in Find mode asks for the
+        /// replacement instead of starting navigation.
+        pub find_then_replace: bool,
+        pub replace_session: Option<ReplaceSession>,
+        pub grep: Option<GrepResults>,
+        pub file_picker: Option<FilePicker>,
+        pub registers: Registers,
+        pub clipboard: Clipboard,
+        pub settings: Settings,
+        pub pending_action: Option<PendingAction>,
+        pub disk_conflict: Option<DiskConflict>,
+        pub recovery: Option<Recovery>,
+        /// Keys typed in normal mode towards a command not yet complete.
+        pub normal_keys: String,
+        /// The last normal mode change with the edits typed after it, for `.`.
+        last_change: Option<(vim::Command, Vec<Typed>)>,
+        /// The change that entered insert mode and the edits typed since.
+        insert_record: Option<(vim::Command, Vec<Typed>)>,
+        pub keymap: Keymap,
+        pub languages: Registry,
+        pub theme: Theme,
+        /// Keys typed so far of a chord not yet complete.
+        pub pending_keys: Vec<Key>,
+        chord_started: Instant,
+        /// Terminal size at the last event, for work done between events.
+        term_size: (u16, u16),
+        last_swap_write: Instant,
+    }
+
+    /// Moves the cursor by `motion` `count` times. With a count, `gg` and
+    /// `G` go to that line instead.
+    fn move_by(page: &mut Page, motion: Motion, count: Option<usize>) {
+        match (motion, count) {
+            (Motion::DocumentStart | Motion::DocumentEnd, Some(line)) => {
+                page.move_cursor_to(line - 1, 0);
+                page.apply_motion(Motion::FirstNonBlank);
+            }
+            _ => (0..count.unwrap_or(1)).for_each(|_| page.apply_motion(motion)),
+        }
+    }
+
+    /// Selects the text `motion` moves over, the way operators see it:
+    /// vertical motions take whole lines, `e` takes the char it lands on,
+    /// and a motion ending at the start of a later line stops at the end of
+    /// the line before, so `dw` on a line's last word keeps the line break.
+    fn select_motion(page: &mut Page, op: vim::Operator, motion: Motion, count: Option<usize>) {
+        let origin = page.cursor();
+        let scroll_offset = page.scroll_offset;
+        // `cw` on a word changes only up to its end, like `ce`.
+        let on_word = page
+            .line(origin.0)
+            .chars()
+            .nth(origin.1)
+            .is_some_and(|c| !c.is_whitespace());
+        let motion = if op == vim::Operator::Change && motion == Motion::WordForward && on_word {
+            Motion::WordEnd
+        } else {
+            motion
+        };
+        // Operators take whole lines, not screen rows, even when wrapping.
+        let wrap_width = page.wrap_width.take();
+        move_by(page, motion, count);
+        page.wrap_width = wrap_width;
+        let target = page.cursor();
+        page.move_cursor_to(origin.0, origin.1);
+        page.scroll_offset = scroll_offset;
+
+        let linewise = matches!(
+            motion,
+            Motion::Up
+                | Motion::Down
+                | Motion::PageUp(_)
+                | Motion::PageDown(_)
+                | Motion::DocumentStart
+                | Motion::DocumentEnd
+        );
+        if linewise {
+            page.select_rows(origin.0.min(target.0), origin.0.max(target.0));
+            return;
+        }
+        let start = page.pos_to_char(origin.0, origin.1);
+        let mut end = page.pos_to_char(target.0, target.1);
+        if matches!(motion, Motion::WordEnd | Motion::MatchingBracket) {
+            end = (end + 1).min(page.pos_to_char(usize::MAX, usize::MAX));
+        } else if target.0 > origin.0 && target.1 <= text::first_non_blank(&page.line(target.0)) {
+            end = page.pos_to_char(target.0 - 1, usize::MAX);
+        }
+        page.select_chars(start.min(end), start.max(end));
+    }
+
+    /// Removes `page`'s swap file once its contents no longer need protecting.
+    fn discard_swap(page: &mut Page) {
+        if page.swap_revision.take().is_some()
+            && let Some(path) = &page.file_path
+        {
+            swap::remove(path);
+        }
+    }
+
+    impl App {
+        pub fn new(initial_path: Option<PathBuf>) -> io::Result<Self> {
+            let directory_view = DirectoryView::new(env::current_dir()?)?;
+            let mut app = Self {
+                tabs: Vec::new(),
+                active_tab_index: 0,
+                directory_view,
+                active_pane: ActivePane::FileTree,
+                mode: Mode::FileTree,
+                command_buffer: String::new(),
+                status_message: String::new(),
+                should_quit: false,
+                find_query: String::new(),
+                find_matches: Vec::new(),
+                find_options: SearchOptions::default(),
+                find_error: None,
+                current_match_index: 0,
+                path_to_delete: None,
+                path_to_rename: None,
+                find_navigation_active: false,
+                find_then_replace: false,
+                replace_session: None,
+                grep: None,
+                file_picker: None,
+                registers: Registers::default(),
+                clipboard: Clipboard::new(ClipboardMode::default()),
+                settings: Settings::default(),
+                pending_action: None,
+                disk_conflict: None,
+                recovery: None,
+                normal_keys: String::new(),
+                last_change: None,
+                insert_record: None,
+                keymap: Keymap::default(),
+                languages: Registry::default(),
+                theme: Theme::default(),
+                pending_keys: Vec::new(),
+                chord_started: Instant::now(),
+                term_size: (80, 24),
+                last_swap_write: Instant::now(),
+            };
+
+            // The project's file comes last so that its entries win.
+            let paths = config::path()
+                .into_iter()
+                .chain(config::find_project(&app.directory_view.path));
+            let mut errors = Vec::new();
+            for path in paths {
+                match config::read(&path) {
+                    Ok(config) => {
+                        errors.extend(app.settings.configure(&config.editor));
+                        errors.extend(app.keymap.configure(&config.keys));
+                    }
+                    Err(e) => errors.push(e),
+                }
+            }
+            app.clipboard.set_mode(app.settings.clipboard);
+            let (languages, language_errors) =
+                Registry::load(config::dir().map(|dir| dir.join("languages")).as_deref());
+            app.languages = languages;
+            errors.extend(language_errors);
+            if let Err(e) = app.load_theme() {
+                errors.push(e);
+            }
+            if !errors.is_empty() {
+                app.status_message = format!("Config errors: {}", errors.join("; "));
+            }
+
+            if let Some(path) = initial_path
+                && let Some(page) = app.load_page(path)
+            {
+                app.tabs.push(page);
+                app.active_pane = ActivePane::Editor;
+                app.mode = app.editing_mode(); // Default to editing when opening a file from CLI
+                app.offer_recovery();
+            }
+            // If no path, tabs vec remains empty, showing the logo.
+
+            if let Ok((width, height)) = crossterm::terminal::size() {
+                app.term_size = (width, height);
+            }
+            app.sync_pages(app.term_size.0);
+            Ok(app)
+        }
+
+        /// Opens `path` as a new page, reporting read errors, binary content
+        /// and non-UTF-8 encodings in the status bar.
+        fn load_page(&mut self, path: PathBuf) -> Option<Page> {
+            match Page::open(path.clone()) {
+                Ok(mut page) => {
+                    page.syntax = self.syntax_for(&path, &page.line(0));
+                    page.tab_width = self.settings.tab_width;
+                    if page.read_only {
+                        self.status_message = format!(
+                            "{} looks like a binary file; opened read-only.",
+                            path.display()
+                        );
+                    } else if page.format.encoding != Encoding::Utf8 {
+                        self.status_message =
+                            format!("Opened as {}.", page.format.encoding.name());
+                    }
+                    Some(page)
+                }
+                Err(e) => {
+                    self.status_message = format!("Error opening {}: {}", path.display(), e);
+                    None
+                }
+            }
+        }
+
+        /// Picks the highlighting for a file from its name and first line,
+        /// reporting a language definition that does not compile.
+        fn syntax_for(&mut self, path: &Path, first_line: &str) -> Option<Highlighter> {
+            match self.languages.detect(path, first_line)? {
+                Ok(language) => Some(Highlighter::new(language)),
+                Err(e) => {
+                    self.status_message = format!("Syntax not loaded: {}", e);
+                    None
+                }
+            }
+        }
+
+        /// Sets the active page's file after "save as", highlighting it if
+        /// it had no language before.
+        fn set_active_path(&mut self, path: PathBuf) {
+            let Some(first_line) = self.get_active_page().map(|page| page.line(0)) else {
+                return;
+            };
+            let syntax = self.syntax_for(&path, &first_line);
+            if let Some(page) = self.get_active_page() {
+                page.file_path = Some(path);
+                if page.syntax.is_none() {
+                    page.syntax = syntax;
+                }
+            }
+        }
+
+        /// Central event handler for the entire application.
+        pub fn handle_event(&mut self, event: Event, term_width: u16, term_height: u16) {
+            self.status_message.clear();
+            self.term_size = (term_width, term_height);
+            self.sync_pages(term_width);
+            match event {
+                Event::Key(key_event) => self.handle_key_event(key_event, term_width, term_height),
+                Event::Mouse(mouse_event) => {
+                    self.handle_mouse_event(mouse_event, term_width, term_height)
+                }
+                Event::FocusGained if self.active_pane == ActivePane::Editor => {
+                    self.detect_disk_change(None);
+                }
+                Event::Paste(text) => self.handle_paste(&text, term_width, term_height),
+                _ => {}
+            }
+            self.cancel_stale_replace();
+            // Settings, tabs and line counts may have changed the layout.
+            self.sync_pages(term_width);
+        }
+
+        /// Gives each page the settings its text is laid out with: the tab
+        /// width, and the width to wrap to when soft wrap is on.
+        fn sync_pages(&mut self, term_width: u16) {
+            let editor_width = term_width
+                .saturating_sub(self.settings.tree_width(term_width))
+                .saturating_sub(1);
+            for page in &mut self.tabs {
+                page.tab_width = self.settings.tab_width;
+                page.wrap_width = self.settings.wrap.then(|| {
+                    editor_width.saturating_sub(self.settings.gutter_width(page.line_count())) as usize
+                });
+            }
+        }
+
+        /// Handles text pasted through the terminal's bracketed paste. In Edit
+        /// mode it goes in verbatim as a single undo step, replacing any
+        /// selection; prompts take its first line. Normal mode leaves the
+        /// buffer alone, as it does for typed text.
+        fn handle_paste(&mut self, text: &str, term_width: u16, term_height: u16) {
+            let text = text.replace("\r\n", "\n").replace('\r', "\n");
+            let first_line = text.lines().next().unwrap_or_default();
+            match self.mode {
+                Mode::Edit => {
+                    if !self.check_writable() {
+                        return;
+                    }
+                    if let Some((_, typed)) = &mut self.insert_record {
+                        typed.push(Typed::Text(text.clone()));
+                    }
+                    if let Some(page) = self.get_active_page() {
+                        page.begin_transaction();
+                        page.delete_selection();
+                        page.insert_text(&text);
+                        page.end_transaction();
+                    }
+                    self.scroll_to_cursor(term_width, term_height);
+                }
+                // Keys are commands in Normal mode, so pasted text is not
+                // inserted there; `p` puts from a register instead.
+                Mode::Normal => {
+                    self.status_message = "Enter insert mode to paste.".to_string();
+                }
+                Mode::Command
+                | Mode::PromptSave
+                | Mode::PromptSaveAndQuit
+                | Mode::PromptNewFile
+                | Mode::PromptNewDirectory
+                | Mode::PromptRename
+                | Mode::PromptReplace => self.command_buffer.push_str(first_line),
+                Mode::Find => {
+                    if self.find_navigation_active {
+                        self.find_query.clear();
+                        self.find_navigation_active = false;
+                    }
+                    self.find_query.push_str(first_line);
+                    self.update_search_matches();
+                    self.scroll_to_cursor(term_width, term_height);
+                }
+                Mode::FilePicker => {
+                    if let Some(picker) = &mut self.file_picker {
+                        picker.query.push_str(first_line);
+                    }
+                    self.update_picker_matches();
+                }
+                _ => {}
+            }
+        }
+
+        fn handle_mouse_event(&mut self, event: MouseEvent, term_width: u16, term_height: u16) {
+            let MouseEvent { kind, column, row, .. } = event;
+            let file_tree_width = self.settings.tree_width(term_width);
+
+            match kind {
+                MouseEventKind::ScrollUp => {
+                    if column < file_tree_width {
+                        self.directory_view.scroll_offset =
+                            self.directory_view.scroll_offset.saturating_sub(1);
+                    } else if let Some(page) = self.get_active_page() {
+                        page.scroll_offset = page.scroll_offset.saturating_sub(1);
+                    }
+                }
+                MouseEventKind::ScrollDown => {
+                    if column < file_tree_width {
+                        let view_height = term_height.saturating_sub(2) as usize;
+                        if self.directory_view.entries.len() > view_height {
+                            self.directory_view.scroll_offset = (self.directory_view.scroll_offset + 1)
+                                .min(self.directory_view.entries.len() - view_height);
+                        }
+                    } else if let Some(page) = self.get_active_page() {
+                        let total_lines = page.line_count();
+                        let view_height = term_height.saturating_sub(2) as usize;
+                        if total_lines > view_height {
+                            page.scroll_offset =
+                                (page.scroll_offset + 1).min(total_lines - view_height);
+                        }
+                    }
+                }
+                MouseEventKind::Down(_) => {
+                    // 1. Check for File Tree Click
+                    if column < file_tree_width {
+                        self.active_pane = ActivePane::FileTree;
+                        self.mode = Mode::FileTree;
+                        // row 0 is header. Clicks are relative to view + scroll offset.
+                        let target_index = row.saturating_sub(1) as usize + self.directory_view.scroll_offset;
+                        if !self.directory_view.entries.is_empty() {
+                            let max_index = self.directory_view.entries.len().saturating_sub(1);
+                            self.directory_view.selected_index = target_index.min(max_index);
+                        }
+                        return;
+                    }
+
+                    let editor_start_col = file_tree_width + 1;
+
+                    // 2. Check for Tab Bar Click
+                    if row == 0 && column >= editor_start_col && !self.tabs.is_empty() {
+                        let mut current_col = editor_start_col;
+                        let mut clicked_tab = None;
+                        for (i, page) in self.tabs.iter().enumerate() {
+                            let tab_text = page.tab_title();
+                            let tab_width = text::line_width(&tab_text, self.settings.tab_width) as u16;
+
+                            if column >= current_col && column < current_col + tab_width {
+                                clicked_tab = Some(i);
+                                break;
+                            }
+                            current_col += tab_width;
+                        }
+                        if let Some(i) = clicked_tab {
+                            self.focus_tab(i);
+                        }
+                        return;
+                    }
+
+                    // 3. Check for Editor Content Click
+                    if row > 0 && column >= editor_start_col && !self.tabs.is_empty() {
+                        self.active_pane = ActivePane::Editor;
+                        if self.mode != Mode::Edit {
+                            self.mode = self.editing_mode();
+                        }
+
+                        if let Some(page) = self.tabs.get_mut(self.active_tab_index) {
+                            let line_gutter_width = self.settings.gutter_width(page.line_count());
+                            let adjusted_row = row.saturating_sub(1) as usize + page.scroll_offset;
+                            let adjusted_col = column
+                                .saturating_sub(editor_start_col + line_gutter_width)
+                                as usize
+                                + page.horizontal_scroll_offset;
+                            page.clear_selection();
+                            if page.wrap_width.is_some() {
+                                let (line, sub_row) = page.line_at_screen_row(row.saturating_sub(1) as usize);
+                                page.move_cursor_to_visual(line, sub_row, adjusted_col);
+                            } else {
+                                page.move_cursor_to_display(adjusted_row, adjusted_col);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        fn scroll_to_cursor(&mut self, term_width: u16, term_height: u16) {
+            self.sync_pages(term_width);
+            let file_tree_width = self.settings.tree_width(term_width);
+            let view_height = term_height as usize;
+
+            if let Some(grep) = &mut self.grep {
+                // The overlay sits below the tab bar and has its own title row.
+                grep.list.scroll_into_view(view_height.saturating_sub(3));
+            }
+            if let Some(picker) = &mut self.file_picker {
+                let rows = picker_list_height(term_height).saturating_sub(1);
+                picker.list.scroll_into_view(rows as usize);
+            }
+
+            match self.active_pane {
+                ActivePane::Editor => {
+                    if let Some(page) = self.tabs.get_mut(self.active_tab_index) {
+                        let cursor_row = page.cursor_row();
+                        let scroll_offset = page.scroll_offset;
+                        let editor_view_height = view_height.saturating_sub(2);
+                        // Keep the margin, but never so much that the cursor has nowhere to be.
+                        let margin = self
+                            .settings
+                            .scroll_margin
+                            .min(editor_view_height.saturating_sub(1) / 2);
+
+                        if page.wrap_width.is_some() {
+                            page.scroll_wrapped_to_cursor(editor_view_height, margin);
+                            page.horizontal_scroll_offset = 0;
+                            return;
+                        }
+
+                        // Vertical scroll logic
+                        if cursor_row < scroll_offset + margin {
+                            page.scroll_offset = cursor_row.saturating_sub(margin);
+                        } else if cursor_row + margin >= scroll_offset + editor_view_height {
+                            let last_top = page.line_count().saturating_sub(editor_view_height);
+                            page.scroll_offset = (cursor_row + margin + 1)
+                                .saturating_sub(editor_view_height)
+                                .min(last_top.max(scroll_offset));
+                        }
+
+                        // Horizontal scroll logic
+                        let cursor_col = page.cursor_display_col();
+                        let h_scroll_offset = page.horizontal_scroll_offset;
+                        let line_gutter_width = self.settings.gutter_width(page.line_count());
+                        let editor_width = term_width.saturating_sub(file_tree_width).saturating_sub(1);
+                        let editor_text_area_width = editor_width.saturating_sub(line_gutter_width) as usize;
+
+                        if cursor_col < h_scroll_offset {
+                            page.horizontal_scroll_offset = cursor_col;
+                        } else if cursor_col >= h_scroll_offset + editor_text_area_width {
+                            page.horizontal_scroll_offset = cursor_col - editor_text_area_width + 1;
+                        }
+                    }
+                }
+                ActivePane::FileTree => {
+                    let selected_index = self.directory_view.selected_index;
+                    let scroll_offset = self.directory_view.scroll_offset;
+                    let file_tree_view_height = view_height.saturating_sub(2);
+
+                    if selected_index < scroll_offset {
+                        self.directory_view.scroll_offset = selected_index;
+                    } else if selected_index >= scroll_offset + file_tree_view_height {
+                        self.directory_view.scroll_offset = selected_index - file_tree_view_height + 1;
+                    }
+                }
+            }
+        }
+
+        fn handle_key_event(&mut self, event: KeyEvent, term_width: u16, term_height: u16) {
+            let Some(scope) = Scope::of(self.mode) else {
+                self.handle_unbound_key(event, term_width, term_height);
+                return;
+            };
+            self.pending_keys.push(Key::from_event(&event));
+            let page_size = (term_height as usize).saturating_sub(2).max(1);
+            let handled = match self.keymap.lookup(scope, &self.pending_keys) {
+                Lookup::Action(action) => {
+                    let chord = std::mem::take(&mut self.pending_keys).len() > 1;
+                    self.run_action(action, page_size, false) || chord
+                }
+                Lookup::Prefix(_) => {
+                    self.chord_started = Instant::now();
+                    return;
+                }
+                Lookup::Unbound => {
+                    let keys = std::mem::take(&mut self.pending_keys);
+                    if let Some((last, typed)) = keys.split_last()
+                        && !typed.is_empty()
+                    {
+                        // The last key broke off a chord: the keys before it
+                        // mean what they would unbound, and it starts afresh.
+                        for key in typed {
+                            self.handle_unbound_key(key.to_event(), term_width, term_height);
+                        }
+                        self.handle_key_event(last.to_event(), term_width, term_height);
+                        return;
+                    }
+                    // Shift added to a key bound to a motion extends the selection.
+                    let shifted = keys[0].without_shift().map(|key| self.keymap.lookup(scope, &[key]));
+                    match shifted {
+                        Some(Lookup::Action(action @ keymap::Action::Move(_))) => {
+                            self.run_action(action, page_size, true)
+                        }
+                        _ => false,
+                    }
+                }
+            };
+            if handled {
+                self.scroll_to_cursor(term_width, term_height);
+            } else {
+                self.handle_unbound_key(event, term_width, term_height);
+            }
+        }
+
+        /// Runs the chord typed so far once it has waited too long for its
+        /// next key: as its own binding if it has one, otherwise as the
+        /// separate keys.
+        fn flush_chord(&mut self) {
+            let keys = std::mem::take(&mut self.pending_keys);
+            let (term_width, term_height) = self.term_size;
+            let page_size = (term_height as usize).saturating_sub(2).max(1);
+            let bound = Scope::of(self.mode).map(|scope| self.keymap.lookup(scope, &keys));
+            if let Some(Lookup::Prefix(Some(action))) = bound
+                && self.run_action(action, page_size, false)
+            {
+                self.scroll_to_cursor(term_width, term_height);
+                return;
+            }
+            for key in keys {
+                self.handle_unbound_key(key.to_event(), term_width, term_height);
+            }
+        }
+
+        /// Runs `action`, extending the selection if it is a motion and
+        /// `extend` is set. Returns false if the action does not apply right
+        /// now, in which case the key keeps its built-in meaning.
+        fn run_action(&mut self, action: keymap::Action, page_size: usize, extend: bool) -> bool {
+            use keymap::Action;
+            match action {
+                Action::SwitchPane => {
+                    if self.active_pane == ActivePane::Editor {
+                        self.active_pane = ActivePane::FileTree;
+                        self.mode = Mode::FileTree;
+                    } else {
+                        self.active_pane = ActivePane::Editor;
+                        self.mode = if self.tabs.is_empty() {
+                            Mode::Command
+                        } else {
+                            self.editing_mode()
+                        };
+                    }
+                    self.command_buffer.clear();
+                }
+                Action::NextTab | Action::PrevTab if self.tabs.len() > 1 => {
+                    let len = self.tabs.len();
+                    let step = if action == Action::NextTab { 1 } else { len - 1 };
+                    self.focus_tab((self.active_tab_index + step) % len);
+                }
+                Action::FilePicker => self.open_file_picker(""),
+                Action::Find if !self.tabs.is_empty() => {
+                    self.active_pane = ActivePane::Editor;
+                    self.mode = Mode::Find;
+                    self.find_query.clear();
+                }
+                Action::Save if !self.tabs.is_empty() => {
+                    self.save_active_file(None, AfterSave::Nothing);
+                }
+                Action::Quit => self.quit(false),
+                Action::Undo => self.undo(),
+                Action::Redo => self.redo(),
+                Action::Copy => self.copy_selection(),
+                Action::Cut => self.cut_selection(),
+                Action::Paste => self.paste(false),
+                Action::Move(motion) => {
+                    let motion = match motion {
+                        Motion::PageUp(_) => Motion::PageUp(page_size),
+                        Motion::PageDown(_) => Motion::PageDown(page_size),
+                        motion => motion,
+                    };
+                    let Some(page) = self.get_active_page() else {
+                        return false;
+                    };
+                    if extend {
+                        page.extend_selection();
+                    } else {
+                        page.clear_selection();
+                    }
+                    page.apply_motion(motion);
+                }
+                Action::TreeUp => self.directory_view.move_up(),
+                Action::TreeDown => self.directory_view.move_down(),
+                Action::TreeOpen => {
+                    self.open_selected_entry();
+                    self.command_buffer.clear();
+                }
+                Action::TreeParent => {
+                    self.go_up_directory();
+                    self.command_buffer.clear();
+                }
+                Action::FindNext if self.find_navigation_active => self.jump_to_next_match(),
+                Action::FindPrev if self.find_navigation_active => self.jump_to_prev_match(),
+                Action::ToggleRegex | Action::CycleCase | Action::ToggleWholeWord => {
+                    match action {
+                        Action::ToggleRegex => self.find_options.regex = !self.find_options.regex,
+                        Action::CycleCase => self.find_options.case = self.find_options.case.next(),
+                        _ => self.find_options.whole_word = !self.find_options.whole_word,
+                    }
+                    self.update_search_matches();
+                }
+                Action::Replace if !self.find_query.is_empty() => self.prompt_replacement(),
+                Action::Indent | Action::Dedent if self.mode == Mode::Edit => {
+                    self.indent(action == Action::Indent);
+                }
+                _ => return false,
+            }
+            true
+        }
+
+        /// Tab and Shift+Tab in Edit mode: indent or dedent the selected
+        /// lines, keeping them selected, or without a selection insert one
+        /// level at the cursor or dedent the cursor line.
+        fn indent(&mut self, indent: bool) {
+            if !self.check_writable() {
+                return;
+            }
+            let unit = self.settings.indent_unit();
+            let tab_width = self.settings.tab_width;
+            let expand_tabs = self.settings.expand_tabs;
+            let Some(page) = self.get_active_page() else {
+                return;
+            };
+            if let Some((first, last)) = page.selection_rows() {
+                if indent {
+                    page.indent_rows(first, last, &unit);
+                } else {
+                    page.dedent_rows(first, last, tab_width);
+                }
+                page.select_rows(first, last);
+                return;
+            }
+            let (row, col) = page.cursor();
+            if indent {
+                // Spaces go to the next tab stop rather than a fixed count.
+                let text = if expand_tabs {
+                    " ".repeat(tab_width - page.cursor_display_col() % tab_width)
+                } else {
+                    unit
+                };
+                page.insert_text(&text);
+                if let Some((_, typed)) = &mut self.insert_record {
+                    typed.push(Typed::Text(text));
+                }
+            } else {
+                let len = page.line_len(row);
+                page.dedent_rows(row, row, tab_width);
+                let removed = len - page.line_len(row);
+                page.move_cursor_to(row, col.saturating_sub(removed));
+            }
+        }
+
+        /// Handles a key by its built-in meaning in the current mode.
+        fn handle_unbound_key(&mut self, event: KeyEvent, term_width: u16, term_height: u16) {
+            if self.mode == Mode::ConfirmDelete {
+                self.handle_delete_confirm_event(event.code);
+                return;
+            }
+
+            if self.mode == Mode::ConfirmDiscard {
+                self.handle_discard_confirm_event(event.code);
+                return;
+            }
+
+            if self.mode == Mode::ConfirmReload {
+                self.handle_reload_confirm_event(event.code);
+                return;
+            }
+
+            if self.mode == Mode::ConfirmRecover {
+                self.handle_recover_confirm_event(event.code);
+                return;
+            }
+
+            if self.mode == Mode::ConfirmReplace {
+                self.handle_replace_confirm_event(event.code);
+                self.scroll_to_cursor(term_width, term_height);
+                return;
+            }
+
+            if self.mode == Mode::PromptReplace {
+                self.handle_replace_prompt_event(event.code);
+                self.scroll_to_cursor(term_width, term_height);
+                return;
+            }
+
+            if self.mode == Mode::FilePicker {
+                let page_size = picker_list_height(term_height).saturating_sub(1).max(1);
+                self.handle_file_picker_event(event, page_size as usize);
+                self.scroll_to_cursor(term_width, term_height);
+                return;
+            }
+
+            if self.mode == Mode::GrepResults {
+                let page_size = (term_height as usize).saturating_sub(3).max(1);
+                self.handle_grep_event(event.code, page_size);
+                self.scroll_to_cursor(term_width, term_height);
+                return;
+            }
+
+            if self.mode == Mode::PromptNewFile
+                || self.mode == Mode::PromptNewDirectory
+                || self.mode == Mode::PromptRename
+            {
+                self.handle_prompt_input_event(event.code);
+                return;
+            }
+            
+            match self.active_pane {
+                ActivePane::Editor => {
+                    // Rows of text visible below the tab bar.
+                    let page_size = (term_height as usize).saturating_sub(2).max(1);
+                    self.handle_editor_event(event, page_size)
+                }
+                ActivePane::FileTree => self.handle_file_tree_event(event.code),
+            };
+            self.scroll_to_cursor(term_width, term_height);
+        }
+
+        fn handle_file_tree_event(&mut self, key_code: KeyCode) {
+            match key_code {
+                KeyCode::Enter => {
+                    if self.command_buffer.is_empty() {
+                        self.open_selected_entry();
+                    } else {
+                        let cmd = self.command_buffer.clone();
+                        self.command_buffer.clear();
+                        match cmd.as_str() {
+                            "d" => self.prompt_for_delete(),
+                            "nf" => self.mode = Mode::PromptNewFile,
+                            "nd" => self.mode = Mode::PromptNewDirectory,
+                            "rn" => self.prompt_for_rename(),
+                            _ => {
+                                self.status_message = format!("Unknown command: {}", cmd);
+                            }
+                        }
+                    }
+                },
+
+                // Mode Switching
+                KeyCode::Esc => {
+                    self.active_pane = ActivePane::Editor;
+                    self.mode = self.idle_mode();
+                    self.command_buffer.clear();
+                },
+                
+                // Command Input
+                KeyCode::Char(c) => {
+                    self.command_buffer.push(c);
+                },
+                KeyCode::Backspace => {
+                    self.command_buffer.pop();
+                },
+
+                _ => {}
+            }
+        }
+
+
+        fn prompt_for_delete(&mut self) {
+            if let Some(entry) = self.directory_view.entries.get(self.directory_view.selected_index) {
+                let path = entry.path();
+                self.path_to_delete = Some(path);
+                self.mode = Mode::ConfirmDelete;
+            }
+        }
+
+        fn handle_delete_confirm_event(&mut self, key_code: KeyCode) {
+            match key_code {
+                KeyCode::Char('y') | KeyCode::Char('Y') => self.delete_path(false),
+                KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => {
+                    self.path_to_delete = None;
+                    self.status_message = "Delete cancelled.".to_string();
+                    self.mode = Mode::FileTree;
+                }
+                _ => {}
+            }
+        }
+
+        /// Deletes `path_to_delete` and closes the tabs open under it, asking
+        /// first if any of them has unsaved changes unless `force` is set.
+        fn delete_path(&mut self, force: bool) {
+            if !force
+                && let Some(path) = &self.path_to_delete
+                && self.modified_tabs_under(path) > 0
+            {
+                self.pending_action = Some(PendingAction::Delete);
+                self.mode = Mode::ConfirmDiscard;
+                return;
+            }
+            if let Some(path) = self.path_to_delete.take() {
+                // Swap files are named after the resolved path, which
+                // can no longer be resolved once the file is gone.
+                let swap_files: Vec<_> = self
+                    .tabs
+                    .iter()
+                    .filter_map(|page| page.file_path.as_ref())
+                    .filter(|page_path| page_path.starts_with(&path))
+                    .filter_map(|page_path| swap::swap_path(page_path))
+                    .collect();
+                let result = if path.is_dir() {
+                    fs::remove_dir_all(&path)
+                } else {
+                    fs::remove_file(&path)
+                };
+
+                match result {
+                    Ok(_) => {
+                        self.status_message = format!("Deleted {}", path.display());
+                        for swap_file in swap_files {
+                            let _ = fs::remove_file(swap_file);
+                        }
+                        self.tabs.retain(|page| {
+                            if let Some(page_path) = &page.file_path {
+                                !page_path.starts_with(&path)
+                            } else {
+                                true
+                            }
+                        });
+                        if self.tabs.is_empty() {
+                            self.mode = Mode::Command;
+                            self.active_tab_index = 0;
+                        } else if self.active_tab_index >= self.tabs.len() {
+                            self.active_tab_index = self.tabs.len() - 1;
+                        }
+
+                        let current_dir = self.directory_view.path.clone();
+                        if let Ok(new_view) = DirectoryView::new(current_dir) {
+                            self.directory_view = new_view;
+                        }
+                    }
+                    Err(e) => {
+                        self.status_message = format!("Error deleting: {}", e);
+                    }
+                }
+            }
+            self.mode = Mode::FileTree;
+        }
+
+        fn handle_prompt_input_event(&mut self, key_code: KeyCode) {
+            match key_code {
+                KeyCode::Esc => {
+                    self.status_message = "Cancelled.".to_string();
+                    self.command_buffer.clear();
+                    self.mode = Mode::FileTree;
+                }
+                KeyCode::Char(c) => self.command_buffer.push(c),
+                KeyCode::Backspace => {
+                    self.command_buffer.pop();
+                }
+                KeyCode::Enter if !self.command_buffer.is_empty() => {
+                    let name = self.command_buffer.clone();
+                    let current_mode = self.mode;
+                    self.command_buffer.clear();
+
+                    if current_mode == Mode::PromptRename {
+                       self.execute_rename(name);
+                    } else {
+                        self.execute_new_item(name, current_mode);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        fn execute_rename(&mut self, new_name: String) {
+            if let Some(old_path) = self.path_to_rename.take() {
+                let mut new_path = old_path.clone();
+                new_path.set_file_name(new_name);
+
+                match fs::rename(&old_path, &new_path) {
+                    Ok(_) => {
+                        self.status_message = format!("Renamed to {}", new_path.display());
+                        for tab in self.tabs.iter_mut() {
+                            if tab.file_path.as_ref() == Some(&old_path) {
+                                tab.file_path = Some(new_path.clone());
+                            }
+                        }
+                        let current_dir = self.directory_view.path.clone();
+                        if let Ok(new_view) = DirectoryView::new(current_dir) {
+                            self.directory_view = new_view;
+                        }
+                    },
+                    Err(e) => {
+                        self.status_message = format!("Error: {}", e);
+                    }
+                }
+            }
+            self.mode = Mode::FileTree;
+        }
+
+        fn execute_new_item(&mut self, name: String, mode: Mode) {
+            let mut path = self.directory_view.path.clone();
+            path.push(name);
+
+            let result = if mode == Mode::PromptNewFile {
+                fs::write(&path, "")
+            } else {
+                fs::create_dir(&path)
+            };
+
+            match result {
+                Ok(_) => {
+                    self.status_message = format!("Created {}", path.display());
+                    if mode == Mode::PromptNewFile
+                        && let Some(page) = self.load_page(path)
+                    {
+                        self.tabs.push(page);
+                        self.active_tab_index = self.tabs.len() - 1;
+                        self.active_pane = ActivePane::Editor;
+                        self.mode = self.editing_mode();
+                    } else {
+                        self.mode = Mode::FileTree;
+                    }
+                    let current_dir = self.directory_view.path.clone();
+                    if let Ok(new_view) = DirectoryView::new(current_dir) {
+                        self.directory_view = new_view;
+                    }
+                },
+                Err(e) => {
+                    self.status_message = format!("Error: {}", e);
+                    self.mode = Mode::FileTree;
+                }
+            }
+        }
+
+        fn prompt_for_rename(&mut self) {
+            if let Some(entry) = self.directory_view.entries.get(self.directory_view.selected_index) {
+                let path = entry.path();
+                self.path_to_rename = Some(path);
+                self.mode = Mode::PromptRename;
+            }
+        }
+
+
+        fn go_up_directory(&mut self) {
+            if let Some(parent) = self.directory_view.path.parent() {
+                match DirectoryView::new(parent.to_path_buf()) {
+                    Ok(new_view) => self.directory_view = new_view,
+                    Err(_) => self.status_message = "Cannot access parent directory.".to_string(),
+                }
+            }
+        }
+
+        fn handle_editor_event(&mut self, event: KeyEvent, page_size: usize) {
+            if self.mode == Mode::Find {
+                self.handle_find_event(event);
+                return;
+            }
+
+            if self.mode == Mode::PromptSave || self.mode == Mode::PromptSaveAndQuit {
+                self.handle_prompt_event(event.code);
+                return;
+            }
+
+            if self.mode == Mode::Visual {
+                self.handle_visual_event(event, page_size);
+                return;
+            }
+
+            if self.mode == Mode::Normal {
+                self.handle_normal_event(event, page_size);
+                return;
+            }
+
+            match event.code {
+                KeyCode::Esc => match self.mode {
+                    Mode::Edit if self.settings.vim => self.leave_insert(),
+                    Mode::Edit => self.mode = Mode::Command,
+                    Mode::Command if !self.tabs.is_empty() => {
+                        self.mode = self.editing_mode();
+                        self.command_buffer.clear();
+                        if let Some(page) = self.get_active_page() {
+                            page.clear_selection();
+                        }
+                    }
+                    _ => {}
+                },
+                KeyCode::Char(_) | KeyCode::Backspace | KeyCode::Enter
+                    if self.mode == Mode::Edit
+                        && self.tabs.get(self.active_tab_index).is_some_and(|p| p.read_only) =>
+                {
+                    self.status_message = "Buffer is read-only.".to_string();
+                }
+                KeyCode::Char(c) => match self.mode {
+                    Mode::Edit => {
+                        let auto_pairs = self.settings.auto_pairs;
+                        let Some(page) = self.get_active_page() else {
+                            return;
+                        };
+                        let edit = if page.delete_selection().is_none() && auto_pairs {
+                            page.insert_paired(c);
+                            Typed::Paired(c)
+                        } else {
+                            page.insert_char(c);
+                            Typed::Text(c.to_string())
+                        };
+                        if let Some((_, typed)) = &mut self.insert_record {
+                            typed.push(edit);
+                        }
+                    }
+                    Mode::Command => self.command_buffer.push(c),
+                    _ => {}
+                },
+                KeyCode::Backspace => match self.mode {
+                    Mode::Edit => {
+                        let auto_pairs = self.settings.auto_pairs;
+                        let Some(page) = self.get_active_page() else {
+                            return;
+                        };
+                        // Deleting a selection is left out of the record: `.`
+                        // has no selection to delete.
+                        let edit = if page.delete_selection().is_some() {
+                            None
+                        } else if auto_pairs && page.delete_pair() {
+                            Some(Typed::DeletePair)
+                        } else {
+                            page.delete();
+                            Some(Typed::Backspace)
+                        };
+                        if let Some((_, typed)) = &mut self.insert_record {
+                            typed.extend(edit);
+                        }
+                    }
+                    Mode::Command => {
+                        self.command_buffer.pop();
+                    }
+                    _ => {}
+                },
+                KeyCode::Enter => match self.mode {
+                    Mode::Edit => {
+                        let unit = self.settings.indent_unit();
+                        let Some(page) = self.get_active_page() else {
+                            return;
+                        };
+                        page.delete_selection();
+                        let inserted = page.insert_newline_indented(&unit);
+                        if let Some((_, typed)) = &mut self.insert_record {
+                            typed.push(Typed::Text(inserted));
+                        }
+                    }
+                    Mode::Command => {
+                        self.execute_command();
+                        if self.mode == Mode::Command {
+                            self.mode = self.idle_mode();
+                        }
+                    }
+                    _ => {}
+                },
+                _ => {}
+            }
+        }
+
+        /// Loads the theme named by the settings, keeping the current one if
+        /// it cannot be read.
+        fn load_theme(&mut self) -> Result<(), String> {
+            let user_dir = config::dir().map(|dir| dir.join("themes"));
+            self.theme = Theme::load(&self.settings.theme, user_dir.as_deref(), ColorSupport::detect())?;
+            Ok(())
+        }
+
+        /// `:syntax` names the active page's language, `:syntax off` turns
+        /// highlighting off and `:syntax <name>` picks a language.
+        fn set_syntax(&mut self, name: Option<&str>) {
+            let language = match name {
+                None => {
+                    let current = self.tabs[self.active_tab_index]
+                        .syntax
+                        .as_ref()
+                        .map_or("off", |syntax| syntax.language.name.as_str());
+                    self.status_message = format!(
+                        "Syntax is {} (syntax off|{})",
+                        current,
+                        self.languages.names().join("|")
+                    );
+                    return;
+                }
+                Some("off") => None,
+                Some(name) => match self.languages.get(name) {
+                    Some(Ok(language)) => Some(language),
+                    Some(Err(e)) => {
+                        self.status_message = format!("Syntax not loaded: {}", e);
+                        return;
+                    }
+                    None => {
+                        self.status_message = format!("Unknown language \"{}\"", name);
+                        return;
+                    }
+                },
+            };
+            if let Some(page) = self.get_active_page() {
+                page.syntax = language.map(Highlighter::new);
+            }
+        }
+
+        /// `:set` shows every setting, `:set key` shows one and
+        /// `:set key=value ...` changes them.
+        fn run_set(&mut self, args: &str) {
+            if args.trim().is_empty() {
+                self.status_message = self.settings.describe();
+                return;
+            }
+            let mut shown = Vec::new();
+            for arg in args.split_whitespace() {
+                if let Some((key, value)) = arg.split_once('=') {
+                    self.set_setting(key, value);
+                    if !self.status_message.is_empty() {
+                        return;
+                    }
+                } else {
+                    match self.settings.get(arg) {
+                        Some(value) => shown.push(format!("{}={}", arg, value)),
+                        None => {
+                            self.status_message = format!("Unknown setting \"{}\"", arg);
+                            return;
+                        }
+                    }
+                }
+            }
+            self.status_message = shown.join(" ");
+        }
+
+        /// Changes one setting, reporting an invalid key or value in the
+        /// status bar.
+        fn set_setting(&mut self, key: &str, value: &str) {
+            let previous_theme = self.settings.theme.clone();
+            if let Err(e) = self.settings.set(key, value) {
+                self.status_message = format!("Cannot set: {}", e);
+                return;
+            }
+            if self.settings.theme != previous_theme
+                && let Err(e) = self.load_theme()
+            {
+                self.settings.theme = previous_theme;
+                self.status_message = format!("Cannot set: {}", e);
+                return;
+            }
+            if self.clipboard.mode() != self.settings.clipboard {
+                self.clipboard.set_mode(self.settings.clipboard);
+            }
+            if !self.settings.vim && self.mode == Mode::Normal {
+                self.mode = Mode::Command;
+            }
+        }
+
+        /// Moves to `spec`, a 1-based `line` or `line:col`. Without a column
+        /// the cursor goes to the first non-blank char of the line.
+        fn goto(&mut self, spec: Option<&str>) {
+            let parsed = spec.and_then(|spec| {
+                let (line, col) = match spec.split_once(':') {
+                    Some((line, col)) => (line, Some(col.parse::<usize>().ok()?)),
+                    None => (spec, None),
+                };
+                Some((line.parse::<usize>().ok()?, col))
+            });
+            let Some((line, col)) = parsed else {
+                self.status_message = "Usage: goto <line>[:col]".to_string();
+                return;
+            };
+            if let Some(page) = self.get_active_page() {
+                let row = line.max(1) - 1;
+                match col {
+                    Some(col) => page.move_cursor_to(row, col.max(1) - 1),
+                    None => {
+                        page.move_cursor_to(row, 0);
+                        page.apply_motion(Motion::FirstNonBlank);
+                    }
+                }
+                self.active_pane = ActivePane::Editor;
+                self.mode = self.editing_mode();
+            }
+        }
+
+        fn start_visual(&mut self, linewise: bool) {
+            if let Some(page) = self.get_active_page() {
+                page.start_selection(linewise);
+                self.mode = Mode::Visual;
+            }
+        }
+
+        fn handle_visual_event(&mut self, event: KeyEvent, page_size: usize) {
+            let ctrl = event.modifiers.contains(KeyModifiers::CONTROL);
+            let motion = match event.code {
+                KeyCode::Char(c) if !ctrl => Motion::from_vi_key(c),
+                _ => Motion::from_key(&event, page_size),
+            };
+            if let Some(motion) = motion {
+                if let Some(page) = self.get_active_page() {
+                    page.apply_motion(motion);
+                }
+                return;
+            }
+            match event.code {
+                KeyCode::Char(':') => {
+                    // Keep the selection so `:'<,'>s/...` can use it.
+                    self.mode = Mode::Command;
+                    self.command_buffer = "'<,'>".to_string();
+                    return;
+                }
+                KeyCode::Char('y') => self.copy_selection(),
+                KeyCode::Char('c') if ctrl => self.copy_selection(),
+                KeyCode::Char('d' | 'x') => self.cut_selection(),
+                KeyCode::Char('p') => self.paste(false),
+                KeyCode::Char('v') if ctrl => self.paste(false),
+                KeyCode::Backspace | KeyCode::Delete => {
+                    if !self.check_writable() {
+                        return;
+                    }
+                    if let Some(page) = self.get_active_page() {
+                        page.delete_selection();
+                    }
+                }
+                KeyCode::Esc => {}
+                _ => return,
+            }
+            if let Some(page) = self.get_active_page() {
+                page.clear_selection();
+            }
+            self.mode = self.idle_mode();
+        }
+
+        /// The mode the editor pane rests in: normal mode when vim keys are
+        /// on and a file is open, the command line otherwise.
+        fn idle_mode(&self) -> Mode {
+            if self.settings.vim && !self.tabs.is_empty() {
+                Mode::Normal
+            } else {
+                Mode::Command
+            }
+        }
+
+        /// The mode a freshly focused file is edited in.
+        fn editing_mode(&self) -> Mode {
+            if self.settings.vim { Mode::Normal } else { Mode::Edit }
+        }
+
+        fn handle_normal_event(&mut self, event: KeyEvent, page_size: usize) {
+            let ctrl = event.modifiers.contains(KeyModifiers::CONTROL);
+            match event.code {
+                KeyCode::Char('r') if ctrl => {
+                    self.normal_keys.clear();
+                    self.redo();
+                    return;
+                }
+                KeyCode::Char(c) if !ctrl => self.normal_keys.push(c),
+                _ => {
+                    self.normal_keys.clear();
+                    if let Some(motion) = Motion::from_key(&event, page_size)
+                        && let Some(page) = self.get_active_page()
+                    {
+                        page.clear_selection();
+                        page.apply_motion(motion);
+                    }
+                    return;
+                }
+            }
+            match vim::parse(&self.normal_keys) {
+                vim::Parse::Pending => {}
+                vim::Parse::Invalid => self.normal_keys.clear(),
+                vim::Parse::Done(command) => {
+                    self.normal_keys.clear();
+                    self.run_normal(command);
+                }
+            }
+        }
+
+        fn run_normal(&mut self, command: vim::Command) {
+            if command.is_change() && !self.check_writable() {
+                return;
+            }
+            let count = command.count.unwrap_or(1);
+            let register = command.register.unwrap_or(UNNAMED);
+            match command.action {
+                vim::Action::Move(motion) => {
+                    if let Some(page) = self.get_active_page() {
+                        page.clear_selection();
+                        move_by(page, motion, command.count);
+                    }
+                }
+                vim::Action::Operate(op, target) => self.operate(op, target, command.count, register),
+                vim::Action::Insert(insert) => self.start_insert(insert),
+                vim::Action::Paste { after } => self.paste_register(register, after, count),
+                vim::Action::Undo => (0..count).for_each(|_| self.undo()),
+                vim::Action::Repeat => self.repeat_change(command.count),
+                vim::Action::Visual { linewise } => self.start_visual(linewise),
+                vim::Action::CommandLine => {
+                    self.mode = Mode::Command;
+                    self.command_buffer.clear();
+                }
+            }
+            if command.is_change() {
+                if self.mode == Mode::Edit {
+                    self.insert_record = Some((command, Vec::new()));
+                } else {
+                    self.last_change = Some((command, Vec::new()));
+                }
+            }
+        }
+
+        /// Applies `op` to `target`, storing deleted and yanked text in `register`.
+        fn operate(&mut self, op: vim::Operator, target: vim::Target, count: Option<usize>, register: char) {
+            let Some(page) = self.tabs.get_mut(self.active_tab_index) else {
+                return;
+            };
+            let origin = page.cursor();
+            let selected = match target {
+                vim::Target::Line => {
+                    page.select_rows(origin.0, origin.0 + count.unwrap_or(1) - 1);
+                    true
+                }
+                vim::Target::Object(object) => page.select_object(object),
+                vim::Target::Motion(motion) => {
+                    select_motion(page, op, motion, count);
+                    true
+                }
+            };
+            let range = page.selection_range().filter(|_| selected);
+            let Some((start, _)) = range.filter(|(start, end)| start < end) else {
+                page.clear_selection();
+                // Changing an empty object, like `ci(` on `()`, still inserts there.
+                if let Some((start, _)) = range.filter(|_| op == vim::Operator::Change) {
+                    let (row, col) = page.char_to_pos(start);
+                    page.move_cursor_to(row, col);
+                    self.mode = Mode::Edit;
+                }
+                return;
+            };
+            let linewise = page.is_linewise_selection();
+            let (first, last) = page.selection_rows().unwrap_or((origin.0, origin.0));
+            let text = page.selected_text().unwrap_or_default();
+            match op {
+                vim::Operator::Delete => {
+                    page.delete_selection();
+                }
+                vim::Operator::Change if linewise => {
+                    // Keep one line, with the first line's indentation, to type on.
+                    let line = page.line(first);
+                    let indent = &line[..line.len() - line.trim_start().len()];
+                    let end = page.pos_to_char(last, usize::MAX);
+                    page.clear_selection();
+                    page.replace_range(page.pos_to_char(first, 0), end, indent);
+                    self.mode = Mode::Edit;
+                }
+                vim::Operator::Change => {
+                    page.delete_selection();
+                    self.mode = Mode::Edit;
+                }
+                vim::Operator::Yank => {
+                    page.clear_selection();
+                    let (row, col) = if linewise { (first, origin.1) } else { page.char_to_pos(start) };
+                    page.move_cursor_to(row, col);
+                }
+                vim::Operator::Indent | vim::Operator::Dedent => {
+                    page.clear_selection();
+                    let unit = self.settings.indent_unit();
+                    if op == vim::Operator::Indent {
+                        page.indent_rows(first, last, &unit);
+                    } else {
+                        page.dedent_rows(first, last, self.settings.tab_width);
+                    }
+                    return;
+                }
+            }
+            self.store_register(register, Register { text, linewise }, op == vim::Operator::Yank);
+        }
+
+        /// Stores `register` under `name`. The `+` register is the system
+        /// clipboard, which yanks into the unnamed register also reach.
+        fn store_register(&mut self, name: char, register: Register, yank: bool) {
+            if (name == '+' || (yank && name == UNNAMED))
+                && let Err(e) = self.clipboard.copy(&register.text)
+            {
+                self.status_message = format!("Clipboard: {}", e);
+            }
+            self.registers.set(if name == '+' { UNNAMED } else { name }, register);
+        }
+
+        /// Pastes register `name` `count` times, after the cursor if `after`
+        /// is set.
+        fn paste_register(&mut self, name: char, after: bool, count: usize) {
+            let register = if name == '+' {
+                self.clipboard.paste().map(|text| Register { text, linewise: false })
+            } else {
+                self.registers.get(name).cloned()
+            };
+            let Some(register) = register else {
+                self.status_message = format!("Register {} is empty.", name);
+                return;
+            };
+            if let Some(page) = self.get_active_page() {
+                page.begin_transaction();
+                page.paste(&register.text.repeat(count), register.linewise, after);
+                page.end_transaction();
+            }
+        }
+
+        fn start_insert(&mut self, insert: vim::Insert) {
+            let unit = self.settings.indent_unit();
+            let Some(page) = self.get_active_page() else {
+                return;
+            };
+            page.clear_selection();
+            match insert {
+                vim::Insert::Before => {}
+                vim::Insert::After => page.move_right(),
+                vim::Insert::LineStart => page.apply_motion(Motion::FirstNonBlank),
+                vim::Insert::LineEnd => page.apply_motion(Motion::LineEnd),
+                vim::Insert::LineBelow => {
+                    page.apply_motion(Motion::LineEnd);
+                    page.insert_newline_indented(&unit);
+                }
+                vim::Insert::LineAbove => {
+                    let line = page.line(page.cursor_row());
+                    let indent = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+                    page.apply_motion(Motion::LineStart);
+                    page.insert_newline();
+                    page.move_up();
+                    page.insert_text(indent);
+                }
+            }
+            self.mode = Mode::Edit;
+        }
+
+        /// Returns from insert mode to normal mode, remembering what was
+        /// typed for `.`.
+        fn leave_insert(&mut self) {
+            if let Some(change) = self.insert_record.take() {
+                self.last_change = Some(change);
+            }
+            self.mode = Mode::Normal;
+            if let Some(page) = self.get_active_page() {
+                page.clear_selection();
+                if page.cursor_col() > 0 {
+                    page.move_left();
+                }
+            }
+        }
+
+        /// Repeats the last change, with `count` in place of its own count
+        /// if one was typed.
+        fn repeat_change(&mut self, count: Option<usize>) {
+            let Some((mut command, typed)) = self.last_change.clone() else {
+                return;
+            };
+            if count.is_some() {
+                command.count = count;
+            }
+            self.run_normal(command);
+            if self.mode == Mode::Edit {
+                self.insert_record = None;
+                if let Some(page) = self.get_active_page() {
+                    for edit in &typed {
+                        match edit {
+                            Typed::Text(text) => page.insert_text(text),
+                            Typed::Paired(c) => page.insert_paired(*c),
+                            Typed::Backspace => page.delete(),
+                            Typed::DeletePair => {
+                                page.delete_pair();
+                            }
+                        }
+                    }
+                }
+                self.leave_insert();
+            }
+            self.last_change = Some((command, typed));
+        }
+
+        /// Reports and returns false if the active page is read-only.
+        fn check_writable(&mut self) -> bool {
+            if self.tabs.get(self.active_tab_index).is_some_and(|p| p.read_only) {
+                self.status_message = "Buffer is read-only.".to_string();
+                return false;
+            }
+            true
+        }
+
+        /// Copies the selection, or the cursor line if nothing is selected,
+        /// into the unnamed register.
+        fn copy_selection(&mut self) {
+            let Some(page) = self.tabs.get(self.active_tab_index) else {
+                return;
+            };
+            let register = match page.selected_text() {
+                Some(text) => Register {
+                    text,
+                    linewise: page.is_linewise_selection(),
+                },
+                None => Register {
+                    text: page.line(page.cursor_row()) + "\n",
+                    linewise: true,
+                },
+            };
+            if let Err(e) = self.clipboard.copy(&register.text) {
+                self.status_message = format!("Clipboard: {}", e);
+            }
+            self.registers.set(UNNAMED, register);
+        }
+
+        /// Like `copy_selection`, then deletes what was copied.
+        fn cut_selection(&mut self) {
+            if !self.check_writable() {
+                return;
+            }
+            self.copy_selection();
+            if let Some(page) = self.get_active_page() {
+                if !page.has_selection() {
+                    page.start_selection(true);
+                }
+                page.delete_selection();
+            }
+        }
+
+        /// Pastes the system clipboard, replacing the selection if there is
+        /// one. Text last copied in jot keeps its line-wise flag.
+        fn paste(&mut self, after: bool) {
+            let unnamed = self.registers.get(UNNAMED).cloned();
+            let register = match self.clipboard.paste() {
+                Some(text) if unnamed.as_ref().is_none_or(|r| r.text != text) => {
+                    Some(Register { text, linewise: false })
+                }
+                _ => unnamed,
+            };
+            let Some(register) = register else {
+                self.status_message = "Nothing to paste.".to_string();
+                return;
+            };
+            if !self.check_writable() {
+                return;
+            }
+            if let Some(page) = self.get_active_page() {
+                page.begin_transaction();
+                page.delete_selection();
+                page.paste(&register.text, register.linewise, after);
+                page.end_transaction();
+            }
+        }
+
+        fn handle_find_event(&mut self, event: KeyEvent) {
+            match event.code {
+                KeyCode::Esc => {
+                    self.mode = self.idle_mode();
+                    self.find_query.clear();
+                    self.find_matches.clear();
+                    self.find_error = None;
+                    self.find_navigation_active = false;
+                    self.find_then_replace = false;
+                }
+                KeyCode::Enter if self.find_then_replace && !self.find_query.is_empty() => {
+                    self.prompt_replacement();
+                }
+                KeyCode::Enter if !self.find_query.is_empty() => {
+                    self.find_navigation_active = true;
+                    self.jump_to_match();
+                }
+                KeyCode::Char(c) => {
+                    if self.find_navigation_active {
+                        self.find_query.clear();
+                        self.find_navigation_active = false;
+                    }
+                    self.find_query.push(c);
+                    self.update_search_matches();
+                }
+                KeyCode::Backspace if !self.find_query.is_empty() => {
+                    self.find_navigation_active = false;
+                    self.find_query.pop();
+                    self.update_search_matches();
+                }
+                _ => {}
+            }
+        }
+        
+        fn prompt_replacement(&mut self) {
+            self.find_then_replace = false;
+            self.command_buffer.clear();
+            self.mode = Mode::PromptReplace;
+        }
+
+        fn handle_replace_prompt_event(&mut self, key_code: KeyCode) {
+            match key_code {
+                KeyCode::Esc => {
+                    self.command_buffer.clear();
+                    self.mode = Mode::Find;
+                }
+                KeyCode::Char(c) => self.command_buffer.push(c),
+                KeyCode::Backspace => {
+                    self.command_buffer.pop();
+                }
+                KeyCode::Enter => {
+                    let replacement = std::mem::take(&mut self.command_buffer);
+                    let template = if self.find_options.regex {
+                        search::expand_template(&replacement)
+                    } else {
+                        search::literal_template(&replacement)
+                    };
+                    let Ok(regex) = search::compile(&self.find_query, self.find_options) else {
+                        self.status_message = "Invalid regex.".to_string();
+                        self.mode = Mode::Find;
+                        return;
+                    };
+                    let Some(page) = self.tabs.get(self.active_tab_index) else {
+                        return;
+                    };
+                    let planned = search::plan_replacements(
+                        &regex,
+                        page.lines().enumerate(),
+                        &template,
+                        true,
+                    );
+                    // Start from the match Find navigation was on and wrap around.
+                    let start = self
+                        .find_matches
+                        .get(self.current_match_index)
+                        .and_then(|current| {
+                            planned
+                                .iter()
+                                .position(|(m, _)| (m.row, m.start) >= (current.row, current.start))
+                        })
+                        .unwrap_or(0);
+                    self.find_query.clear();
+                    self.find_navigation_active = false;
+                    self.start_replace(planned, true, start);
+                }
+                _ => {}
+            }
+        }
+
+        /// Runs a parsed `:s` command on the active page.
+        fn substitute(&mut self, substitute: Substitute) {
+            let options = SearchOptions {
+                regex: true,
+                case: substitute.case,
+                whole_word: false,
+            };
+            let regex = match search::compile(&substitute.pattern, options) {
+                Ok(regex) => regex,
+                Err(_) => {
+                    self.status_message = format!("Invalid regex: {}", substitute.pattern);
+                    return;
+                }
+            };
+            let Some(page) = self.tabs.get(self.active_tab_index) else {
+                return;
+            };
+            let (first, last) = substitute.rows;
+            let mut planned = search::plan_replacements(
+                &regex,
+                (first..=last).map(|row| (row, page.line(row))),
+                &search::expand_template(&substitute.replacement),
+                substitute.global,
+            );
+            if substitute.in_selection
+                && let Some((start, end)) = page.selection_range()
+            {
+                planned.retain(|(m, _)| {
+                    page.pos_to_char(m.row, m.start) >= start && page.pos_to_char(m.row, m.end) <= end
+                });
+            }
+            if planned.is_empty() {
+                self.status_message = format!("Pattern not found: {}", substitute.pattern);
+                return;
+            }
+            self.start_replace(planned, substitute.confirm, 0);
+        }
+
+        /// Begins replacing `planned` matches on the active page, asking about
+        /// each one if `confirm` is set. All replacements form one undo step.
+        fn start_replace(&mut self, planned: Vec<(Match, String)>, confirm: bool, start: usize) {
+            let Some(page) = self.tabs.get(self.active_tab_index) else {
+                return;
+            };
+            if page.read_only {
+                self.status_message = "Buffer is read-only.".to_string();
+                self.mode = self.idle_mode();
+                return;
+            }
+            if planned.is_empty() {
+                self.status_message = "No matches to replace.".to_string();
+                self.mode = self.idle_mode();
+                return;
+            }
+            let pending: Vec<_> = planned
+                .iter()
+                .map(|(m, text)| {
+                    let start = page.pos_to_char(m.row, m.start);
+                    (start, start + (m.end - m.start), text.clone())
+                })
+                .collect();
+            self.find_matches = planned.into_iter().map(|(m, _)| m).collect();
+            self.current_match_index = start.min(self.find_matches.len() - 1);
+            self.replace_session = Some(ReplaceSession {
+                tab_index: self.active_tab_index,
+                accepted: vec![if confirm { None } else { Some(true) }; pending.len()],
+                pending,
+            });
+            if confirm {
+                self.mode = Mode::ConfirmReplace;
+                self.jump_to_match();
+            } else {
+                self.finish_replace();
+            }
+        }
+
+        fn handle_replace_confirm_event(&mut self, key_code: KeyCode) {
+            let index = self.current_match_index;
+            let Some(session) = &mut self.replace_session else {
+                return;
+            };
+            match key_code {
+                KeyCode::Char(c @ ('y' | 'n')) => {
+                    if let Some(answer) = session.accepted.get_mut(index) {
+                        *answer = Some(c == 'y');
+                    }
+                }
+                KeyCode::Char('a') => {
+                    for answer in session.accepted.iter_mut().filter(|a| a.is_none()) {
+                        *answer = Some(true);
+                    }
+                }
+                KeyCode::Char('q') | KeyCode::Esc => {
+                    self.finish_replace();
+                    return;
+                }
+                _ => return,
+            }
+            // Go on to the next match not yet answered, wrapping around.
+            let count = session.accepted.len();
+            let next = (1..=count)
+                .map(|step| (index + step) % count)
+                .find(|&i| session.accepted[i].is_none());
+            match next {
+                Some(next) => {
+                    self.current_match_index = next;
+                    self.jump_to_match();
+                }
+                None => self.finish_replace(),
+            }
+        }
+
+        /// Makes the accepted replacements, as one undo step.
+        fn finish_replace(&mut self) {
+            let Some(session) = self.replace_session.take() else {
+                return;
+            };
+            let accepted: Vec<_> = session
+                .pending
+                .into_iter()
+                .zip(session.accepted)
+                .filter_map(|(replacement, answer)| (answer == Some(true)).then_some(replacement))
+                .collect();
+            if !accepted.is_empty()
+                && let Some(page) = self.tabs.get_mut(session.tab_index)
+            {
+                page.begin_transaction();
+                // From the end, so the ranges before each one stay put.
+                for (start, end, text) in accepted.iter().rev() {
+                    page.replace_range(*start, *end, text);
+                }
+                page.end_transaction();
+            }
+            self.find_matches.clear();
+            self.mode = self.idle_mode();
+            self.status_message = format!("Replaced {} occurrence(s).", accepted.len());
+        }
+
+        /// Drops a replace session left by switching modes or tabs, without
+        /// replacing anything.
+        fn cancel_stale_replace(&mut self) {
+            if self.replace_session.as_ref().is_some_and(|session| {
+                self.mode != Mode::ConfirmReplace || self.active_tab_index != session.tab_index
+            }) {
+                self.replace_session = None;
+                self.find_matches.clear();
+                self.status_message = "Replace cancelled.".to_string();
+            }
+        }
+
+        fn update_search_matches(&mut self) {
+            self.find_matches.clear();
+            self.find_error = None;
+            if self.find_query.is_empty() {
+                return;
+            }
+            let regex = match search::compile(&self.find_query, self.find_options) {
+                Ok(regex) => regex,
+                Err(_) => {
+                    self.find_error = Some("invalid regex".to_string());
+                    return;
+                }
+            };
+            if let Some(page) = self.tabs.get(self.active_tab_index) {
+                self.find_matches = search::find_all(&regex, page.lines());
+            }
+            if !self.find_matches.is_empty() {
+                self.current_match_index = 0;
+                self.jump_to_match();
+            }
+        }
+
+        fn jump_to_next_match(&mut self) {
+            if !self.find_matches.is_empty() {
+                self.current_match_index = (self.current_match_index + 1) % self.find_matches.len();
+                self.jump_to_match();
+            }
+        }
+
+        fn jump_to_prev_match(&mut self) {
+            if !self.find_matches.is_empty() {
+                self.current_match_index = (self.current_match_index + self.find_matches.len() - 1) % self.find_matches.len();
+                self.jump_to_match();
+            }
+        }
+        
+        fn jump_to_match(&mut self) {
+            let current = self.find_matches.get(self.current_match_index).copied();
+            if let Some(m) = current
+                && let Some(page) = self.get_active_page()
+            {
+                page.move_cursor_to(m.row, m.start);
+            }
+        }
+
+        fn handle_prompt_event(&mut self, key_code: KeyCode) {
+            match key_code {
+                KeyCode::Esc => {
+                    self.status_message = "Save cancelled.".to_string();
+                    self.command_buffer.clear();
+                    self.mode = Mode::Command;
+                }
+                KeyCode::Char(c) => self.command_buffer.push(c),
+                KeyCode::Backspace => {
+                    self.command_buffer.pop();
+                }
+                KeyCode::Enter if !self.command_buffer.is_empty() => {
+                    let file_name = self.command_buffer.clone();
+                    let mut path = self.directory_view.path.clone();
+                    path.push(file_name);
+
+                    let should_quit_after = self.mode == Mode::PromptSaveAndQuit;
+
+                    match self.write_page(self.active_tab_index, &path) {
+                        Ok(_) => {
+                            self.set_active_path(path.clone());
+                            self.status_message = format!("Saved to {}", path.display());
+                            self.mode = Mode::Command;
+                            if should_quit_after {
+                                self.quit(false);
+                            }
+                            // Refresh the directory view to show the new file.
+                            let current_dir_path = self.directory_view.path.clone();
+                            if let Ok(new_view) = DirectoryView::new(current_dir_path) {
+                                self.directory_view = new_view;
+                            }
+                        }
+                        Err(e) => {
+                            self.status_message = format!("Error: {}", e);
+                            self.mode = Mode::Command;
+                        }
+                    }
+                    self.command_buffer.clear();
+                }
+                _ => {}
+            }
+        }
+
+        fn open_selected_entry(&mut self) {
+            if let Some(entry) = self.directory_view.entries.get(self.directory_view.selected_index)
+            {
+                let path = entry.path();
+                if path.is_dir() {
+                    self.directory_view = DirectoryView::new(path).unwrap_or_else(|e| {
+                        self.status_message = format!("Error: {}", e);
+                        DirectoryView::new(self.directory_view.path.clone()).unwrap()
+                    });
+                } else {
+                    self.open_path(path);
+                }
+            }
+        }
+
+        /// Focuses the tab editing `path`, opening it in a new tab if needed.
+        /// Returns false if the file could not be opened.
+        fn open_path(&mut self, path: PathBuf) -> bool {
+            if let Some(index) = self
+                .tabs
+                .iter()
+                .position(|p| p.file_path.as_ref() == Some(&path))
+            {
+                self.active_tab_index = index;
+                self.active_pane = ActivePane::Editor;
+                self.mode = self.editing_mode();
+                self.detect_disk_change(None);
+            } else if let Some(page) = self.load_page(path) {
+                self.tabs.push(page);
+                self.active_tab_index = self.tabs.len() - 1;
+                self.active_pane = ActivePane::Editor;
+                self.mode = self.editing_mode();
+                self.offer_recovery();
+            } else {
+                return false;
+            }
+            true
+        }
+
+        /// Opens the file picker over every file under the directory view's
+        /// root, with `query` already typed.
+        fn open_file_picker(&mut self, query: &str) {
+            let root = self.directory_view.path.clone();
+            let files = grep::files(&root)
+                .filter_map(|path| {
+                    path.strip_prefix(&root)
+                        .ok()
+                        .map(|p| p.to_string_lossy().into_owned())
+                })
+                .collect();
+            self.file_picker = Some(FilePicker {
+                query: query.to_string(),
+                files,
+                matches: Vec::new(),
+                list: ListView::default(),
+                preview: Vec::new(),
+                resume_mode: self.mode,
+            });
+            self.mode = Mode::FilePicker;
+            self.update_picker_matches();
+        }
+
+        fn update_picker_matches(&mut self) {
+            if let Some(picker) = &mut self.file_picker {
+                picker.matches = fuzzy::rank(&picker.query, &picker.files);
+                let items = picker.matches.iter().map(|&i| picker.files[i].clone()).collect();
+                picker.list = ListView::new(items);
+            }
+            self.update_picker_preview();
+        }
+
+        fn update_picker_preview(&mut self) {
+            let root = self.directory_view.path.clone();
+            let Some(picker) = &mut self.file_picker else {
+                return;
+            };
+            let Some(path) = picker
+                .matches
+                .get(picker.list.selected_index)
+                .map(|&i| root.join(&picker.files[i]))
+            else {
+                picker.preview.clear();
+                return;
+            };
+            picker.preview = match fs::read(&path) {
+                Ok(bytes) => {
+                    let decoded = fileformat::decode(&bytes);
+                    if decoded.binary {
+                        vec!["(binary file)".to_string()]
+                    } else {
+                        let (_, body) = FileFormat::detect(&decoded.text);
+                        body.lines().take(PREVIEW_LINES).map(str::to_string).collect()
+                    }
+                }
+                Err(e) => vec![format!("(cannot read: {})", e)],
+            };
+        }
+
+        fn handle_file_picker_event(&mut self, event: KeyEvent, page_size: usize) {
+            let Some(picker) = &mut self.file_picker else {
+                self.mode = Mode::Command;
+                return;
+            };
+            match event.code {
+                KeyCode::Esc => {
+                    self.mode = picker.resume_mode;
+                    self.file_picker = None;
+                }
+                KeyCode::Enter => {
+                    let selected = picker.matches.get(picker.list.selected_index).copied();
+                    let Some(index) = selected else {
+                        return;
+                    };
+                    let path = self.directory_view.path.join(&picker.files[index]);
+                    self.mode = picker.resume_mode;
+                    self.file_picker = None;
+                    self.open_path(path);
+                }
+                KeyCode::Up => picker.list.move_up(1),
+                KeyCode::Down => picker.list.move_down(1),
+                KeyCode::Char('k') if event.modifiers == KeyModifiers::CONTROL => picker.list.move_up(1),
+                KeyCode::Char('j') if event.modifiers == KeyModifiers::CONTROL => picker.list.move_down(1),
+                KeyCode::PageUp => picker.list.move_up(page_size),
+                KeyCode::PageDown => picker.list.move_down(page_size),
+                KeyCode::Backspace => {
+                    picker.query.pop();
+                    self.update_picker_matches();
+                    return;
+                }
+                KeyCode::Char(c) => {
+                    picker.query.push(c);
+                    self.update_picker_matches();
+                    return;
+                }
+                _ => return,
+            }
+            self.update_picker_preview();
+        }
+
+        /// Searches the project rooted at the directory view for `pattern`
+        /// and shows the hits in a list.
+        fn run_grep(&mut self, pattern: &str) {
+            let options = SearchOptions {
+                regex: true,
+                ..SearchOptions::default()
+            };
+            let Ok(regex) = search::compile(pattern, options) else {
+                self.status_message = format!("Invalid regex: {}", pattern);
+                return;
+            };
+            let root = self.directory_view.path.clone();
+            let (hits, truncated) = grep::search(&root, &regex, GREP_LIMIT);
+            if hits.is_empty() {
+                self.status_message = format!("No matches for {}", pattern);
+                return;
+            }
+            let items = hits
+                .iter()
+                .map(|hit| {
+                    let path = hit.path.strip_prefix(&root).unwrap_or(&hit.path);
+                    format!("{}:{}: {}", path.display(), hit.row + 1, hit.preview)
+                })
+                .collect();
+            self.grep = Some(GrepResults {
+                query: pattern.to_string(),
+                hits,
+                truncated,
+                list: ListView::new(items),
+            });
+            self.mode = Mode::GrepResults;
+        }
+
+        fn handle_grep_event(&mut self, key_code: KeyCode, page_size: usize) {
+            let Some(grep) = &mut self.grep else {
+                self.mode = Mode::Command;
+                return;
+            };
+            match key_code {
+                KeyCode::Up | KeyCode::Char('k') => grep.list.move_up(1),
+                KeyCode::Down | KeyCode::Char('j') => grep.list.move_down(1),
+                KeyCode::PageUp => grep.list.move_up(page_size),
+                KeyCode::PageDown => grep.list.move_down(page_size),
+                KeyCode::Esc | KeyCode::Char('q') => self.mode = self.idle_mode(),
+                KeyCode::Enter => {
+                    let Some(hit) = grep.hits.get(grep.list.selected_index) else {
+                        return;
+                    };
+                    let (path, row, col) = (hit.path.clone(), hit.row, hit.col);
+                    if self.open_path(path)
+                        && let Some(page) = self.get_active_page()
+                    {
+                        page.move_cursor_to(row, col);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        fn execute_command(&mut self) {
+            let cmd_line = self.command_buffer.clone();
+            if let Some(page) = self.tabs.get(self.active_tab_index)
+                && let Some(parsed) = Substitute::parse(
+                    cmd_line.trim(),
+                    page.cursor_row(),
+                    page.line_count() - 1,
+                    page.selection_rows(),
+                )
+            {
+                match parsed {
+                    Ok(substitute) => self.substitute(substitute),
+                    Err(e) => self.status_message = e,
+                }
+                if let Some(page) = self.get_active_page() {
+                    page.clear_selection();
+                }
+                self.command_buffer.clear();
+                return;
+            }
+            let parts: Vec<&str> = cmd_line.split_whitespace().collect();
+            let command = parts.first().cloned().unwrap_or("");
+            let arg = parts.get(1).cloned();
+
+            match command {
+                "f" | "find" => {
+                    self.mode = Mode::Find;
+                    self.find_query.clear();
+                }
+                "grep" => {
+                    let pattern = cmd_line.trim_start()[command.len()..].trim();
+                    if !pattern.is_empty() {
+                        self.run_grep(pattern);
+                    } else if self.grep.is_some() {
+                        self.mode = Mode::GrepResults;
+                    } else {
+                        self.status_message = "Usage: grep <regex>".to_string();
+                    }
+                }
+                "open" => {
+                    let query = cmd_line.trim_start()[command.len()..].trim();
+                    self.open_file_picker(query);
+                }
+                "clipboard" => match arg.map(ClipboardMode::parse) {
+                    Some(Some(mode)) => self.set_setting("clipboard", mode.name()),
+                    Some(None) => {
+                        self.status_message =
+                            "Clipboard modes: auto, osc52, wl-copy, xclip, xsel, internal".to_string();
+                    }
+                    None => {
+                        self.status_message =
+                            format!("Clipboard is {}", self.clipboard.mode().name());
+                    }
+                },
+                "syntax" if !self.tabs.is_empty() => self.set_syntax(arg),
+                "set" => self.run_set(&cmd_line.trim_start()[command.len()..]),
+                "goto" => self.goto(arg),
+                "vim" => match arg {
+                    Some(value @ ("on" | "off")) => self.set_setting("vim", value),
+                    _ => {
+                        let state = if self.settings.vim { "on" } else { "off" };
+                        self.status_message = format!("Vim keys are {} (vim on|off)", state);
+                    }
+                },
+                _ if command.starts_with(|c: char| c.is_ascii_digit()) => self.goto(Some(command)),
+                "v" | "visual" => self.start_visual(false),
+                "V" | "vline" => self.start_visual(true),
+                "replace" if !self.tabs.is_empty() => {
+                    self.mode = Mode::Find;
+                    self.find_query.clear();
+                    self.find_then_replace = true;
+                }
+                "q" | "quit" => self.close_active_tab(false),
+                "q!" | "quit!" => self.close_active_tab(true),
+                "x" | "exit" => self.quit(false),
+                "x!" | "exit!" => self.quit(true),
+                "wx" => {
+                    // Resolve files changed behind our back before writing anything.
+                    let conflict = self.tabs.iter().position(|p| {
+                        p.is_modified() && p.disk_status() != DiskStatus::Unchanged
+                    });
+                    if let Some(index) = conflict {
+                        self.active_tab_index = index;
+                        self.detect_disk_change(None);
+                        self.command_buffer.clear();
+                        return;
+                    }
+                    let mut errors = Vec::new();
+                    for index in 0..self.tabs.len() {
+                        let page = &self.tabs[index];
+                        if page.read_only || !page.is_modified() {
+                            continue;
+                        }
+                        if let Some(path) = page.file_path.clone()
+                            && let Err(e) = self.write_page(index, &path)
+                        {
+                            errors.push(format!("{}: {}", path.display(), e));
+                        }
+                    }
+
+                    if !errors.is_empty() {
+                        self.status_message = format!("Errors saving files: {}", errors.join(", "));
+                    } else {
+                        self.status_message = "All files saved.".to_string();
+                        self.quit(false);
+                    }
+                }
+                "h" | "help" => {
+                    self.status_message =
+                        "Help | Modes: Esc (Cmd/Edit), Ctrl+W (Dir) | Cmds: f, replace, s/a/b/gc, grep, open (Ctrl+P), goto, <line>, v, V, vim, set, syntax, q, q!, w, wq, x, x!, wx, r, u, redo, enc, eol, bom, eofnl, backup, clipboard | Dir Cmds: nf, nd, rn, d"
+                            .to_string();
+                }
+                "r" | "revert" => self.revert_active_file(),
+                "u" | "undo" => self.undo(),
+                "redo" => self.redo(),
+                "enc" | "eol" | "bom" | "eofnl" => self.set_file_format(command, arg),
+                "backup" => match arg {
+                    Some(value @ ("on" | "off")) => self.set_setting("backup", value),
+                    _ => {
+                        let state = if self.settings.backup { "on" } else { "off" };
+                        self.status_message = format!("Backups are {} (backup on|off)", state);
+                    }
+                },
+                "w" | "write" => { self.save_active_file(arg, AfterSave::Nothing); },
+                "wq" => { self.save_active_file(arg, AfterSave::CloseTab); },
+                _ => self.status_message = format!("Unknown command: {}", cmd_line),
+            }
+            self.command_buffer.clear();
+        }
+
+        fn revert_active_file(&mut self) {
+            let file_path = self.get_active_page().and_then(|p| p.file_path.clone());
+            if let Some(path) = file_path {
+                if let Some(page) = self.get_active_page() {
+                    match page.reload() {
+                        Ok(()) => self.status_message = "Reverted to saved version.".to_string(),
+                        Err(_) => {
+                            self.status_message = format!("Error reading file: {}", path.display())
+                        }
+                    }
+                }
+            } else {
+                self.status_message = "No file to revert from.".to_string();
+            }
+        }
+
+        /// Handles `enc <encoding>`, `eol lf|crlf`, `bom on|off` and
+        /// `eofnl on|off`, which change how the active buffer is written on the
+        /// next save.
+        fn set_file_format(&mut self, command: &str, arg: Option<&str>) {
+            let Some(page) = self.tabs.get_mut(self.active_tab_index) else {
+                self.status_message = "No active file.".to_string();
+                return;
+            };
+            if let Some(value) = arg {
+                let on = match value {
+                    "on" | "yes" | "true" => Some(true),
+                    "off" | "no" | "false" => Some(false),
+                    _ => None,
+                };
+                let applied = match command {
+                    "enc" => Encoding::parse(value).map(|e| page.format.encoding = e),
+                    "eol" => LineEnding::parse(value).map(|l| page.format.line_ending = l),
+                    "bom" => on.map(|on| page.format.bom = on),
+                    "eofnl" => on.map(|on| page.format.final_newline = on),
+                    _ => None,
+                };
+                if applied.is_none() {
+                    self.status_message = format!("Invalid value for {}: {}", command, value);
+                    return;
+                }
+            }
+            self.status_message = format!("File format: {}", page.format.describe());
+        }
+
+        fn undo(&mut self) {
+            if let Some(page) = self.get_active_page()
+                && !page.undo()
+            {
+                self.status_message = "Already at oldest change.".to_string();
+            }
+        }
+
+        fn redo(&mut self) {
+            if let Some(page) = self.get_active_page()
+                && !page.redo()
+            {
+                self.status_message = "Already at newest change.".to_string();
+            }
+        }
+
+        fn save_active_file(&mut self, arg: Option<&str>, after: AfterSave) -> bool {
+            let path_from_arg = arg.map(PathBuf::from);
+
+            let path_from_page = self
+                .tabs
+                .get(self.active_tab_index)
+                .and_then(|p| p.file_path.clone());
+
+            let path_to_write = path_from_arg.or(path_from_page.clone());
+
+            if let Some(path) = path_to_write {
+                if path_from_page.as_ref() == Some(&path)
+                    && self.detect_disk_change(Some(after))
+                {
+                    return false;
+                }
+                self.finish_save(path, after)
+            } else {
+                self.mode = if after == AfterSave::Quit {
+                    Mode::PromptSaveAndQuit
+                } else {
+                    Mode::PromptSave
+                };
+                self.command_buffer.clear();
+                false
+            }
+        }
+
+        /// Writes the active page to `path`, then runs `after`.
+        fn finish_save(&mut self, path: PathBuf, after: AfterSave) -> bool {
+            match self.write_page(self.active_tab_index, &path) {
+                Ok(_) => {
+                    self.status_message = format!("Saved to {}", path.display());
+                    self.set_active_path(path);
+                    match after {
+                        AfterSave::Nothing => {}
+                        AfterSave::CloseTab => self.close_active_tab(true),
+                        AfterSave::Quit => self.quit(false),
+                    }
+                    true
+                }
+                Err(e) => {
+                    self.status_message = format!("Error: {}", e);
+                    false
+                }
+            }
+        }
+
+        /// Makes tab `index` active and checks its file for outside changes.
+        fn focus_tab(&mut self, index: usize) {
+            self.active_tab_index = index;
+            self.detect_disk_change(None);
+        }
+
+        /// Checks whether the active page's file changed on disk and, if so,
+        /// asks the user how to resolve it. `pending_save` is the save that
+        /// was interrupted by the check, if any. Returns true if prompting.
+        pub fn detect_disk_change(&mut self, pending_save: Option<AfterSave>) -> bool {
+            if self.mode == Mode::ConfirmReload {
+                return true;
+            }
+            let status = self
+                .tabs
+                .get(self.active_tab_index)
+                .map_or(DiskStatus::Unchanged, |p| p.disk_status());
+            if status == DiskStatus::Unchanged {
+                return false;
+            }
+            self.disk_conflict = Some(DiskConflict {
+                tab_index: self.active_tab_index,
+                deleted: status == DiskStatus::Deleted,
+                pending_save,
+                resume_mode: self.mode,
+            });
+            self.mode = Mode::ConfirmReload;
+            true
+        }
+
+        fn handle_reload_confirm_event(&mut self, key_code: KeyCode) {
+            let Some(conflict) = self.disk_conflict.take() else {
+                self.mode = Mode::Command;
+                return;
+            };
+            self.mode = conflict.resume_mode;
+            self.active_tab_index = conflict.tab_index;
+            match key_code {
+                KeyCode::Char('r') => {
+                    if let Some(page) = self.get_active_page() {
+                        match page.reload() {
+                            Ok(()) => self.status_message = "Reloaded from disk.".to_string(),
+                            Err(e) => self.status_message = format!("Error reloading: {}", e),
+                        }
+                    }
+                }
+                KeyCode::Char('o') => {
+                    let path = self.tabs.get(self.active_tab_index).and_then(|p| p.file_path.clone());
+                    if let Some(path) = path {
+                        self.finish_save(path, conflict.pending_save.unwrap_or(AfterSave::Nothing));
+                    }
+                }
+                KeyCode::Char('d') => self.open_disk_diff(conflict.tab_index),
+                KeyCode::Char('k') | KeyCode::Esc => {
+                    // Keeping the buffer acknowledges the new disk state, so
+                    // the next save writes without asking again.
+                    if let Some(page) = self.get_active_page()
+                        && let Some(path) = page.file_path.clone()
+                    {
+                        page.disk_stamp = fs::read(&path)
+                            .ok()
+                            .and_then(|bytes| DiskStamp::capture(&path, &bytes));
+                    }
+                    self.status_message = "Kept buffer contents.".to_string();
+                }
+                _ => {
+                    self.mode = Mode::ConfirmReload;
+                    self.disk_conflict = Some(conflict);
+                }
+            }
+        }
+
+        /// Opens a read-only tab showing how the buffer at `index` differs
+        /// from its file on disk.
+        fn open_disk_diff(&mut self, index: usize) {
+            let Some(page) = self.tabs.get(index) else {
+                return;
+            };
+            let Some(path) = page.file_path.clone() else {
+                return;
+            };
+            // Both sides are compared in normalized form with one trailing
+            // newline, so only real content differences show up.
+            let disk_text = fs::read(&path)
+                .map(|bytes| FileFormat::detect(&fileformat::decode(&bytes).text).1 + "\n")
+                .unwrap_or_default();
+            let buffer_text = page.text() + "\n";
+            let name = page.display_name();
+            self.open_diff_tab(
+                &format!("{}.diff", name),
+                (&format!("{} (disk)", name), &disk_text),
+                (&format!("{} (buffer)", name), &buffer_text),
+            );
+        }
+
+        /// Opens a read-only tab titled `title` with a unified diff from the
+        /// labelled `old` text to the labelled `new` text.
+        fn open_diff_tab(&mut self, title: &str, old: (&str, &str), new: (&str, &str)) {
+            let diff = TextDiff::from_lines(old.1, new.1)
+                .unified_diff()
+                .header(old.0, new.0)
+                .to_string();
+
+            let mut diff_page = Page::new();
+            diff_page.load_from_string(&diff);
+            diff_page.read_only = true;
+            diff_page.title = Some(title.to_string());
+            self.tabs.push(diff_page);
+            self.active_tab_index = self.tabs.len() - 1;
+            self.active_pane = ActivePane::Editor;
+            self.mode = self.editing_mode();
+        }
+
+        /// Writes the page at `index` to `path` through the atomic save routine.
+        /// Every save path goes through here.
+        fn write_page(&mut self, index: usize, path: &Path) -> io::Result<()> {
+            let content = match self.tabs.get(index) {
+                Some(page) => page.to_file_bytes()?,
+                None => Vec::new(),
+            };
+            let options = SaveOptions { backup: self.settings.backup };
+            let target = save::write_atomic(path, &content, options)?;
+            if let Some(page) = self.tabs.get_mut(index) {
+                page.disk_stamp = DiskStamp::capture(&target, &content);
+                page.mark_saved();
+                discard_swap(page);
+            }
+            Ok(())
+        }
+
+        /// Called regularly by the main loop; writes swap files for modified
+        /// buffers at most once per `SWAP_INTERVAL` and gives up on chords
+        /// that waited too long for their next key. Returns true if the
+        /// screen needs redrawing.
+        pub fn tick(&mut self) -> bool {
+            if self.last_swap_write.elapsed() >= SWAP_INTERVAL {
+                self.last_swap_write = Instant::now();
+                self.write_swap_files();
+            }
+            if !self.pending_keys.is_empty() && self.chord_started.elapsed() >= self.keymap.timeout {
+                self.flush_chord();
+                return true;
+            }
+            false
+        }
+
+        /// Brings every buffer's swap file up to date: modified buffers are
+        /// written out, and swap files of buffers that are clean again removed.
+        pub fn write_swap_files(&mut self) {
+            for page in &mut self.tabs {
+                let Some(path) = page.file_path.clone() else {
+                    continue;
+                };
+                if !page.is_modified() {
+                    discard_swap(page);
+                } else if page.swap_revision != Some(page.revision())
+                    && swap::write(&path, &page.text()).is_ok()
+                {
+                    page.swap_revision = Some(page.revision());
+                }
+            }
+        }
+
+        /// Offers to restore the active page from a swap file left behind by
+        /// an earlier session that did not exit cleanly.
+        fn offer_recovery(&mut self) {
+            let Some(page) = self.tabs.get(self.active_tab_index) else {
+                return;
+            };
+            let Some(path) = page.file_path.clone() else {
+                return;
+            };
+            let Some(text) = swap::read(&path) else {
+                return;
+            };
+            if text == page.text() {
+                swap::remove(&path);
+                return;
+            }
+            self.recovery = Some(Recovery {
+                tab_index: self.active_tab_index,
+                text,
+                resume_mode: self.mode,
+            });
+            self.mode = Mode::ConfirmRecover;
+        }
+
+        fn handle_recover_confirm_event(&mut self, key_code: KeyCode) {
+            let Some(recovery) = self.recovery.take() else {
+                self.mode = Mode::Command;
+                return;
+            };
+            let path = self
+                .tabs
+                .get(recovery.tab_index)
+                .and_then(|p| p.file_path.clone());
+            match key_code {
+                KeyCode::Char('r') => {
+                    self.active_tab_index = recovery.tab_index;
+                    if let Some(page) = self.get_active_page() {
+                        // Swap files hold the buffer text; the format stays the file's.
+                        page.replace_text(&recovery.text);
+                        // The swap file already holds exactly these contents.
+                        page.swap_revision = Some(page.revision());
+                    }
+                    self.status_message = "Recovered unsaved changes; :w to keep them.".to_string();
+                }
+                KeyCode::Char('d') => {
+                    let page = &self.tabs[recovery.tab_index];
+                    let name = page.display_name();
+                    let disk_text = page.text() + "\n";
+                    let swap_text = recovery.text.clone() + "\n";
+                    self.open_diff_tab(
+                        &format!("{}.swp.diff", name),
+                        (&format!("{} (disk)", name), &disk_text),
+                        (&format!("{} (swap)", name), &swap_text),
+                    );
+                    self.mode = Mode::ConfirmRecover;
+                    self.recovery = Some(recovery);
+                    return;
+                }
+                KeyCode::Char('D') => {
+                    self.active_tab_index = recovery.tab_index;
+                    if let Some(path) = &path {
+                        swap::remove(path);
+                    }
+                    self.status_message = "Discarded swap file.".to_string();
+                }
+                KeyCode::Esc => {
+                    self.active_tab_index = recovery.tab_index;
+                    self.status_message = "Swap file kept; it will be offered again next time.".to_string();
+                }
+                _ => {
+                    self.recovery = Some(recovery);
+                    return;
+                }
+            }
+            self.mode = recovery.resume_mode;
+        }
+
+        /// Closes the active tab, asking first if it has unsaved changes
+        /// unless `force` is set.
+        fn close_active_tab(&mut self, force: bool) {
+            let modified = self
+                .tabs
+                .get(self.active_tab_index)
+                .is_some_and(|p| p.is_modified());
+            if modified && !force {
+                self.pending_action = Some(PendingAction::CloseTab);
+                self.mode = Mode::ConfirmDiscard;
+                return;
+            }
+            if !self.tabs.is_empty() {
+                let mut page = self.tabs.remove(self.active_tab_index);
+                discard_swap(&mut page);
+            }
+            if self.tabs.is_empty() {
+                self.mode = Mode::Command;
+                self.active_tab_index = 0;
+            } else if self.active_tab_index >= self.tabs.len() {
+                self.active_tab_index = self.tabs.len() - 1;
+            }
+        }
+
+        /// Quits the editor, asking first if any tab has unsaved changes
+        /// unless `force` is set.
+        fn quit(&mut self, force: bool) {
+            if !force && self.modified_tab_count() > 0 {
+                self.pending_action = Some(PendingAction::Quit);
+                self.mode = Mode::ConfirmDiscard;
+                return;
+            }
+            self.tabs.iter_mut().for_each(discard_swap);
+            self.should_quit = true;
+        }
+
+        pub fn modified_tab_count(&self) -> usize {
+            self.tabs.iter().filter(|p| p.is_modified()).count()
+        }
+
+        /// Number of modified tabs whose file is `path` or lies beneath it.
+        pub fn modified_tabs_under(&self, path: &Path) -> usize {
+            self.tabs
+                .iter()
+                .filter(|p| p.is_modified())
+                .filter(|p| p.file_path.as_ref().is_some_and(|fp| fp.starts_with(path)))
+                .count()
+        }
+
+        fn handle_discard_confirm_event(&mut self, key_code: KeyCode) {
+            match key_code {
+                KeyCode::Char('y') | KeyCode::Char('Y') => {
+                    self.mode = Mode::Command;
+                    match self.pending_action.take() {
+                        Some(PendingAction::CloseTab) => self.close_active_tab(true),
+                        Some(PendingAction::Quit) => self.quit(true),
+                        Some(PendingAction::Delete) => self.delete_path(true),
+                        None => {}
+                    }
+                }
+                KeyCode::Char('n') | KeyCode::Char('N') | KeyCode::Esc => {
+                    self.status_message = "Cancelled.".to_string();
+                    self.mode = self.idle_mode();
+                    if self.pending_action.take() == Some(PendingAction::Delete) {
+                        self.path_to_delete = None;
+                        self.status_message = "Delete cancelled.".to_string();
+                        self.mode = Mode::FileTree;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        pub fn get_active_page(&mut self) -> Option<&mut Page> {
+            self.tabs.get_mut(self.active_tab_index)
+        }
+    }
+}
+
+/// Undo/redo bookkeeping for buffer edits.
+pub mod history {
+    use std::collections::VecDeque;
+
+    /// Maximum number of undo steps kept per buffer.
+    const MAX_UNDO_STEPS: usize = 1000;
+
+    /// A single reversible change, addressed by absolute char index.
+    #[derive(Clone, Debug)]
+    pub enum Edit {
+        Insert { at: usize, text: String },
+        Delete { at: usize, text: String },
+    }
+
+    impl Edit {
+        pub fn inverse(&self) -> Edit {
+            match self {
+                Edit::Insert { at, text } => Edit::Delete {
+                    at: *at,
+                    text: text.clone(),
+                },
+                Edit::Delete { at, text } => Edit::Insert {
+                    at: *at,
+                    text: text.clone(),
+                },
+            }
+        }
+    }
+
+    /// The user action that produced an edit. Runs of `Typing` or
+    /// `Deleting` edits are merged into a single undo step.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum EditKind {
+        Typing,
+        Deleting,
+        Newline,
+        MergeLines,
+        Other,
+    }
+
+    impl EditKind {
+        fn groups(self) -> bool {
+            matches!(self, EditKind::Typing | EditKind::Deleting)
+        }
+    }
+
+    /// One undo step: a list of edits plus where the cursor was around them.
+    #[derive(Clone, Debug)]
+    pub struct Transaction {
+        /// Unique per history; identifies the buffer state after this step.
+        pub id: u64,
+        pub edits: Vec<Edit>,
+        pub cursor_before: (usize, usize),
+        pub cursor_after: (usize, usize),
+    }
+
+    #[derive(Default)]
+    pub struct History {
+        undo: VecDeque<Transaction>,
+        redo: Vec<Transaction>,
+        open_kind: Option<EditKind>,
+        explicit_depth: usize,
+        next_id: u64,
+        /// The id of the newest step dropped off the bottom of the undo
+        /// stack: the revision once everything left has been undone.
+        base_id: u64,
+    }
+
+    impl History {
+        pub fn new() -> Self {
+            Self::default()
+        }
+
+        /// Records an edit that has already been applied to the buffer.
+        pub fn record(
+            &mut self,
+            edit: Edit,
+            kind: EditKind,
+            cursor_before: (usize, usize),
+            cursor_after: (usize, usize),
+        ) {
+            self.redo.clear();
+            let extend = self.explicit_depth > 0
+                || (kind.groups() && self.open_kind == Some(kind));
+            match self.undo.back_mut() {
+                Some(last) if extend => {
+                    last.cursor_after = cursor_after;
+                    push_coalesced(&mut last.edits, edit);
+                }
+                _ => {
+                    let id = self.allocate_id();
+                    self.push_undo(Transaction {
+                        id,
+                        edits: vec![edit],
+                        cursor_before,
+                        cursor_after,
+                    });
+                }
+            }
+            if self.explicit_depth == 0 {
+                self.open_kind = kind.groups().then_some(kind);
+            }
+        }
+
+        /// Ends the current run of grouped typing, e.g. after a cursor move.
+        pub fn break_group(&mut self) {
+            self.open_kind = None;
+        }
+
+        /// Starts an explicit transaction; every edit recorded until the
+        /// matching `end_transaction` becomes a single undo step.
+        pub fn begin_transaction(&mut self, cursor: (usize, usize)) {
+            if self.explicit_depth == 0 {
+                self.open_kind = None;
+                let id = self.allocate_id();
+                self.push_undo(Transaction {
+                    id,
+                    edits: Vec::new(),
+                    cursor_before: cursor,
+                    cursor_after: cursor,
+                });
+            }
+            self.explicit_depth += 1;
+        }
+
+        pub fn end_transaction(&mut self) {
+            if self.explicit_depth == 0 {
+                return;
+            }
+            self.explicit_depth -= 1;
+            if self.explicit_depth == 0
+                && self.undo.back().is_some_and(|t| t.edits.is_empty())
+            {
+                self.undo.pop_back();
+            }
+        }
+
+        /// Moves the newest undo step onto the redo stack and returns it.
+        pub fn undo(&mut self) -> Option<Transaction> {
+            self.open_kind = None;
+            let transaction = self.undo.pop_back()?;
+            self.redo.push(transaction.clone());
+            Some(transaction)
+        }
+
+        /// Moves the newest redo step back onto the undo stack and returns it.
+        pub fn redo(&mut self) -> Option<Transaction> {
+            self.open_kind = None;
+            let transaction = self.redo.pop()?;
+            self.undo.push_back(transaction.clone());
+            Some(transaction)
+        }
+
+        pub fn clear(&mut self) {
+            let next_id = self.next_id;
+            *self = Self::default();
+            self.next_id = next_id;
+        }
+
+        /// Identifies the current buffer state; equal revisions mean equal text.
+        pub fn revision(&self) -> u64 {
+            self.undo.back().map_or(self.base_id, |t| t.id)
+        }
+
+        /// Pushes a new undo step, dropping the oldest beyond the limit.
+        fn push_undo(&mut self, transaction: Transaction) {
+            self.undo.push_back(transaction);
+            if self.undo.len() > MAX_UNDO_STEPS
+                && let Some(dropped) = self.undo.pop_front()
+            {
+                self.base_id = dropped.id;
+            }
+        }
+
+        fn allocate_id(&mut self) -> u64 {
+            self.next_id += 1;
+            self.next_id
+        }
+    }
+
+    /// Appends `edit`, merging it into the previous edit when it continues
+    /// the same insertion or deletion.
+    fn push_coalesced(edits: &mut Vec<Edit>, edit: Edit) {
+        match (edits.last_mut(), &edit) {
+            (Some(Edit::Insert { at, text }), Edit::Insert { at: next_at, text: next })
+                if *at + text.chars().count() == *next_at =>
+            {
+                text.push_str(next);
+            }
+            (Some(Edit::Delete { at, text }), Edit::Delete { at: next_at, text: next })
+                if *next_at + next.chars().count() == *at =>
+            {
+                text.insert_str(0, next);
+                *at = *next_at;
+            }
+            _ => edits.push(edit),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn insert(history: &mut History, at: usize, text: &str, kind: EditKind) {
+            let edit = Edit::Insert { at, text: text.to_string() };
+            history.record(edit, kind, (0, at), (0, at + text.chars().count()));
+        }
+
+        #[test]
+        fn typing_runs_are_grouped() {
+            let mut history = History::new();
+            insert(&mut history, 0, "a", EditKind::Typing);
+            insert(&mut history, 1, "b", EditKind::Typing);
+            history.break_group();
+            insert(&mut history, 2, "c", EditKind::Typing);
+
+            let last = history.undo().unwrap();
+            assert!(matches!(&last.edits[..], [Edit::Insert { at: 2, text }] if text == "c"));
+            let first = history.undo().unwrap();
+            assert!(matches!(&first.edits[..], [Edit::Insert { at: 0, text }] if text == "ab"));
+            assert_eq!(first.cursor_before, (0, 0));
+            assert_eq!(first.cursor_after, (0, 2));
+            assert!(history.undo().is_none());
+        }
+
+        #[test]
+        fn other_edits_are_not_grouped() {
+            let mut history = History::new();
+            insert(&mut history, 0, "a", EditKind::Other);
+            insert(&mut history, 1, "b", EditKind::Other);
+            assert!(history.undo().is_some());
+            assert!(history.undo().is_some());
+            assert!(history.undo().is_none());
+        }
+
+        #[test]
+        fn transactions_are_one_step() {
+            let mut history = History::new();
+            history.begin_transaction((0, 0));
+            insert(&mut history, 0, "a", EditKind::Other);
+            history.begin_transaction((0, 1));
+            insert(&mut history, 5, "b", EditKind::Newline);
+            history.end_transaction();
+            history.end_transaction();
+            assert_eq!(history.undo().unwrap().edits.len(), 2);
+            assert!(history.undo().is_none());
+
+            // An empty transaction leaves no step behind.
+            let revision = history.revision();
+            history.begin_transaction((0, 0));
+            history.end_transaction();
+            assert_eq!(history.revision(), revision);
+            assert!(history.undo().is_none());
+        }
+
+        #[test]
+        fn redo_is_cleared_by_new_edits() {
+            let mut history = History::new();
+            insert(&mut history, 0, "a", EditKind::Other);
+            let step = history.revision();
+            history.undo();
+            assert_eq!(history.redo().unwrap().id, step);
+            assert_eq!(history.revision(), step);
+            history.undo();
+            insert(&mut history, 0, "b", EditKind::Other);
+            assert!(history.redo().is_none());
+        }
+
+        #[test]
+        fn revisions_identify_states() {
+            let mut history = History::new();
+            let empty = history.revision();
+            insert(&mut history, 0, "a", EditKind::Other);
+            let one = history.revision();
+            assert_ne!(one, empty);
+            history.undo();
+            assert_eq!(history.revision(), empty);
+            insert(&mut history, 0, "b", EditKind::Other);
+            assert_ne!(history.revision(), one);
+        }
+
+        #[test]
+        fn revision_after_trim() {
+            let mut history = History::new();
+            let empty = history.revision();
+            for at in 0..=MAX_UNDO_STEPS {
+                insert(&mut history, at, "x", EditKind::Other);
+            }
+            let mut undone = 0;
+            while history.undo().is_some() {
+                undone += 1;
+            }
+            assert_eq!(undone, MAX_UNDO_STEPS);
+            // The oldest step can no longer be undone, so this is not the
+            // empty state.
+            assert_ne!(history.revision(), empty);
+            history.redo();
+            let after_redo = history.revision();
+            history.undo();
+            assert_ne!(history.revision(), after_redo);
+        }
+    }
+}
+
+/// Column arithmetic for a single line of text.
+///
+/// Cursor columns are char indices into a line, but the cursor only ever
+/// rests on grapheme cluster boundaries. Screen positions are display
+/// columns: wide characters take two cells, tabs expand to the next tab
+/// stop and control characters are shown in caret notation (`^X`).
+pub mod text {
+    use unicode_segmentation::UnicodeSegmentation;
+    use unicode_width::UnicodeWidthStr;
+
+    /// Iterates over `(char_col, grapheme)` pairs of `line`.
+    pub fn graphemes(line: &str) -> impl Iterator<Item = (usize, &str)> {
+        let mut col = 0;
+        line.graphemes(true).map(move |g| {
+            let start = col;
+            col += g.chars().count();
+            (start, g)
+        })
+    }
+
+    /// Number of terminal cells `grapheme` occupies when it starts at
+    /// display column `display_col`, with tab stops every `tab_width` cells.
+    pub fn grapheme_width(grapheme: &str, display_col: usize, tab_width: usize) -> usize {
+        if grapheme == "\t" {
+            let tab_width = tab_width.max(1);
+            tab_width - display_col % tab_width
+        } else if grapheme.chars().all(char::is_control) {
+            2 * grapheme.chars().count()
+        } else {
+            grapheme.width()
+        }
+    }
+
+    /// Returns the text a grapheme should be drawn as, given it occupies
+    /// `width` cells.
+    pub fn grapheme_display(grapheme: &str, width: usize) -> String {
+        if grapheme == "\t" {
+            " ".repeat(width)
+        } else if grapheme.chars().all(char::is_control) {
+            grapheme.chars().map(caret_notation).collect()
+        } else {
+            grapheme.to_string()
+        }
+    }
+
+    fn caret_notation(c: char) -> String {
+        let code = c as u32;
+        match code {
+            0x7f => "^?".to_string(),
+            0..=0x1f => format!("^{}", char::from_u32(code + 0x40).unwrap_or('?')),
+            _ => "^?".to_string(),
+        }
+    }
+
+    /// Char column of the grapheme boundary after `col`.
+    pub fn next_boundary(line: &str, col: usize) -> usize {
+        graphemes(line)
+            .map(|(start, g)| start + g.chars().count())
+            .find(|&end| end > col)
+            .unwrap_or_else(|| line.chars().count())
+    }
+
+    /// Char column of the grapheme boundary before `col`.
+    pub fn prev_boundary(line: &str, col: usize) -> usize {
+        graphemes(line)
+            .map(|(start, _)| start)
+            .take_while(|&start| start < col)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Moves `col` back to the start of the grapheme that contains it.
+    pub fn snap_to_boundary(line: &str, col: usize) -> usize {
+        if col >= line.chars().count() {
+            return line.chars().count();
+        }
+        graphemes(line)
+            .map(|(start, _)| start)
+            .take_while(|&start| start <= col)
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Display column at which char column `col` is drawn.
+    pub fn display_col(line: &str, col: usize, tab_width: usize) -> usize {
+        let mut display = 0;
+        for (start, g) in graphemes(line) {
+            if start >= col {
+                break;
+            }
+            display += grapheme_width(g, display, tab_width);
+        }
+        display
+    }
+
+    /// Char column of the grapheme drawn at display column `target`, or the
+    /// end of the line if `target` is past it.
+    pub fn col_at_display(line: &str, target: usize, tab_width: usize) -> usize {
+        let mut display = 0;
+        for (start, g) in graphemes(line) {
+            let width = grapheme_width(g, display, tab_width);
+            if target < display + width {
+                return start;
+            }
+            display += width;
+        }
+        line.chars().count()
+    }
+
+    /// Char columns at which the rows of `line` start when it is wrapped
+    /// to `width` cells. Rows break after whitespace where they can, and
+    /// whitespace at a break may hang past the edge rather than start the
+    /// next row. Each row is measured from its own first cell.
+    pub fn wrap_starts(line: &str, width: usize, tab_width: usize) -> Vec<usize> {
+        let mut starts = vec![0];
+        if width == 0 {
+            return starts;
+        }
+        let graphemes: Vec<_> = graphemes(line).collect();
+        let mut i = 0;
+        while i < graphemes.len() {
+            let row_start = i;
+            let mut display = 0;
+            let mut break_after = None;
+            while i < graphemes.len() {
+                let g = graphemes[i].1;
+                let g_width = grapheme_width(g, display, tab_width);
+                if g.chars().all(char::is_whitespace) {
+                    break_after = Some(i + 1);
+                } else if display + g_width > width && i > row_start {
+                    break;
+                }
+                display += g_width;
+                i += 1;
+            }
+            if i < graphemes.len() {
+                i = break_after.unwrap_or(i);
+                starts.push(graphemes[i].0);
+            }
+        }
+        starts
+    }
+
+    /// Which of the rows starting at `starts` holds char column `col`.
+    pub fn wrap_row(starts: &[usize], col: usize) -> usize {
+        starts.partition_point(|&start| start <= col).saturating_sub(1)
+    }
+
+    /// The chars of `line` from column `start` up to `end`.
+    pub fn slice_cols(line: &str, start: usize, end: usize) -> &str {
+        let byte = |col| line.char_indices().nth(col).map_or(line.len(), |(b, _)| b);
+        &line[byte(start)..byte(end)]
+    }
+
+    /// Total display width of `line`.
+    pub fn line_width(line: &str, tab_width: usize) -> usize {
+        display_col(line, usize::MAX, tab_width)
+    }
+
+    /// Converts a byte offset into `line` to a char column.
+    pub fn byte_to_col(line: &str, byte: usize) -> usize {
+        line[..byte].chars().count()
+    }
+
+    /// The kind of character, for word motions: runs of the same class
+    /// form a word.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum CharClass {
+        Whitespace,
+        Word,
+        Punctuation,
+    }
+
+    pub fn char_class(c: char) -> CharClass {
+        if c.is_whitespace() {
+            CharClass::Whitespace
+        } else if c.is_alphanumeric() || c == '_' {
+            CharClass::Word
+        } else {
+            CharClass::Punctuation
+        }
+    }
+
+    /// Char column of the first non-whitespace char of `line`, or its length.
+    pub fn first_non_blank(line: &str) -> usize {
+        line.chars().take_while(|c| c.is_whitespace()).count()
+    }
+
+    /// Truncates or pads `s` with spaces so it occupies exactly `width` cells.
+    /// A tab takes one cell, as labels have no tab stops to line up with.
+    pub fn fit_width(s: &str, width: usize) -> String {
+        let mut out = String::new();
+        let mut used = 0;
+        for g in s.graphemes(true) {
+            let w = grapheme_width(g, used, 1);
+            if used + w > width {
+                break;
+            }
+            out.push_str(&grapheme_display(g, w));
+            used += w;
+        }
+        out.push_str(&" ".repeat(width - used));
+        out
+    }
+}
+
+/// Detection and reproduction of a file's on-disk text conventions.
+pub mod fileformat {
+    use std::io;
+
+    const BOM: char = '\u{feff}';
+
+    /// How many leading bytes are inspected when sniffing for binary content.
+    const SNIFF_LEN: usize = 8192;
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Encoding {
+        Utf8,
+        Utf16Le,
+        Utf16Be,
+        Latin1,
+    }
+
+    impl Encoding {
+        pub fn name(self) -> &'static str {
+            match self {
+                Encoding::Utf8 => "utf-8",
+                Encoding::Utf16Le => "utf-16le",
+                Encoding::Utf16Be => "utf-16be",
+                Encoding::Latin1 => "latin-1",
+            }
+        }
+
+        pub fn parse(s: &str) -> Option<Self> {
+            match s.to_ascii_lowercase().replace(['-', '_'], "").as_str() {
+                "utf8" => Some(Encoding::Utf8),
+                "utf16le" | "utf16" => Some(Encoding::Utf16Le),
+                "utf16be" => Some(Encoding::Utf16Be),
+                "latin1" | "iso88591" => Some(Encoding::Latin1),
+                _ => None,
+            }
+        }
+
+        /// Encodes `text` for writing. Fails if a char cannot be represented.
+        pub fn encode(self, text: &str) -> io::Result<Vec<u8>> {
+            match self {
+                Encoding::Utf8 => Ok(text.as_bytes().to_vec()),
+                Encoding::Utf16Le => Ok(text.encode_utf16().flat_map(u16::to_le_bytes).collect()),
+                Encoding::Utf16Be => Ok(text.encode_utf16().flat_map(u16::to_be_bytes).collect()),
+                Encoding::Latin1 => text
+                    .chars()
+                    .map(|c| {
+                        u8::try_from(u32::from(c)).map_err(|_| {
+                            io::Error::new(
+                                io::ErrorKind::InvalidData,
+                                format!("'{}' cannot be encoded as latin-1", c),
+                            )
+                        })
+                    })
+                    .collect(),
+            }
+        }
+    }
+
+    /// The result of decoding a file's bytes.
+    pub struct Decoded {
+        pub text: String,
+        pub encoding: Encoding,
+        /// The content looked like binary data; `text` is a lossy rendering.
+        pub binary: bool,
+    }
+
+    /// Decodes `bytes`, recognising UTF-16 by its BOM, then UTF-8, and
+    /// falling back to Latin-1 for text that is not valid UTF-8.
+    pub fn decode(bytes: &[u8]) -> Decoded {
+        let utf16 = match bytes {
+            [0xff, 0xfe, ..] => Some(Encoding::Utf16Le),
+            [0xfe, 0xff, ..] => Some(Encoding::Utf16Be),
+            _ => None,
+        };
+        if let Some(encoding) = utf16
+            && bytes.len().is_multiple_of(2)
+        {
+            let units = bytes.chunks_exact(2).map(|pair| match encoding {
+                Encoding::Utf16Be => u16::from_be_bytes([pair[0], pair[1]]),
+                _ => u16::from_le_bytes([pair[0], pair[1]]),
+            });
+            let text: Option<String> = char::decode_utf16(units).map(Result::ok).collect();
+            if let Some(text) = text {
+                return Decoded {
+                    text,
+                    encoding,
+                    binary: false,
+                };
+            }
+        }
+
+        if looks_binary(bytes) {
+            return Decoded {
+                text: String::from_utf8_lossy(bytes).into_owned(),
+                encoding: Encoding::Utf8,
+                binary: true,
+            };
+        }
+        match std::str::from_utf8(bytes) {
+            Ok(text) => Decoded {
+                text: text.to_string(),
+                encoding: Encoding::Utf8,
+                binary: false,
+            },
+            Err(_) => Decoded {
+                text: bytes.iter().map(|&b| char::from(b)).collect(),
+                encoding: Encoding::Latin1,
+                binary: false,
+            },
+        }
+    }
+
+    /// Treats content as binary if it contains NUL bytes, or if more than
+    /// a tenth of the sniffed bytes are control characters other than
+    /// common whitespace and escape.
+    fn looks_binary(bytes: &[u8]) -> bool {
+        let sample = &bytes[..bytes.len().min(SNIFF_LEN)];
+        if sample.contains(&0) {
+            return true;
+        }
+        let control = sample
+            .iter()
+            .filter(|&&b| b < 0x20 && !matches!(b, b'\t' | b'\n' | b'\r' | 0x0c | 0x1b))
+            .count();
+        control * 10 > sample.len()
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum LineEnding {
+        Lf,
+        CrLf,
+    }
+
+    impl LineEnding {
+        pub fn as_str(self) -> &'static str {
+            match self {
+                LineEnding::Lf => "\n",
+                LineEnding::CrLf => "\r\n",
+            }
+        }
+
+        pub fn name(self) -> &'static str {
+            match self {
+                LineEnding::Lf => "LF",
+                LineEnding::CrLf => "CRLF",
+            }
+        }
+
+        pub fn parse(s: &str) -> Option<Self> {
+            match s.to_ascii_lowercase().as_str() {
+                "lf" | "unix" => Some(LineEnding::Lf),
+                "crlf" | "dos" => Some(LineEnding::CrLf),
+                _ => None,
+            }
+        }
+    }
+
+    /// How a buffer's text maps back to bytes on disk.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct FileFormat {
+        pub encoding: Encoding,
+        pub line_ending: LineEnding,
+        pub final_newline: bool,
+        pub bom: bool,
+    }
+
+    impl Default for FileFormat {
+        fn default() -> Self {
+            Self {
+                encoding: Encoding::Utf8,
+                line_ending: LineEnding::Lf,
+                // Like an empty file, so saving an untouched new buffer
+                // writes nothing.
+                final_newline: false,
+                bom: false,
+            }
+        }
+    }
+
+    impl FileFormat {
+        /// Detects the conventions used by `contents` and returns them along
+        /// with the normalized buffer text: `\n` separators, no BOM and no
+        /// trailing newline. Mixed line endings resolve to the majority.
+        pub fn detect(contents: &str) -> (Self, String) {
+            let (bom, contents) = match contents.strip_prefix(BOM) {
+                Some(rest) => (true, rest),
+                None => (false, contents),
+            };
+            let crlf_count = contents.matches("\r\n").count();
+            let lf_count = contents.matches('\n').count() - crlf_count;
+            let line_ending = if crlf_count > lf_count {
+                LineEnding::CrLf
+            } else {
+                LineEnding::Lf
+            };
+
+            let normalized = contents.replace("\r\n", "\n");
+            let (final_newline, body) = match normalized.strip_suffix('\n') {
+                Some(body) => (true, body.to_string()),
+                None => (false, normalized),
+            };
+            let format = Self {
+                encoding: Encoding::Utf8,
+                line_ending,
+                final_newline,
+                bom,
+            };
+            (format, body)
+        }
+
+        /// Converts normalized buffer text back into file contents.
+        pub fn apply(&self, body: &str) -> String {
+            let mut out = String::with_capacity(body.len() + 4);
+            if self.bom {
+                out.push(BOM);
+            }
+            match self.line_ending {
+                LineEnding::Lf => out.push_str(body),
+                LineEnding::CrLf => out.push_str(&body.replace('\n', "\r\n")),
+            }
+            if self.final_newline {
+                out.push_str(self.line_ending.as_str());
+            }
+            out
+        }
+
+        /// Converts normalized buffer text into the bytes written to disk.
+        pub fn encode(&self, body: &str) -> io::Result<Vec<u8>> {
+            self.encoding.encode(&self.apply(body))
+        }
+
+        /// Short summary for the status bar, e.g. `latin-1 CRLF BOM noeol`.
+        pub fn describe(&self) -> String {
+            let mut parts = Vec::new();
+            if self.encoding != Encoding::Utf8 {
+                parts.push(self.encoding.name());
+            }
+            parts.push(self.line_ending.name());
+            if self.bom {
+                parts.push("BOM");
+            }
+            if !self.final_newline {
+                parts.push("noeol");
+            }
+            parts.join(" ")
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// Loads `bytes` the way a page does and checks that saving the
+        /// buffer writes them back unchanged.
+        fn round_trip(bytes: &[u8]) -> (FileFormat, String) {
+            let decoded = decode(bytes);
+            assert!(!decoded.binary);
+            let (mut format, body) = FileFormat::detect(&decoded.text);
+            format.encoding = decoded.encoding;
+            assert_eq!(format.encode(&body).unwrap(), bytes);
+            (format, body)
+        }
+
+        #[test]
+        fn lf_with_final_newline() {
+            let (format, body) = round_trip(b"a\nb\n");
+            assert_eq!(body, "a\nb");
+            assert_eq!(format, FileFormat { final_newline: true, ..FileFormat::default() });
+        }
+
+        #[test]
+        fn crlf() {
+            let (format, body) = round_trip(b"a\r\nb\r\n");
+            assert_eq!(body, "a\nb");
+            assert_eq!(format.line_ending, LineEnding::CrLf);
+            assert!(format.final_newline);
+        }
+
+        #[test]
+        fn no_final_newline() {
+            let (format, body) = round_trip(b"a\r\nb");
+            assert_eq!(body, "a\nb");
+            assert!(!format.final_newline);
+            assert_eq!(format.describe(), "CRLF noeol");
+        }
+
+        #[test]
+        fn utf8_bom() {
+            let (format, body) = round_trip("\u{feff}é\n".as_bytes());
+            assert_eq!(body, "é");
+            assert!(format.bom);
+        }
+
+        #[test]
+        fn utf16() {
+            let mut le = vec![0xff, 0xfe];
+            le.extend("é\r\n".encode_utf16().flat_map(u16::to_le_bytes));
+            let (format, body) = round_trip(&le);
+            assert_eq!(body, "é");
+            assert_eq!(format.encoding, Encoding::Utf16Le);
+            assert_eq!(format.line_ending, LineEnding::CrLf);
+
+            let mut be = vec![0xfe, 0xff];
+            be.extend("x".encode_utf16().flat_map(u16::to_be_bytes));
+            let (format, body) = round_trip(&be);
+            assert_eq!(body, "x");
+            assert_eq!(format.encoding, Encoding::Utf16Be);
+        }
+
+        #[test]
+        fn latin1() {
+            let (format, body) = round_trip(b"caf\xe9\n");
+            assert_eq!(body, "café");
+            assert_eq!(format.encoding, Encoding::Latin1);
+            assert!(Encoding::Latin1.encode("€").is_err());
+        }
+
+        #[test]
+        fn mixed_line_endings_take_the_majority() {
+            let (format, body) = FileFormat::detect("a\r\nb\r\nc\nd");
+            assert_eq!(format.line_ending, LineEnding::CrLf);
+            assert_eq!(body, "a\nb\nc\nd");
+        }
+
+        #[test]
+        fn binary() {
+            assert!(decode(b"\x7fELF\0\0").binary);
+            assert!(!decode(b"\ttabs\x1b[0m\n").binary);
+        }
+    }
+}
+
+/// Crash-safe file writing shared by every save path, and detection of
+/// changes made to a file behind the editor's back.
+pub mod save {
+    use std::{
+        fs::{self, File, OpenOptions},
+        hash::{DefaultHasher, Hash, Hasher},
+        io::{self, Write},
+        path::{Path, PathBuf},
+        process,
+        time::SystemTime,
+    };
+
+    /// What a file looked like on disk when it was last loaded or saved.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct DiskStamp {
+        modified: Option<SystemTime>,
+        len: u64,
+        hash: u64,
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum DiskStatus {
+        Unchanged,
+        Modified,
+        Deleted,
+    }
+
+    impl DiskStamp {
+        /// Records the state of `path`, whose contents are known to be `contents`.
+        pub fn capture(path: &Path, contents: &[u8]) -> Option<Self> {
+            let metadata = fs::metadata(path).ok()?;
+            Some(Self {
+                modified: metadata.modified().ok(),
+                len: metadata.len(),
+                hash: hash_bytes(contents),
+            })
+        }
+
+        /// Compares `path` against this stamp. A differing mtime or size is
+        /// confirmed by hashing the contents, so a bare `touch` or a rewrite
+        /// with identical bytes does not count as a change.
+        pub fn status(&self, path: &Path) -> DiskStatus {
+            let metadata = match fs::metadata(path) {
+                Ok(metadata) => metadata,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return DiskStatus::Deleted,
+                Err(_) => return DiskStatus::Unchanged,
+            };
+            if metadata.len() == self.len && metadata.modified().ok() == self.modified {
+                return DiskStatus::Unchanged;
+            }
+            match fs::read(path) {
+                Ok(bytes) if hash_bytes(&bytes) == self.hash => DiskStatus::Unchanged,
+                Ok(_) => DiskStatus::Modified,
+                Err(_) => DiskStatus::Unchanged,
+            }
+        }
+    }
+
+    fn hash_bytes(bytes: &[u8]) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        bytes.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    #[derive(Clone, Copy, Default)]
+    pub struct SaveOptions {
+        /// Copy the previous contents to `<file>~` before replacing it.
+        pub backup: bool,
+    }
+
+    /// Writes `contents` to `path` without ever leaving a truncated file.
+    ///
+    /// The data goes to a temporary file in the target's directory, is
+    /// fsynced, takes over the original's permission bits (and ownership,
+    /// where allowed) and is then renamed over the original. Symlinks are
+    /// followed so the link itself survives. Returns the path written.
+    pub fn write_atomic(path: &Path, contents: &[u8], options: SaveOptions) -> io::Result<PathBuf> {
+        let target = resolve_symlinks(path)?;
+        let existing = fs::metadata(&target).ok();
+        let dir = match target.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let file_name = target
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+        let temp_path = dir.join(format!(
+            ".{}.jot-{}.tmp",
+            file_name.to_string_lossy(),
+            process::id()
+        ));
+
+        let result = write_temp(&temp_path, contents, existing.as_ref()).and_then(|()| {
+            if options.backup && existing.is_some() {
+                fs::copy(&target, backup_path(&target))?;
+            }
+            fs::rename(&temp_path, &target)
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_file(&temp_path);
+            return Err(e);
+        }
+        sync_dir(&dir);
+        Ok(target)
+    }
+
+    /// The `file~` path a backup of `path` is written to.
+    pub fn backup_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push("~");
+        PathBuf::from(name)
+    }
+
+    fn write_temp(temp_path: &Path, contents: &[u8], existing: Option<&fs::Metadata>) -> io::Result<()> {
+        let mut file = OpenOptions::new().write(true).create_new(true).open(temp_path)?;
+        file.write_all(contents)?;
+        if let Some(metadata) = existing {
+            file.set_permissions(metadata.permissions())?;
+            copy_ownership(&file, metadata);
+        }
+        file.sync_all()
+    }
+
+    /// Follows `path` through any chain of symlinks, including dangling ones,
+    /// to the file that should actually be replaced.
+    fn resolve_symlinks(path: &Path) -> io::Result<PathBuf> {
+        let mut current = path.to_path_buf();
+        for _ in 0..40 {
+            match fs::symlink_metadata(&current) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    let link = fs::read_link(&current)?;
+                    current = match current.parent() {
+                        Some(parent) => parent.join(link),
+                        None => link,
+                    };
+                }
+                _ => return Ok(current),
+            }
+        }
+        Err(io::Error::other("too many levels of symbolic links"))
+    }
+
+    #[cfg(unix)]
+    fn copy_ownership(file: &File, metadata: &fs::Metadata) {
+        use std::os::unix::fs::{MetadataExt, fchown};
+        // Only root can give a file away; other users keep their own ownership.
+        let _ = fchown(file, Some(metadata.uid()), Some(metadata.gid()));
+    }
+
+    #[cfg(not(unix))]
+    fn copy_ownership(_file: &File, _metadata: &fs::Metadata) {}
+
+    /// Flushes the directory entry for the rename where the platform allows it.
+    fn sync_dir(dir: &Path) {
+        #[cfg(unix)]
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+        #[cfg(not(unix))]
+        let _ = dir;
+    }
+}
+
+/// Swap files holding unsaved buffer contents for crash recovery.
+///
+/// Each file-backed buffer with unsaved changes is periodically copied to
+/// `$XDG_STATE_HOME/jot/swap` (or `~/.local/state/jot/swap`). The swap file
+/// is named after the buffer's absolute path with `/` replaced by `%`, and
+/// is removed once the buffer is saved, reverted or closed.
+pub mod swap {
+    use super::save::{self, SaveOptions};
+    use std::{
+        env, fs, io,
+        path::{Path, PathBuf},
+    };
+
+    const HEADER: &str = "jot swap v1";
+
+    /// The directory swap files are kept in, if one can be determined.
+    pub fn swap_dir() -> Option<PathBuf> {
+        let state_home = env::var_os("XDG_STATE_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local/state")))?;
+        Some(state_home.join("jot").join("swap"))
+    }
+
+    /// The absolute form of `file` that its swap file is named after and
+    /// records, with symlinks resolved when it exists.
+    fn absolute(file: &Path) -> Option<String> {
+        let absolute = if file.is_absolute() {
+            file.to_path_buf()
+        } else {
+            env::current_dir().ok()?.join(file)
+        };
+        let absolute = fs::canonicalize(&absolute).unwrap_or(absolute);
+        Some(absolute.to_string_lossy().into_owned())
+    }
+
+    /// The swap file used for the buffer editing `file`.
+    pub fn swap_path(file: &Path) -> Option<PathBuf> {
+        Some(swap_dir()?.join(file_name(&absolute(file)?)))
+    }
+
+    /// The swap file name for the absolute path `absolute`. The path is
+    /// percent-encoded into the name, so no two files share one.
+    fn file_name(absolute: &str) -> String {
+        let encoded = absolute
+            .replace('%', "%25")
+            .replace('/', "%2F")
+            .replace('\\', "%5C");
+        format!("{}.swp", encoded)
+    }
+
+    /// Saves `text` as the unsaved contents of `file`.
+    pub fn write(file: &Path, text: &str) -> io::Result<()> {
+        let path = swap_path(file).ok_or_else(|| io::Error::other("no state directory"))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let original = absolute(file).unwrap_or_default();
+        let contents = format!("{}\n{}\n{}", HEADER, original, text);
+        save::write_atomic(&path, contents.as_bytes(), SaveOptions::default())?;
+        Ok(())
+    }
+
+    /// Returns the buffer text stored in `file`'s swap file, if one exists
+    /// and was written for `file`.
+    pub fn read(file: &Path) -> Option<String> {
+        let contents = fs::read_to_string(swap_path(file)?).ok()?;
+        parse(&contents, &absolute(file)?).map(str::to_string)
+    }
+
+    /// The buffer text in swap file `contents`, if they were written for
+    /// the absolute path `absolute`.
+    fn parse<'a>(contents: &'a str, absolute: &str) -> Option<&'a str> {
+        let rest = contents.strip_prefix(HEADER)?.strip_prefix('\n')?;
+        let (original, text) = rest.split_once('\n')?;
+        (original == absolute).then_some(text)
+    }
+
+    /// Deletes `file`'s swap file, if there is one.
+    pub fn remove(file: &Path) {
+        if let Some(path) = swap_path(file) {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn names_are_distinct() {
+            assert_eq!(file_name("/home/a b/x.rs"), "%2Fhome%2Fa b%2Fx.rs.swp");
+            assert_eq!(file_name(r"C:\src\x.rs"), "C:%5Csrc%5Cx.rs.swp");
+            let paths = ["/a/b", "/a%2Fb", "/a%252Fb", r"/a\b", "/a_b", "/a%b"];
+            let mut names: Vec<_> = paths.iter().map(|path| file_name(path)).collect();
+            names.sort();
+            names.dedup();
+            assert_eq!(names.len(), paths.len());
+        }
+
+        #[test]
+        fn contents_are_checked_against_the_file() {
+            let contents = format!("{}\n/a/b\nline\n\nlast", HEADER);
+            assert_eq!(parse(&contents, "/a/b"), Some("line\n\nlast"));
+            assert_eq!(parse(&contents, "/a_b"), None);
+            assert_eq!(parse(&format!("{}\n/a/b\n", HEADER), "/a/b"), Some(""));
+            assert_eq!(parse("jot swap v0\n/a/b\ntext", "/a/b"), None);
+            assert_eq!(parse(&format!("{}\n/a/b", HEADER), "/a/b"), None);
+        }
+    }
+}
+
+/// Query compilation and matching for Find mode.
+///
+/// Every query is compiled to a regex: plain-text queries are escaped first,
+/// so literal, regex, case and whole-word searches share one code path.
+/// Matches never span lines.
+pub mod search {
+    use super::text;
+    use regex::{Regex, RegexBuilder};
+
+    /// How letter case is treated when matching.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+    pub enum CaseMode {
+        /// Case-insensitive unless the query contains an uppercase letter.
+        #[default]
+        Smart,
+        Sensitive,
+        Insensitive,
+    }
+
+    impl CaseMode {
+        pub fn next(self) -> Self {
+            match self {
+                CaseMode::Smart => CaseMode::Sensitive,
+                CaseMode::Sensitive => CaseMode::Insensitive,
+                CaseMode::Insensitive => CaseMode::Smart,
+            }
+        }
+
+        pub fn name(self) -> &'static str {
+            match self {
+                CaseMode::Smart => "smart case",
+                CaseMode::Sensitive => "match case",
+                CaseMode::Insensitive => "ignore case",
+            }
+        }
+    }
+
+    /// The toggles that control how a query is interpreted.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+    pub struct SearchOptions {
+        pub regex: bool,
+        pub case: CaseMode,
+        pub whole_word: bool,
+    }
+
+    impl SearchOptions {
+        /// A short summary of the active toggles, such as `[regex, word]`.
+        pub fn describe(&self) -> String {
+            let mut parts = Vec::new();
+            if self.regex {
+                parts.push("regex");
+            }
+            if self.case != CaseMode::Smart {
+                parts.push(self.case.name());
+            }
+            if self.whole_word {
+                parts.push("word");
+            }
+            if parts.is_empty() {
+                String::new()
+            } else {
+                format!("[{}]", parts.join(", "))
+            }
+        }
+    }
+
+    /// A match within a single line, as a char range `start..end`.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Match {
+        pub row: usize,
+        pub start: usize,
+        pub end: usize,
+    }
+
+    /// Compiles `query` according to `options`.
+    pub fn compile(query: &str, options: SearchOptions) -> Result<Regex, regex::Error> {
+        let mut pattern = if options.regex {
+            query.to_string()
+        } else {
+            regex::escape(query)
+        };
+        if options.whole_word {
+            pattern = format!(r"\b(?:{})\b", pattern);
+        }
+        let insensitive = match options.case {
+            CaseMode::Smart => !query.chars().any(char::is_uppercase),
+            CaseMode::Sensitive => false,
+            CaseMode::Insensitive => true,
+        };
+        RegexBuilder::new(&pattern)
+            .case_insensitive(insensitive)
+            .build()
+    }
+
+    /// A parsed `:[range]s/pattern/replacement/[flags]` command.
+    ///
+    /// The range is `%` (whole buffer), `'<,'>` (the selection), one or two
+    /// comma-separated line numbers (`.` is the cursor line, `$` the last
+    /// line), or empty for the cursor line. Flags are `g` (every match on a line), `c` (confirm each),
+    /// `i` (ignore case) and `I` (match case). Any punctuation character can
+    /// stand in for `/` as the delimiter.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Substitute {
+        /// First and last row the command applies to, inclusive.
+        pub rows: (usize, usize),
+        pub pattern: String,
+        pub replacement: String,
+        pub global: bool,
+        pub confirm: bool,
+        pub case: CaseMode,
+        /// Only matches inside the selection are replaced.
+        pub in_selection: bool,
+    }
+
+    impl Substitute {
+        /// Parses `command`, resolving `.`, `$` and `'<,'>` against
+        /// `current_row`, `last_row` and the rows of the selection. Returns
+        /// `None` if `command` is not a substitution.
+        pub fn parse(
+            command: &str,
+            current_row: usize,
+            last_row: usize,
+            selection: Option<(usize, usize)>,
+        ) -> Option<Result<Self, String>> {
+            let in_selection = command.starts_with("'<,'>");
+            // A missing selection is only an error once the rest is known to
+            // be a substitution; other commands are not ours to reject.
+            let (rows, rest) = if let Some(rest) = command.strip_prefix("'<,'>") {
+                (selection.unwrap_or_default(), rest)
+            } else if let Some(rest) = command.strip_prefix('%') {
+                ((0, last_row), rest)
+            } else if let Some((first, rest)) = parse_address(command, current_row, last_row) {
+                match rest.strip_prefix(',') {
+                    Some(rest) => {
+                        let (second, rest) = parse_address(rest, current_row, last_row)?;
+                        ((first.min(second), first.max(second)), rest)
+                    }
+                    None => ((first, first), rest),
+                }
+            } else {
+                ((current_row, current_row), command)
+            };
+            let rest = rest.strip_prefix('s')?;
+            let delimiter = rest.chars().next()?;
+            if delimiter.is_alphanumeric() || delimiter.is_whitespace() || delimiter == '\\' {
+                return None;
+            }
+            if in_selection && selection.is_none() {
+                return Some(Err("No selection".to_string()));
+            }
+            let parsed = Self::parse_body(rows, last_row, &rest[delimiter.len_utf8()..], delimiter);
+            Some(parsed.map(|substitute| Self { in_selection, ..substitute }))
+        }
+
+        fn parse_body(rows: (usize, usize), last_row: usize, body: &str, delimiter: char) -> Result<Self, String> {
+            let parts = split_unescaped(body, delimiter);
+            if parts.len() > 3 {
+                return Err("Trailing characters after flags".to_string());
+            }
+            if rows.1 > last_row {
+                return Err("Invalid range".to_string());
+            }
+            let pattern = parts[0].clone();
+            if pattern.is_empty() {
+                return Err("Empty search pattern".to_string());
+            }
+            let mut substitute = Self {
+                rows,
+                pattern,
+                replacement: parts.get(1).cloned().unwrap_or_default(),
+                global: false,
+                confirm: false,
+                case: CaseMode::Sensitive,
+                in_selection: false,
+            };
+            for flag in parts.get(2).map_or("", String::as_str).chars() {
+                match flag {
+                    'g' => substitute.global = true,
+                    'c' => substitute.confirm = true,
+                    'i' => substitute.case = CaseMode::Insensitive,
+                    'I' => substitute.case = CaseMode::Sensitive,
+                    other => return Err(format!("Unknown flag: {}", other)),
+                }
+            }
+            Ok(substitute)
+        }
+    }
+
+    /// Parses a line address at the start of `s` into a row, returning it
+    /// with the rest of `s`.
+    fn parse_address(s: &str, current_row: usize, last_row: usize) -> Option<(usize, &str)> {
+        if let Some(rest) = s.strip_prefix('.') {
+            return Some((current_row, rest));
+        }
+        if let Some(rest) = s.strip_prefix('$') {
+            return Some((last_row, rest));
+        }
+        let digits = s.len() - s.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let line: usize = s[..digits].parse().ok()?;
+        Some((line.max(1) - 1, &s[digits..]))
+    }
+
+    /// Splits `s` on `delimiter`, treating a backslash-escaped delimiter as a
+    /// literal one. Other escapes are kept for the regex and template parsers.
+    fn split_unescaped(s: &str, delimiter: char) -> Vec<String> {
+        let mut parts = vec![String::new()];
+        let mut chars = s.chars();
+        while let Some(c) = chars.next() {
+            let part = parts.last_mut().expect("parts is never empty");
+            if c == '\\' {
+                match chars.next() {
+                    Some(d) if d == delimiter => part.push(d),
+                    Some(d) => {
+                        part.push(c);
+                        part.push(d);
+                    }
+                    None => part.push(c),
+                }
+            } else if c == delimiter {
+                parts.push(String::new());
+            } else {
+                part.push(c);
+            }
+        }
+        parts
+    }
+
+    /// Converts a Vim-style replacement into a template for
+    /// `Captures::expand`: `\1`..`\9` insert capture groups, `&` and `\0` the
+    /// whole match, `\r` or `\n` a line break and `\t` a tab. A backslash
+    /// makes any other character literal, and `$` is always literal.
+    pub fn expand_template(replacement: &str) -> String {
+        let mut template = String::new();
+        let mut chars = replacement.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' => match chars.next() {
+                    Some(d @ '0'..='9') => template.push_str(&format!("${{{}}}", d)),
+                    Some('r' | 'n') => template.push('\n'),
+                    Some('t') => template.push('\t'),
+                    Some('$') => template.push_str("$$"),
+                    Some(other) => template.push(other),
+                    None => template.push('\\'),
+                },
+                '&' => template.push_str("${0}"),
+                '$' => template.push_str("$$"),
+                c => template.push(c),
+            }
+        }
+        template
+    }
+
+    /// A template that inserts `replacement` verbatim.
+    pub fn literal_template(replacement: &str) -> String {
+        replacement.replace('$', "$$")
+    }
+
+    /// Finds the matches of `regex` in `lines`, given as (row, line) pairs,
+    /// paired with the text `template` expands to for each. Only the first
+    /// match on each line is used unless `global` is set.
+    pub fn plan_replacements(
+        regex: &Regex,
+        lines: impl Iterator<Item = (usize, String)>,
+        template: &str,
+        global: bool,
+    ) -> Vec<(Match, String)> {
+        let mut planned = Vec::new();
+        for (row, line) in lines {
+            let per_line = if global { usize::MAX } else { 1 };
+            for caps in regex.captures_iter(&line).take(per_line) {
+                let Some(m) = caps.get(0) else {
+                    continue;
+                };
+                let mut replacement = String::new();
+                caps.expand(template, &mut replacement);
+                let start = text::byte_to_col(&line, m.start());
+                let end = start + m.as_str().chars().count();
+                planned.push((Match { row, start, end }, replacement));
+            }
+        }
+        planned
+    }
+
+    /// Finds every non-empty match of `regex` in `lines`, in document
+    /// order. These are the matches `plan_replacements` finds, less the
+    /// empty ones, which have nothing to show.
+    pub fn find_all(regex: &Regex, lines: impl Iterator<Item = String>) -> Vec<Match> {
+        plan_replacements(regex, lines.enumerate(), "", true)
+            .into_iter()
+            .map(|(m, _)| m)
+            .filter(|m| m.end > m.start)
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn parse(command: &str) -> Option<Result<Substitute, String>> {
+            Substitute::parse(command, 1, 9, Some((3, 5)))
+        }
+
+        fn plan(pattern: &str, replacement: &str, text: &str, global: bool) -> Vec<(Match, String)> {
+            let regex = Regex::new(pattern).unwrap();
+            let lines = text.lines().map(str::to_string).enumerate();
+            plan_replacements(&regex, lines, &expand_template(replacement), global)
+        }
+
+        #[test]
+        fn substitute_ranges() {
+            assert_eq!(parse("s/a/b/").unwrap().unwrap().rows, (1, 1));
+            assert_eq!(parse("%s/a/b/").unwrap().unwrap().rows, (0, 9));
+            assert_eq!(parse("4s/a/b/").unwrap().unwrap().rows, (3, 3));
+            assert_eq!(parse("$,.s/a/b/").unwrap().unwrap().rows, (1, 9));
+            let selected = parse("'<,'>s/a/b/").unwrap().unwrap();
+            assert_eq!(selected.rows, (3, 5));
+            assert!(selected.in_selection);
+            assert_eq!(Substitute::parse("'<,'>s/a/b/", 1, 9, None), Some(Err("No selection".to_string())));
+            assert_eq!(parse("11s/a/b/"), Some(Err("Invalid range".to_string())));
+        }
+
+        #[test]
+        fn substitute_body_and_flags() {
+            let substitute = parse(r"s#a\#b#c#gci").unwrap().unwrap();
+            assert_eq!(substitute.pattern, "a#b");
+            assert_eq!(substitute.replacement, "c");
+            assert!(substitute.global && substitute.confirm);
+            assert_eq!(substitute.case, CaseMode::Insensitive);
+
+            let substitute = parse("s/a/").unwrap().unwrap();
+            assert_eq!(substitute.replacement, "");
+            assert!(!substitute.global);
+            assert_eq!(parse("s/a"), parse("s/a/"));
+
+            assert_eq!(parse("s//b/"), Some(Err("Empty search pattern".to_string())));
+            assert_eq!(parse("s/a/b/x"), Some(Err("Unknown flag: x".to_string())));
+            assert_eq!(parse("s/a/b/g/"), Some(Err("Trailing characters after flags".to_string())));
+        }
+
+        #[test]
+        fn other_commands_are_not_substitutions() {
+            for command in ["sort", "set tab_width=4", "s", "sx", "'<,'>sort", "w"] {
+                assert_eq!(parse(command), None, "{}", command);
+            }
+        }
+
+        #[test]
+        fn templates() {
+            assert_eq!(expand_template(r"\2-\1 & \0"), "${2}-${1} ${0} ${0}");
+            assert_eq!(expand_template(r"$1\&\n"), "$$1&\n");
+            assert_eq!(literal_template("$1&"), "$$1&");
+        }
+
+        #[test]
+        fn replacements_with_capture_groups() {
+            let planned = plan(r"(\w+)=(\w+)", r"\2=\1", "a=b c=d\nx", true);
+            assert_eq!(
+                planned,
+                vec![
+                    (Match { row: 0, start: 0, end: 3 }, "b=a".to_string()),
+                    (Match { row: 0, start: 4, end: 7 }, "d=c".to_string()),
+                ]
+            );
+            assert_eq!(plan(r"(\w+)=(\w+)", r"\2=\1", "a=b c=d", false).len(), 1);
+        }
+
+        #[test]
+        fn replacements_include_empty_matches() {
+            let planned = plan("^", "# ", "a\nb", false);
+            assert_eq!(planned.len(), 2);
+            assert_eq!(planned[1], (Match { row: 1, start: 0, end: 0 }, "# ".to_string()));
+
+            let regex = Regex::new("x*").unwrap();
+            let ranges: Vec<_> = plan("x*", "-", "éxb", true)
+                .into_iter()
+                .map(|(m, _)| (m.start, m.end))
+                .collect();
+            assert_eq!(ranges, vec![(0, 0), (1, 2), (3, 3)]);
+            // Find shows the same matches, less the empty ones.
+            let found = find_all(&regex, std::iter::once("éxb".to_string()));
+            assert_eq!(found, vec![Match { row: 0, start: 1, end: 2 }]);
+        }
+
+        #[test]
+        fn compile_options() {
+            let options = SearchOptions { whole_word: true, ..SearchOptions::default() };
+            let regex = compile("a.b", options).unwrap();
+            assert!(regex.is_match("x A.B y"));
+            assert!(!regex.is_match("axb"));
+            assert!(!compile("A", SearchOptions::default()).unwrap().is_match("a"));
+            let options = SearchOptions { regex: true, case: CaseMode::Insensitive, ..SearchOptions::default() };
+            assert!(compile("A.", options).unwrap().is_match("ab"));
+            assert!(compile("(", SearchOptions { regex: true, ..SearchOptions::default() }).is_err());
+        }
+    }
+}
+
+/// Project-wide search over the files under a directory.
+///
+/// Walks the tree with the same rules as git: `.gitignore`, `.ignore` and
+/// hidden files are skipped, as are files that look binary.
+pub mod grep {
+    use super::fileformat::{self, FileFormat};
+    use super::text;
+    use regex::Regex;
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    /// Longest preview kept for a hit, in chars.
+    const PREVIEW_LEN: usize = 200;
+
+    /// One matching line.
+    pub struct Hit {
+        pub path: PathBuf,
+        pub row: usize,
+        /// Char column of the first match on the line.
+        pub col: usize,
+        pub preview: String,
+    }
+
+    /// Lists the files under `root` that are not ignored, sorted by path.
+    pub fn files(root: &Path) -> impl Iterator<Item = PathBuf> {
+        ignore::WalkBuilder::new(root)
+            .require_git(false)
+            .sort_by_file_path(|a, b| a.cmp(b))
+            .build()
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_some_and(|t| t.is_file()))
+            .map(|entry| entry.into_path())
+    }
+
+    /// Searches every file under `root` for `regex`, stopping after `limit`
+    /// hits. The second value is true if the search stopped early.
+    pub fn search(root: &Path, regex: &Regex, limit: usize) -> (Vec<Hit>, bool) {
+        let mut hits = Vec::new();
+        for path in files(root) {
+            let Ok(bytes) = fs::read(&path) else {
+                continue;
+            };
+            let decoded = fileformat::decode(&bytes);
+            if decoded.binary {
+                continue;
+            }
+            let (_, body) = FileFormat::detect(&decoded.text);
+            for (row, line) in body.lines().enumerate() {
+                let Some(m) = regex.find(line) else {
+                    continue;
+                };
+                if hits.len() == limit {
+                    return (hits, true);
+                }
+                hits.push(Hit {
+                    path: path.clone(),
+                    row,
+                    col: text::byte_to_col(line, m.start()),
+                    preview: line.trim().chars().take(PREVIEW_LEN).collect(),
+                });
+            }
+        }
+        (hits, false)
+    }
+}
+
+/// Fuzzy matching of file paths for the file picker.
+///
+/// A query matches a path if its chars appear in order. Matches score higher
+/// when the chars are consecutive, start words (after `/`, `_`, `-`, `.` or
+/// a lowercase-to-uppercase change) or fall in the file name. Matching
+/// ignores case unless the query contains an uppercase letter.
+pub mod fuzzy {
+    const MATCH: i64 = 1;
+    const CONSECUTIVE: i64 = 5;
+    const WORD_START: i64 = 8;
+    const IN_FILE_NAME: i64 = 2;
+    /// Extra credit when the whole query matches within the file name.
+    const FILE_NAME_ONLY: i64 = 10;
+    const MAX_GAP_PENALTY: usize = 5;
+
+    /// Scores `candidate` against `query`, or returns `None` if it does not match.
+    pub fn score(query: &str, candidate: &str) -> Option<i64> {
+        let query: Vec<char> = query.chars().collect();
+        let candidate: Vec<char> = candidate.chars().collect();
+        let ignore_case = !query.iter().any(|c| c.is_uppercase());
+        let name_start = candidate
+            .iter()
+            .rposition(|&c| c == '/' || c == '\\')
+            .map_or(0, |i| i + 1);
+
+        let whole = match_from(&query, &candidate, 0, name_start, ignore_case)?;
+        let name_only = match_from(&query, &candidate, name_start, name_start, ignore_case)
+            .map(|s| s + FILE_NAME_ONLY);
+        let best = name_only.map_or(whole, |s| s.max(whole));
+        Some(best - (candidate.len() / 8) as i64)
+    }
+
+    /// Greedily matches `query` against `candidate[from..]`.
+    fn match_from(
+        query: &[char],
+        candidate: &[char],
+        from: usize,
+        name_start: usize,
+        ignore_case: bool,
+    ) -> Option<i64> {
+        let eq = |a: char, b: char| {
+            if ignore_case {
+                a.to_lowercase().eq(b.to_lowercase())
+            } else {
+                a == b
+            }
+        };
+        let mut score = 0;
+        let mut next = from;
+        let mut prev: Option<usize> = None;
+        for &q in query {
+            let pos = (next..candidate.len()).find(|&i| eq(candidate[i], q))?;
+            score += MATCH;
+            if pos > 0 && prev == Some(pos - 1) {
+                score += CONSECUTIVE;
+            }
+            if pos == 0 || is_word_start(candidate[pos - 1], candidate[pos]) {
+                score += WORD_START;
+            }
+            if pos >= name_start {
+                score += IN_FILE_NAME;
+            }
+            let gap = pos - prev.map_or(from, |p| p + 1);
+            score -= gap.min(MAX_GAP_PENALTY) as i64;
+            prev = Some(pos);
+            next = pos + 1;
+        }
+        Some(score)
+    }
+
+    fn is_word_start(prev: char, c: char) -> bool {
+        matches!(prev, '/' | '\\' | '_' | '-' | '.' | ' ') || (prev.is_lowercase() && c.is_uppercase())
+    }
+
+    /// Returns the indices of the `candidates` matching `query`, best first.
+    pub fn rank(query: &str, candidates: &[String]) -> Vec<usize> {
+        let mut scored: Vec<(i64, usize)> = candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| score(query, c).map(|s| (s, i)))
+            .collect();
+        scored.sort_by(|a, b| {
+            b.0.cmp(&a.0)
+                .then_with(|| candidates[a.1].len().cmp(&candidates[b.1].len()))
+                .then_with(|| candidates[a.1].cmp(&candidates[b.1]))
+        });
+        scored.into_iter().map(|(_, i)| i).collect()
+    }
+}
+
+/// Named storage for copied and cut text.
+pub mod registers {
+    use std::collections::HashMap;
+
+    /// The register every copy and cut also writes to, and paste reads from
+    /// by default.
+    pub const UNNAMED: char = '"';
+
+    /// Text held in a register, and whether it is whole lines.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub struct Register {
+        pub text: String,
+        pub linewise: bool,
+    }
+
+    #[derive(Default)]
+    pub struct Registers {
+        slots: HashMap<char, Register>,
+    }
+
+    impl Registers {
+        /// Stores `register` under `name` and in the unnamed register.
+        pub fn set(&mut self, name: char, register: Register) {
+            if name != UNNAMED {
+                self.slots.insert(UNNAMED, register.clone());
+            }
+            self.slots.insert(name, register);
+        }
+
+        pub fn get(&self, name: char) -> Option<&Register> {
+            self.slots.get(&name)
+        }
+    }
+}
+
+/// Access to the system clipboard.
+///
+/// Copies are sent to the terminal as an OSC 52 escape sequence, which
+/// reaches the local clipboard even over SSH and (with passthrough) inside
+/// tmux, and to `wl-copy`, `xclip` or `xsel` when one is installed. Pastes
+/// read back through the same helper. The last copied text is always kept
+/// internally, so paste still works where no system clipboard is reachable.
+pub mod clipboard {
+    use std::{
+        env,
+        io::{self, Write},
+        process::{Command, Stdio},
+    };
+
+    /// Where copied text is sent and pasted text is read from.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+    pub enum ClipboardMode {
+        /// OSC 52 plus the first helper tool found.
+        #[default]
+        Auto,
+        Osc52,
+        WlClipboard,
+        Xclip,
+        Xsel,
+        /// Nothing leaves jot.
+        Internal,
+    }
+
+    impl ClipboardMode {
+        pub fn name(self) -> &'static str {
+            match self {
+                ClipboardMode::Auto => "auto",
+                ClipboardMode::Osc52 => "osc52",
+                ClipboardMode::WlClipboard => "wl-copy",
+                ClipboardMode::Xclip => "xclip",
+                ClipboardMode::Xsel => "xsel",
+                ClipboardMode::Internal => "internal",
+            }
+        }
+
+        pub fn parse(s: &str) -> Option<Self> {
+            [
+                ClipboardMode::Auto,
+                ClipboardMode::Osc52,
+                ClipboardMode::WlClipboard,
+                ClipboardMode::Xclip,
+                ClipboardMode::Xsel,
+                ClipboardMode::Internal,
+            ]
+            .into_iter()
+            .find(|mode| mode.name() == s)
+        }
+    }
+
+    /// An external clipboard helper.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    enum Tool {
+        WlClipboard,
+        Xclip,
+        Xsel,
+    }
+
+    impl Tool {
+        fn copy_command(self) -> &'static [&'static str] {
+            match self {
+                Tool::WlClipboard => &["wl-copy"],
+                Tool::Xclip => &["xclip", "-selection", "clipboard", "-in"],
+                Tool::Xsel => &["xsel", "--clipboard", "--input"],
+            }
+        }
+
+        fn paste_command(self) -> &'static [&'static str] {
+            match self {
+                Tool::WlClipboard => &["wl-paste", "--no-newline"],
+                Tool::Xclip => &["xclip", "-selection", "clipboard", "-out"],
+                Tool::Xsel => &["xsel", "--clipboard", "--output"],
+            }
+        }
+
+        /// The first helper that is installed and has a display to talk to.
+        fn detect() -> Option<Self> {
+            let wayland = env::var_os("WAYLAND_DISPLAY").is_some();
+            let x11 = env::var_os("DISPLAY").is_some();
+            [(Tool::WlClipboard, wayland), (Tool::Xclip, x11), (Tool::Xsel, x11)]
+                .into_iter()
+                .find(|&(tool, usable)| usable && in_path(tool.copy_command()[0]))
+                .map(|(tool, _)| tool)
+        }
+    }
+
+    fn in_path(program: &str) -> bool {
+        env::var_os("PATH")
+            .is_some_and(|paths| env::split_paths(&paths).any(|dir| dir.join(program).is_file()))
+    }
+
+    pub struct Clipboard {
+        mode: ClipboardMode,
+        tool: Option<Tool>,
+        /// The most recently copied text.
+        internal: Option<String>,
+    }
+
+    impl Clipboard {
+        pub fn new(mode: ClipboardMode) -> Self {
+            let mut clipboard = Self {
+                mode,
+                tool: None,
+                internal: None,
+            };
+            clipboard.set_mode(mode);
+            clipboard
+        }
+
+        pub fn mode(&self) -> ClipboardMode {
+            self.mode
+        }
+
+        pub fn set_mode(&mut self, mode: ClipboardMode) {
+            self.mode = mode;
+            self.tool = match mode {
+                ClipboardMode::Auto => Tool::detect(),
+                ClipboardMode::WlClipboard => Some(Tool::WlClipboard),
+                ClipboardMode::Xclip => Some(Tool::Xclip),
+                ClipboardMode::Xsel => Some(Tool::Xsel),
+                ClipboardMode::Osc52 | ClipboardMode::Internal => None,
+            };
+        }
+
+        /// Puts `text` on the clipboard.
+        pub fn copy(&mut self, text: &str) -> io::Result<()> {
+            self.internal = Some(text.to_string());
+            if matches!(self.mode, ClipboardMode::Auto | ClipboardMode::Osc52) {
+                let mut stdout = io::stdout();
+                stdout.write_all(osc52_sequence(text).as_bytes())?;
+                stdout.flush()?;
+            }
+            if let Some(tool) = self.tool {
+                run_copy(tool.copy_command(), text)?;
+            }
+            Ok(())
+        }
+
+        /// Reads the clipboard, falling back to the last text copied in jot.
+        pub fn paste(&self) -> Option<String> {
+            self.tool
+                .and_then(|tool| run_paste(tool.paste_command()))
+                .or_else(|| self.internal.clone())
+        }
+    }
+
+    fn run_copy(command: &[&str], text: &str) -> io::Result<()> {
+        let mut child = Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(text.as_bytes())?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("{} failed", command[0])));
+        }
+        Ok(())
+    }
+
+    fn run_paste(command: &[&str]) -> Option<String> {
+        let output = Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        String::from_utf8(output.stdout).ok()
+    }
+
+    /// The OSC 52 sequence that sets the clipboard to `text`, wrapped for
+    /// tmux passthrough when running inside tmux.
+    fn osc52_sequence(text: &str) -> String {
+        let sequence = format!("\x1b]52;c;{}\x07", base64(text.as_bytes()));
+        if env::var_os("TMUX").is_some() {
+            format!("\x1bPtmux;{}\x1b\\", sequence.replace('\x1b', "\x1b\x1b"))
+        } else {
+            sequence
+        }
+    }
+
+    fn base64(bytes: &[u8]) -> String {
+        const ALPHABET: &[u8; 64] =
+            b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+        for chunk in bytes.chunks(3) {
+            let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+            let n = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+                } else {
+                    out.push('=');
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Vim-style normal mode: parses typed keys into commands.
+///
+/// A command is an optional `"x` register prefix, an optional count and
+/// then either a motion, an action such as `p` or `u`, or an operator
+/// followed by its own optional count and a motion, a text object or the
+/// operator key again for whole lines (`dd`, `>>`).
+pub mod vim {
+    use super::core::Motion;
+
+    /// Counts are capped so a mistyped number cannot hang the editor.
+    const MAX_COUNT: usize = 9999;
+
+    /// What an operator does with the text it covers.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Operator {
+        Delete,
+        Change,
+        Yank,
+        Indent,
+        Dedent,
+    }
+
+    impl Operator {
+        fn from_key(c: char) -> Option<Self> {
+            Some(match c {
+                'd' => Operator::Delete,
+                'c' => Operator::Change,
+                'y' => Operator::Yank,
+                '>' => Operator::Indent,
+                '<' => Operator::Dedent,
+                _ => return None,
+            })
+        }
+    }
+
+    /// A span of text around the cursor. The inner variant leaves out the
+    /// surrounding whitespace, brackets or quotes.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum TextObject {
+        Word { inner: bool },
+        Paragraph { inner: bool },
+        Bracket { open: char, close: char, inner: bool },
+        Quote { quote: char, inner: bool },
+    }
+
+    impl TextObject {
+        fn from_key(c: char, inner: bool) -> Option<Self> {
+            let bracket = |open, close| TextObject::Bracket { open, close, inner };
+            Some(match c {
+                'w' => TextObject::Word { inner },
+                'p' => TextObject::Paragraph { inner },
+                '(' | ')' | 'b' => bracket('(', ')'),
+                '{' | '}' | 'B' => bracket('{', '}'),
+                '[' | ']' => bracket('[', ']'),
+                '<' | '>' => bracket('<', '>'),
+                '"' | '\'' | '`' => TextObject::Quote { quote: c, inner },
+                _ => return None,
+            })
+        }
+    }
+
+    /// What an operator acts on.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Target {
+        Motion(Motion),
+        Object(TextObject),
+        /// Whole lines, from a doubled operator key.
+        Line,
+    }
+
+    /// Where `i`, `a`, `I`, `A`, `o` and `O` start inserting.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Insert {
+        Before,
+        After,
+        LineStart,
+        LineEnd,
+        LineBelow,
+        LineAbove,
+    }
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Action {
+        Move(Motion),
+        Operate(Operator, Target),
+        Insert(Insert),
+        Paste { after: bool },
+        Undo,
+        /// `.`, repeating the last change.
+        Repeat,
+        Visual { linewise: bool },
+        /// `:`, opening the command line.
+        CommandLine,
+    }
+
+    /// A complete normal mode command.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Command {
+        pub register: Option<char>,
+        /// The typed count; the counts before and after an operator multiply.
+        pub count: Option<usize>,
+        pub action: Action,
+    }
+
+    impl Command {
+        /// Whether the command changes the buffer, and so is what `.` repeats.
+        pub fn is_change(&self) -> bool {
+            match self.action {
+                Action::Operate(op, _) => op != Operator::Yank,
+                Action::Insert(_) | Action::Paste { .. } => true,
+                _ => false,
+            }
+        }
+    }
+
+    /// The result of parsing the keys typed so far.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Parse {
+        /// More keys are needed.
+        Pending,
+        /// The keys can never form a command and should be dropped.
+        Invalid,
+        Done(Command),
+    }
+
+    /// Parses `keys`, the keys typed since the last complete command.
+    pub fn parse(keys: &str) -> Parse {
+        let keys: Vec<char> = keys.chars().collect();
+        let mut reader = Reader { keys: &keys, pos: 0 };
+        match parse_command(&mut reader) {
+            Ok(command) => Parse::Done(command),
+            Err(parse) => parse,
+        }
+    }
+
+    struct Reader<'a> {
+        keys: &'a [char],
+        pos: usize,
+    }
+
+    impl Reader<'_> {
+        fn next(&mut self) -> Result<char, Parse> {
+            let c = *self.keys.get(self.pos).ok_or(Parse::Pending)?;
+            self.pos += 1;
+            Ok(c)
+        }
+
+        /// Reads a count if one is next. A leading `0` is the line start
+        /// motion rather than a count.
+        fn count(&mut self) -> Option<usize> {
+            let mut count: Option<usize> = None;
+            while let Some(digit) = self.keys.get(self.pos).and_then(|c| c.to_digit(10)) {
+                if count.is_none() && digit == 0 {
+                    break;
+                }
+                count = Some((count.unwrap_or(0) * 10 + digit as usize).min(MAX_COUNT));
+                self.pos += 1;
+            }
+            count
+        }
+    }
+
+    fn parse_command(keys: &mut Reader) -> Result<Command, Parse> {
+        let register = if keys.keys.first() == Some(&'"') {
+            keys.pos += 1;
+            Some(keys.next()?)
+        } else {
+            None
+        };
+        let mut count = keys.count();
+        let c = keys.next()?;
+        let action = match c {
+            'i' => Action::Insert(Insert::Before),
+            'a' => Action::Insert(Insert::After),
+            'I' => Action::Insert(Insert::LineStart),
+            'A' => Action::Insert(Insert::LineEnd),
+            'o' => Action::Insert(Insert::LineBelow),
+            'O' => Action::Insert(Insert::LineAbove),
+            'x' => Action::Operate(Operator::Delete, Target::Motion(Motion::Right)),
+            'D' => Action::Operate(Operator::Delete, Target::Motion(Motion::LineEnd)),
+            'C' => Action::Operate(Operator::Change, Target::Motion(Motion::LineEnd)),
+            'Y' => Action::Operate(Operator::Yank, Target::Line),
+            'p' => Action::Paste { after: true },
+            'P' => Action::Paste { after: false },
+            'u' => Action::Undo,
+            '.' => Action::Repeat,
+            'v' => Action::Visual { linewise: false },
+            'V' => Action::Visual { linewise: true },
+            ':' => Action::CommandLine,
+            'g' => Action::Move(parse_g(keys)?),
+            _ => match Operator::from_key(c) {
+                Some(op) => {
+                    let inner_count = keys.count();
+                    if count.is_some() || inner_count.is_some() {
+                        let total = count.unwrap_or(1) * inner_count.unwrap_or(1);
+                        count = Some(total.min(MAX_COUNT));
+                    }
+                    Action::Operate(op, parse_target(keys, c)?)
+                }
+                None => Action::Move(Motion::from_vi_key(c).ok_or(Parse::Invalid)?),
+            },
+        };
+        Ok(Command { register, count, action })
+    }
+
+    /// Parses what follows the operator typed as `op_key`.
+    fn parse_target(keys: &mut Reader, op_key: char) -> Result<Target, Parse> {
+        let c = keys.next()?;
+        if c == op_key {
+            return Ok(Target::Line);
+        }
+        match c {
+            'i' | 'a' => {
+                let object = TextObject::from_key(keys.next()?, c == 'i');
+                object.map(Target::Object).ok_or(Parse::Invalid)
+            }
+            'g' => Ok(Target::Motion(parse_g(keys)?)),
+            _ => Motion::from_vi_key(c).map(Target::Motion).ok_or(Parse::Invalid),
+        }
+    }
+
+    /// Parses the key after `g`; only `gg` is supported.
+    fn parse_g(keys: &mut Reader) -> Result<Motion, Parse> {
+        match keys.next()? {
+            'g' => Ok(Motion::DocumentStart),
+            _ => Err(Parse::Invalid),
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn done(keys: &str) -> Command {
+            match parse(keys) {
+                Parse::Done(command) => command,
+                other => panic!("{:?} parsed as {:?}", keys, other),
+            }
+        }
+
+        fn operate(op: Operator, target: Target, count: Option<usize>) -> Command {
+            Command { register: None, count, action: Action::Operate(op, target) }
+        }
+
+        #[test]
+        fn motions_and_counts() {
+            assert_eq!(done("w").action, Action::Move(Motion::WordForward));
+            assert_eq!(done("3j").count, Some(3));
+            assert_eq!(done("0").action, Action::Move(Motion::LineStart));
+            assert_eq!(done("10l").count, Some(10));
+            assert_eq!(done("gg").action, Action::Move(Motion::DocumentStart));
+            assert_eq!(done("99999999G").count, Some(MAX_COUNT));
+        }
+
+        #[test]
+        fn operators() {
+            let word = Target::Motion(Motion::WordForward);
+            assert_eq!(done("dw"), operate(Operator::Delete, word, None));
+            assert_eq!(done("cc"), operate(Operator::Change, Target::Line, None));
+            assert_eq!(done(">>"), operate(Operator::Indent, Target::Line, None));
+            let top = Target::Motion(Motion::DocumentStart);
+            assert_eq!(done("dgg"), operate(Operator::Delete, top, None));
+            // Counts before and after the operator multiply.
+            let down = Target::Motion(Motion::Down);
+            assert_eq!(done("2d3j"), operate(Operator::Delete, down, Some(6)));
+            assert_eq!(done("d0").count, None);
+            assert_eq!(done("x"), operate(Operator::Delete, Target::Motion(Motion::Right), None));
+        }
+
+        #[test]
+        fn text_objects() {
+            let quote = TextObject::Quote { quote: '"', inner: true };
+            assert_eq!(done("ci\""), operate(Operator::Change, Target::Object(quote), None));
+            let bracket = TextObject::Bracket { open: '(', close: ')', inner: false };
+            assert_eq!(done("yab"), operate(Operator::Yank, Target::Object(bracket), None));
+            assert_eq!(done("da)"), done("dab"));
+        }
+
+        #[test]
+        fn registers() {
+            let yank = operate(Operator::Yank, Target::Line, Some(2));
+            assert_eq!(done("\"a2yy"), Command { register: Some('a'), ..yank });
+            assert_eq!(done("\"+p").action, Action::Paste { after: true });
+        }
+
+        #[test]
+        fn pending_and_invalid() {
+            for keys in ["", "2", "d", "d2", "di", "g", "\"", "\"a", "\"ad"] {
+                assert_eq!(parse(keys), Parse::Pending, "{:?}", keys);
+            }
+            for keys in ["z", "dz", "diz", "gx", "dgx"] {
+                assert_eq!(parse(keys), Parse::Invalid, "{:?}", keys);
+            }
+        }
+
+        #[test]
+        fn changes() {
+            assert!(done("dd").is_change());
+            assert!(done("A").is_change());
+            assert!(done("P").is_change());
+            assert!(!done("yy").is_change());
+            assert!(!done("j").is_change());
+            assert!(!done(".").is_change());
+        }
+    }
+}
+
+/// The configuration files: the user's `~/.config/jot/config.toml`, and a
+/// project's `.jot.toml`, whose entries override the user's.
+pub mod config {
+    use serde::Deserialize;
+    use std::{
+        collections::BTreeMap,
+        env, fs, io,
+        path::{Path, PathBuf},
+    };
+
+    /// Key sequences mapped to action names, for one scope.
+    pub type Bindings = BTreeMap<String, String>;
+
+    /// The name of a project's config file, looked for in the directory
+    /// view's directory and its ancestors.
+    pub const PROJECT_FILE: &str = ".jot.toml";
+
+    #[derive(Deserialize, Default)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct Config {
+        /// Settings by name; see `settings::KEYS`.
+        pub editor: toml::Table,
+        pub keys: KeysConfig,
+    }
+
+    /// The `[keys]` table: the chord timeout and a table of overrides per
+    /// scope, such as `[keys.edit]`.
+    #[derive(Deserialize, Default)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct KeysConfig {
+        /// How long to wait for the next key of a chord, in milliseconds.
+        pub timeout_ms: Option<u64>,
+        pub global: Bindings,
+        pub edit: Bindings,
+        pub command: Bindings,
+        pub normal: Bindings,
+        pub tree: Bindings,
+        pub find: Bindings,
+    }
+
+    /// The directory of the user's configuration, `jot` under
+    /// `$XDG_CONFIG_HOME` or `~/.config`.
+    pub fn dir() -> Option<PathBuf> {
+        let config_home = env::var_os("XDG_CONFIG_HOME")
+            .filter(|v| !v.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(config_home.join("jot"))
+    }
+
+    /// Where the config file lives.
+    pub fn path() -> Option<PathBuf> {
+        Some(dir()?.join("config.toml"))
+    }
+
+    /// The nearest project config file in `dir` or above it.
+    pub fn find_project(dir: &Path) -> Option<PathBuf> {
+        dir.ancestors()
+            .map(|dir| dir.join(PROJECT_FILE))
+            .find(|path| path.is_file())
+    }
+
+    /// Reads the config file at `path`; a missing file gives the defaults.
+    /// Errors name the file and line, for the status bar.
+    pub fn read(path: &Path) -> Result<Config, String> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(format!("{}: {}", path.display(), e)),
+        };
+        toml::from_str(&text).map_err(|e| {
+            let line = e.span().map_or(0, |span| text[..span.start].matches('\n').count() + 1);
+            format!("{} line {}: {}", path.display(), line, e.message().trim())
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// Reads `text` as a config file.
+        fn read_text(name: &str, text: &str) -> Result<Config, String> {
+            let path = env::temp_dir().join(format!("jot-{}-{}.toml", name, std::process::id()));
+            fs::write(&path, text).unwrap();
+            let config = read(&path);
+            fs::remove_file(&path).unwrap();
+            config.map_err(|e| e.replacen(&path.display().to_string(), "config.toml", 1))
+        }
+
+        #[test]
+        fn reads_sections() {
+            let text = "[editor]\n\
+                        tab_width = 8\n\
+                        [keys]\n\
+                        timeout_ms = 300\n\
+                        [keys.edit]\n\
+                        \"ctrl+k ctrl+d\" = \"cut\"\n";
+            let config = read_text("sections", text).unwrap();
+            assert_eq!(config.editor["tab_width"].as_integer(), Some(8));
+            assert_eq!(config.keys.timeout_ms, Some(300));
+            assert_eq!(config.keys.edit["ctrl+k ctrl+d"], "cut");
+            assert!(config.keys.global.is_empty());
+        }
+
+        #[test]
+        fn missing_file_gives_defaults() {
+            let config = read(&env::temp_dir().join("jot-no-such-config.toml")).unwrap();
+            assert!(config.editor.is_empty());
+            assert_eq!(config.keys.timeout_ms, None);
+        }
+
+        #[test]
+        fn errors_name_the_line() {
+            let text = "[keys]\ntimeout_ms = 5\n\nbogus = 1\n";
+            let error = read_text("unknown", text).err().unwrap();
+            assert!(error.starts_with("config.toml line 4: unknown field `bogus`"), "{}", error);
+            let text = "[editor]\n\n[keys]\ntimeout_ms = \"soon\"\n";
+            let error = read_text("type", text).err().unwrap();
+            assert!(error.starts_with("config.toml line 4: "), "{}", error);
+            let error = read_text("syntax", "[keys\n").err().unwrap();
+            assert!(error.starts_with("config.toml line 1: "), "{}", error);
+        }
+    }
+}
+
+/// Syntax highlighting: language definitions read from TOML, and a
+/// line-at-a-time tokenizer whose state carries block comments and
+/// strings from one line to the next.
+pub mod syntax {
+    use regex::{Regex, RegexBuilder};
+    use serde::Deserialize;
+    use std::{
+        cell::{OnceCell, RefCell},
+        fs,
+        path::Path,
+        rc::Rc,
+    };
+
+    /// What a piece of text is, for the theme to give it a style.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Role {
+        Comment,
+        Keyword,
+        Type,
+        Function,
+        String,
+        Escape,
+        Number,
+        Constant,
+        Variable,
+        Property,
+        Attribute,
+        Label,
+        Heading,
+        Emphasis,
+        Link,
+        Code,
+    }
+
+    impl Role {
+        pub const ALL: [Role; 16] = [
+            Role::Comment,
+            Role::Keyword,
+            Role::Type,
+            Role::Function,
+            Role::String,
+            Role::Escape,
+            Role::Number,
+            Role::Constant,
+            Role::Variable,
+            Role::Property,
+            Role::Attribute,
+            Role::Label,
+            Role::Heading,
+            Role::Emphasis,
+            Role::Link,
+            Role::Code,
+        ];
+
+        pub fn name(self) -> &'static str {
+            match self {
+                Role::Comment => "comment",
+                Role::Keyword => "keyword",
+                Role::Type => "type",
+                Role::Function => "function",
+                Role::String => "string",
+                Role::Escape => "escape",
+                Role::Number => "number",
+                Role::Constant => "constant",
+                Role::Variable => "variable",
+                Role::Property => "property",
+                Role::Attribute => "attribute",
+                Role::Label => "label",
+                Role::Heading => "heading",
+                Role::Emphasis => "emphasis",
+                Role::Link => "link",
+                Role::Code => "code",
+            }
+        }
+
+        pub fn parse(s: &str) -> Option<Self> {
+            Role::ALL.into_iter().find(|role| role.name() == s)
+        }
+    }
+
+    /// The chars `start..end` of a line have `role`.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Span {
+        pub start: usize,
+        pub end: usize,
+        pub role: Role,
+    }
+
+    /// The region, by rule index, that a line starts inside of, if any.
+    pub type State = Option<usize>;
+
+    const BUILTIN: &[&str] = &[
+        include_str!("languages/rust.toml"),
+        include_str!("languages/toml.toml"),
+        include_str!("languages/markdown.toml"),
+        include_str!("languages/json.toml"),
+        include_str!("languages/shell.toml"),
+        include_str!("languages/yaml.toml"),
+    ];
+
+    /// A language definition as written in its TOML file.
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct Definition {
+        name: String,
+        #[serde(default)]
+        extensions: Vec<String>,
+        #[serde(default)]
+        filenames: Vec<String>,
+        /// Interpreter names, as in `#!/usr/bin/env bash`.
+        #[serde(default)]
+        shebangs: Vec<String>,
+        #[serde(default)]
+        rules: Vec<RuleDefinition>,
+        /// Chars typed as a pair, each written opener first, as in `"()"`.
+        /// Without the key the language uses `DEFAULT_PAIRS`.
+        pairs: Option<Vec<String>>,
+    }
+
+    /// One rule: a `pattern` or list of `words` that matches within a
+    /// line, or a region from `start` to `end` that may span lines. Only
+    /// the first capture group of a pattern is styled, when it has one.
+    #[derive(Deserialize)]
+    #[serde(deny_unknown_fields)]
+    struct RuleDefinition {
+        role: String,
+        pattern: Option<String>,
+        words: Option<Vec<String>>,
+        start: Option<String>,
+        end: Option<String>,
+        /// Inside a region, text such as `\"` that does not end it.
+        escape: Option<String>,
+    }
+
+    enum Rule {
+        Token { regex: Regex, role: Role },
+        Region { start: Regex, end: Regex, escape: Option<Regex>, role: Role },
+    }
+
+    impl Rule {
+        fn compile(def: &RuleDefinition) -> Result<Rule, String> {
+            let role = Role::parse(&def.role)
+                .ok_or_else(|| format!("unknown role \"{}\"", def.role))?;
+            let compile = |pattern: &str| -> Result<Regex, String> {
+                let regex = RegexBuilder::new(pattern)
+                    .multi_line(true)
+                    .build()
+                    .map_err(|e| e.to_string())?;
+                if regex.is_match("") {
+                    return Err(format!("pattern \"{}\" matches empty text", pattern));
+                }
+                Ok(regex)
+            };
+            match (&def.pattern, &def.words, &def.start, &def.end) {
+                (Some(pattern), None, None, None) => Ok(Rule::Token { regex: compile(pattern)?, role }),
+                (None, Some(words), None, None) => {
+                    if words.iter().any(|w| w.is_empty()) {
+                        return Err("words must not be empty".to_string());
+                    }
+                    let words: Vec<_> = words.iter().map(|w| regex::escape(w)).collect();
+                    let pattern = format!(r"\b(?:{})\b", words.join("|"));
+                    Ok(Rule::Token { regex: compile(&pattern)?, role })
+                }
+                (None, None, Some(start), Some(end)) => Ok(Rule::Region {
+                    start: compile(start)?,
+                    end: compile(end)?,
+                    escape: def.escape.as_deref().map(compile).transpose()?,
+                    role,
+                }),
+                _ => Err("a rule needs one of pattern, words, or start and end".to_string()),
+            }
+        }
+
+        fn regex(&self) -> &Regex {
+            match self {
+                Rule::Token { regex, .. } => regex,
+                Rule::Region { start, .. } => start,
+            }
+        }
+    }
+
+    /// A match of a rule: the bytes it consumes and the bytes it styles.
+    #[derive(Clone, Copy)]
+    struct Hit {
+        start: usize,
+        end: usize,
+        styled: (usize, usize),
+    }
+
+    /// The first match of `regex` at or after `from` that takes at least
+    /// one char. Patterns such as `\b` match nothing inside text, and
+    /// taking those would stop a scan from moving forward.
+    fn captures_at<'a>(regex: &Regex, line: &'a str, mut from: usize) -> Option<regex::Captures<'a>> {
+        loop {
+            let captures = regex.captures_at(line, from)?;
+            let whole = captures.get(0)?;
+            if !whole.is_empty() {
+                return Some(captures);
+            }
+            from = whole.start() + line[whole.start()..].chars().next()?.len_utf8();
+        }
+    }
+
+    fn find(regex: &Regex, line: &str, from: usize) -> Option<Hit> {
+        let captures = captures_at(regex, line, from)?;
+        let whole = captures.get(0)?;
+        let styled = captures.get(1).unwrap_or(whole);
+        Some(Hit {
+            start: whole.start(),
+            end: whole.end(),
+            styled: (styled.start(), styled.end()),
+        })
+    }
+
+    /// The pairs closed automatically in buffers without a language, or
+    /// whose definition does not list its own.
+    pub const DEFAULT_PAIRS: &[(char, char)] = &[('(', ')'), ('[', ']'), ('{', '}'), ('"', '"')];
+
+    pub struct Language {
+        pub name: String,
+        /// Opening and closing chars typed as a pair.
+        pub pairs: Vec<(char, char)>,
+        rules: Vec<Rule>,
+    }
+
+    impl Language {
+        /// Splits `line` into styled spans, given the state it starts in,
+        /// and returns the state the next line starts in.
+        pub fn highlight_line(&self, line: &str, mut state: State) -> (Vec<Span>, State) {
+            let mut spans: Vec<(usize, usize, Role)> = Vec::new();
+            let mut push = |start: usize, end: usize, role: Role| match spans.last_mut() {
+                _ if end <= start => {}
+                Some(last) if last.1 == start && last.2 == role => last.1 = end,
+                _ => spans.push((start, end, role)),
+            };
+            let mut pos = 0;
+            // The next match of each rule at or after `pos`, refreshed only
+            // once `pos` has passed it.
+            let mut next: Vec<Option<Hit>> =
+                self.rules.iter().map(|rule| find(rule.regex(), line, 0)).collect();
+            loop {
+                if let Some(index) = state {
+                    let Rule::Region { end, escape, role, .. } = &self.rules[index] else {
+                        return (Vec::new(), None);
+                    };
+                    let mut from = pos;
+                    let close = loop {
+                        let close = captures_at(end, line, from).and_then(|c| c.get(0));
+                        let skip = escape.as_ref().and_then(|e| captures_at(e, line, from)?.get(0));
+                        match (close, skip) {
+                            (close, Some(skip))
+                                if close.is_none_or(|c| skip.start() < c.start()) =>
+                            {
+                                push(pos, skip.start(), *role);
+                                push(skip.start(), skip.end(), Role::Escape);
+                                pos = skip.end();
+                                from = skip.end();
+                            }
+                            (close, _) => break close,
+                        }
+                    };
+                    match close {
+                        Some(close) => {
+                            push(pos, close.end(), *role);
+                            pos = close.end();
+                            state = None;
+                        }
+                        None => {
+                            push(pos, line.len(), *role);
+                            break;
+                        }
+                    }
+                }
+
+                for (rule, hit) in self.rules.iter().zip(next.iter_mut()) {
+                    if hit.is_some_and(|h| h.start < pos) {
+                        *hit = find(rule.regex(), line, pos);
+                    }
+                }
+                let best = next
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, hit)| Some((i, (*hit)?)))
+                    .min_by_key(|&(i, hit)| (hit.start, i));
+                let Some((index, hit)) = best else {
+                    break;
+                };
+                match &self.rules[index] {
+                    Rule::Token { role, .. } => push(hit.styled.0, hit.styled.1, *role),
+                    Rule::Region { role, .. } => {
+                        push(hit.start, hit.end, *role);
+                        state = Some(index);
+                    }
+                }
+                pos = hit.end;
+            }
+
+            (to_char_spans(line, spans), state)
+        }
+    }
+
+    /// Converts byte ranges, in order, to char ranges.
+    fn to_char_spans(line: &str, spans: Vec<(usize, usize, Role)>) -> Vec<Span> {
+        let mut chars = 0;
+        let mut byte = 0;
+        let mut char_at = |target: usize| {
+            chars += line[byte..target].chars().count();
+            byte = target;
+            chars
+        };
+        spans
+            .into_iter()
+            .map(|(start, end, role)| Span {
+                start: char_at(start),
+                end: char_at(end),
+                role,
+            })
+            .collect()
+    }
+
+    struct Entry {
+        definition: Definition,
+        compiled: OnceCell<Result<Rc<Language>, String>>,
+    }
+
+    /// All known languages. Definitions are compiled on first use.
+    #[derive(Default)]
+    pub struct Registry {
+        entries: Vec<Entry>,
+    }
+
+    impl Registry {
+        /// The builtin languages, then any `*.toml` in `user_dir`; a user
+        /// definition with a builtin's name replaces it. Returns the
+        /// problems found with the user's files.
+        pub fn load(user_dir: Option<&Path>) -> (Registry, Vec<String>) {
+            let mut registry = Registry::default();
+            for text in BUILTIN {
+                let definition = toml::from_str(text).expect("builtin language definition");
+                registry.add(definition);
+            }
+            let mut errors = Vec::new();
+            let Some(dir) = user_dir else {
+                return (registry, errors);
+            };
+            let Ok(read_dir) = fs::read_dir(dir) else {
+                return (registry, errors);
+            };
+            let mut paths: Vec<_> = read_dir
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.extension().is_some_and(|ext| ext == "toml"))
+                .collect();
+            paths.sort();
+            for path in paths {
+                let parsed = fs::read_to_string(&path)
+                    .map_err(|e| e.to_string())
+                    .and_then(|text| toml::from_str(&text).map_err(|e| e.message().trim().to_string()));
+                match parsed {
+                    Ok(definition) => registry.add(definition),
+                    Err(e) => errors.push(format!("{}: {}", path.display(), e)),
+                }
+            }
+            (registry, errors)
+        }
+
+        fn add(&mut self, definition: Definition) {
+            self.entries.retain(|entry| entry.definition.name != definition.name);
+            self.entries.push(Entry {
+                definition,
+                compiled: OnceCell::new(),
+            });
+        }
+
+        pub fn names(&self) -> Vec<&str> {
+            self.entries.iter().map(|e| e.definition.name.as_str()).collect()
+        }
+
+        /// The language called `name`.
+        pub fn get(&self, name: &str) -> Option<Result<Rc<Language>, String>> {
+            let entry = self.entries.iter().find(|e| e.definition.name == name)?;
+            Some(
+                entry
+                    .compiled
+                    .get_or_init(|| compile(&entry.definition))
+                    .clone(),
+            )
+        }
+
+        /// The language of the file at `path` whose first line is
+        /// `first_line`, by file name, then extension, then shebang.
+        pub fn detect(&self, path: &Path, first_line: &str) -> Option<Result<Rc<Language>, String>> {
+            let file_name = path.file_name()?.to_string_lossy();
+            let extension = path.extension().map(|ext| ext.to_string_lossy());
+            let interpreter = shebang_interpreter(first_line);
+            let by = |matches: &dyn Fn(&Definition) -> bool| {
+                self.entries.iter().find(|e| matches(&e.definition))
+            };
+            let entry = by(&|d| d.filenames.iter().any(|f| *f == file_name))
+                .or_else(|| {
+                    let extension = extension.as_deref()?;
+                    by(&|d| d.extensions.iter().any(|e| e == extension))
+                })
+                .or_else(|| {
+                    let interpreter = interpreter?;
+                    by(&|d| d.shebangs.iter().any(|s| s == interpreter))
+                })?;
+            self.get(&entry.definition.name)
+        }
+    }
+
+    fn compile(definition: &Definition) -> Result<Rc<Language>, String> {
+        let rules = definition
+            .rules
+            .iter()
+            .enumerate()
+            .map(|(i, rule)| {
+                Rule::compile(rule)
+                    .map_err(|e| format!("{} rule {}: {}", definition.name, i + 1, e))
+            })
+            .collect::<Result<_, _>>()?;
+        let pairs = match &definition.pairs {
+            Some(pairs) => pairs
+                .iter()
+                .map(|pair| {
+                    let mut chars = pair.chars();
+                    match (chars.next(), chars.next(), chars.next()) {
+                        (Some(open), Some(close), None) => Ok((open, close)),
+                        _ => Err(format!("{} pair \"{}\" is not two chars", definition.name, pair)),
+                    }
+                })
+                .collect::<Result<_, _>>()?,
+            None => DEFAULT_PAIRS.to_vec(),
+        };
+        Ok(Rc::new(Language {
+            name: definition.name.clone(),
+            pairs,
+            rules,
+        }))
+    }
+
+    /// The program named by a `#!` line, looking through `env`.
+    fn shebang_interpreter(first_line: &str) -> Option<&str> {
+        let mut words = first_line.strip_prefix("#!")?.split_whitespace();
+        let program = words.next()?.rsplit('/').next()?;
+        if program == "env" {
+            words.find(|word| !word.starts_with('-') && !word.contains('='))
+        } else {
+            Some(program)
+        }
+    }
+
+    /// A language and the state at the start of each line, kept as far as
+    /// lines have been highlighted and cut back when a line is edited.
+    pub struct Highlighter {
+        pub language: Rc<Language>,
+        states: RefCell<Vec<State>>,
+    }
+
+    impl Highlighter {
+        pub fn new(language: Rc<Language>) -> Self {
+            Self {
+                language,
+                states: RefCell::new(vec![None]),
+            }
+        }
+
+        /// Forgets the states after `row`, whose text has changed.
+        pub fn invalidate_from(&self, row: usize) {
+            self.states.borrow_mut().truncate(row + 1);
+        }
+
+        /// The spans of `row`; `line(r)` gives the text of row `r`. Lines
+        /// above `row` not yet seen are tokenized once to find its state.
+        pub fn highlight(&self, row: usize, line: impl Fn(usize) -> String) -> Vec<Span> {
+            let mut states = self.states.borrow_mut();
+            while states.len() <= row {
+                let known = states.len() - 1;
+                let (_, next) = self.language.highlight_line(&line(known), states[known]);
+                states.push(next);
+            }
+            self.language.highlight_line(&line(row), states[row]).0
+        }
+    }
+}
+
+/// Editor settings, from the `[editor]` table of the global config file
+/// and of the project's `.jot.toml`, and from `:set`.
+pub mod settings {
+    use super::clipboard::ClipboardMode;
+
+    /// How lines are numbered in the gutter.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum LineNumbers {
+        Absolute,
+        /// Distance from the cursor line, which shows its own number.
+        Relative,
+        Off,
+    }
+
+    impl LineNumbers {
+        pub fn name(self) -> &'static str {
+            match self {
+                LineNumbers::Absolute => "absolute",
+                LineNumbers::Relative => "relative",
+                LineNumbers::Off => "off",
+            }
+        }
+
+        pub fn parse(s: &str) -> Option<Self> {
+            [LineNumbers::Absolute, LineNumbers::Relative, LineNumbers::Off]
+                .into_iter()
+                .find(|style| style.name() == s)
+        }
+    }
 
-        fn execute_new_item(&mut self, name: String, mode: Mode) {
-            let mut path = self.directory_view.path.clone();
-            path.push(name);
+    #[derive(Clone, Debug)]
+    pub struct Settings {
+        /// Width of the file tree, in percent of the terminal width.
+        pub tree_width: u16,
+        /// Columns per indentation level, and per tab stop.
+        pub tab_width: usize,
+        /// Indent with spaces rather than tab characters.
+        pub expand_tabs: bool,
+        pub line_numbers: LineNumbers,
+        /// Rows kept visible above and below the cursor when scrolling.
+        pub scroll_margin: usize,
+        /// Wrap long lines at word boundaries instead of scrolling sideways.
+        pub wrap: bool,
+        /// Close brackets and quotes as they are opened, and type over the
+        /// closing one.
+        pub auto_pairs: bool,
+        /// The builtin `default` theme, or one in `~/.config/jot/themes`.
+        pub theme: String,
+        /// Copy the previous contents to `<file>~` before saving over it.
+        pub backup: bool,
+        pub clipboard: ClipboardMode,
+        /// Esc leads to vim-style normal mode rather than the command line.
+        pub vim: bool,
+    }
 
-            let result = if mode == Mode::PromptNewFile {
-                fs::write(&path, "")
-            } else {
-                fs::create_dir(&path)
+    impl Default for Settings {
+        fn default() -> Self {
+            Self {
+                tree_width: 25,
+                tab_width: 4,
+                expand_tabs: true,
+                line_numbers: LineNumbers::Absolute,
+                scroll_margin: 0,
+                wrap: false,
+                auto_pairs: true,
+                theme: "default".to_string(),
+                backup: false,
+                clipboard: ClipboardMode::Auto,
+                vim: false,
+            }
+        }
+    }
+
+    /// Every setting, by the name used in config files and `:set`.
+    pub const KEYS: &[&str] = &[
+        "tree_width",
+        "tab_width",
+        "expand_tabs",
+        "line_numbers",
+        "scroll_margin",
+        "wrap",
+        "auto_pairs",
+        "theme",
+        "backup",
+        "clipboard",
+        "vim",
+    ];
+
+    fn parse_bool(value: &str) -> Option<bool> {
+        match value {
+            "on" | "yes" | "true" => Some(true),
+            "off" | "no" | "false" => Some(false),
+            _ => None,
+        }
+    }
+
+    fn parse_in(value: &str, min: usize, max: usize) -> Option<usize> {
+        value.parse().ok().filter(|n| (min..=max).contains(n))
+    }
+
+    impl Settings {
+        /// Sets `key` from its text form, as typed after `:set key=`.
+        pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+            let valid = match key {
+                "tree_width" => parse_in(value, 5, 90).map(|n| self.tree_width = n as u16),
+                "tab_width" => parse_in(value, 1, 16).map(|n| self.tab_width = n),
+                "expand_tabs" => parse_bool(value).map(|on| self.expand_tabs = on),
+                "line_numbers" => LineNumbers::parse(value).map(|style| self.line_numbers = style),
+                "scroll_margin" => parse_in(value, 0, 100).map(|n| self.scroll_margin = n),
+                "wrap" => parse_bool(value).map(|on| self.wrap = on),
+                "auto_pairs" => parse_bool(value).map(|on| self.auto_pairs = on),
+                "theme" if !value.is_empty() => {
+                    self.theme = value.to_string();
+                    Some(())
+                }
+                "backup" => parse_bool(value).map(|on| self.backup = on),
+                "clipboard" => ClipboardMode::parse(value).map(|mode| self.clipboard = mode),
+                "vim" => parse_bool(value).map(|on| self.vim = on),
+                _ if !KEYS.contains(&key) => return Err(format!("unknown setting \"{}\"", key)),
+                _ => None,
             };
+            valid.ok_or_else(|| format!("invalid value \"{}\" for {}", value, key))
+        }
 
-            match result {
-                Ok(_) => {
-                    self.status_message = format!("Created {}", path.display());
-                    if mode == Mode::PromptNewFile {
-                        self.tabs.push(Page::from_file(Some(path)));
-                        self.active_tab_index = self.tabs.len() - 1;
-                        self.active_pane = ActivePane::Editor;
-                        self.mode = Mode::Edit;
-                    } else {
-                        self.mode = Mode::FileTree;
-                    }
-                    let current_dir = self.directory_view.path.clone();
-                    if let Ok(new_view) = DirectoryView::new(current_dir) {
-                        self.directory_view = new_view;
+        /// The text form of `key`, if it is a setting.
+        pub fn get(&self, key: &str) -> Option<String> {
+            Some(match key {
+                "tree_width" => self.tree_width.to_string(),
+                "tab_width" => self.tab_width.to_string(),
+                "expand_tabs" => self.expand_tabs.to_string(),
+                "line_numbers" => self.line_numbers.name().to_string(),
+                "scroll_margin" => self.scroll_margin.to_string(),
+                "wrap" => self.wrap.to_string(),
+                "auto_pairs" => self.auto_pairs.to_string(),
+                "theme" => self.theme.clone(),
+                "backup" => self.backup.to_string(),
+                "clipboard" => self.clipboard.name().to_string(),
+                "vim" => self.vim.to_string(),
+                _ => return None,
+            })
+        }
+
+        /// Every setting as `key=value`, for `:set` without arguments.
+        pub fn describe(&self) -> String {
+            KEYS.iter()
+                .filter_map(|key| Some(format!("{}={}", key, self.get(key)?)))
+                .collect::<Vec<_>>()
+                .join(" ")
+        }
+
+        /// Applies an `[editor]` table from a config file, returning a
+        /// message for each entry that could not be used.
+        pub fn configure(&mut self, table: &toml::Table) -> Vec<String> {
+            let mut errors = Vec::new();
+            for (key, value) in table {
+                let text = match value {
+                    toml::Value::String(s) => s.clone(),
+                    toml::Value::Integer(n) => n.to_string(),
+                    toml::Value::Boolean(b) => b.to_string(),
+                    other => {
+                        errors.push(format!("[editor] {}: unsupported value {}", key, other));
+                        continue;
                     }
-                },
-                Err(e) => {
-                    self.status_message = format!("Error: {}", e);
-                    self.mode = Mode::FileTree;
+                };
+                if let Err(e) = self.set(key, &text) {
+                    errors.push(format!("[editor] {}", e));
                 }
             }
+            errors
         }
 
-        fn prompt_for_rename(&mut self) {
-            if let Some(entry) = self.directory_view.entries.get(self.directory_view.selected_index) {
-                let path = entry.path();
-                self.path_to_rename = Some(path);
-                self.mode = Mode::PromptRename;
+        /// What one level of indentation inserts.
+        pub fn indent_unit(&self) -> String {
+            if self.expand_tabs {
+                " ".repeat(self.tab_width)
+            } else {
+                "\t".to_string()
             }
         }
 
+        /// Columns taken by the file tree on a terminal `term_width` wide.
+        pub fn tree_width(&self, term_width: u16) -> u16 {
+            ((term_width as u32 * self.tree_width as u32 + 50) / 100) as u16
+        }
 
-        fn go_up_directory(&mut self) {
-            if let Some(parent) = self.directory_view.path.parent() {
-                match DirectoryView::new(parent.to_path_buf()) {
-                    Ok(new_view) => self.directory_view = new_view,
-                    Err(_) => self.status_message = "Cannot access parent directory.".to_string(),
-                }
+        /// Columns taken by the line numbers of a buffer of `line_count`
+        /// lines, including the space after them.
+        pub fn gutter_width(&self, line_count: usize) -> u16 {
+            match self.line_numbers {
+                LineNumbers::Off => 0,
+                _ => line_count.to_string().len() as u16 + 2,
             }
         }
+    }
+}
 
-        fn handle_editor_event(&mut self, event: KeyEvent) {
-            if self.mode == Mode::Find {
-                self.handle_find_event(event);
-                return;
+/// Themes: the style of each part of the screen and of each syntax role,
+/// read from TOML and turned into escape sequences the terminal supports.
+pub mod theme {
+    use super::syntax::Role;
+    use serde::Deserialize;
+    use std::{collections::BTreeMap, env, fs, path::Path};
+
+    /// Ends any style begun by `Theme::ui` or `Theme::syntax`.
+    pub const RESET: &str = "\x1b[0m";
+
+    const DEFAULT: &str = include_str!("themes/default.toml");
+
+    /// A part of the screen with a style of its own.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Element {
+        /// Headings over the file tree and the overlay lists.
+        Title,
+        TabActive,
+        TabInactive,
+        Gutter,
+        Selection,
+        SearchMatch,
+        StatusBar,
+        /// A file in the tree.
+        DirectoryEntry,
+        /// A directory in the tree.
+        Directory,
+        TreeSelected,
+        /// The tree's selection while the editor has focus.
+        TreeSelectedUnfocused,
+        /// The highlighted item of an overlay list.
+        ListSelected,
+        Divider,
+        /// The bracket paired with the one at the cursor.
+        MatchingBracket,
+    }
+
+    impl Element {
+        pub const ALL: [Element; 14] = [
+            Element::Title,
+            Element::TabActive,
+            Element::TabInactive,
+            Element::Gutter,
+            Element::Selection,
+            Element::SearchMatch,
+            Element::StatusBar,
+            Element::DirectoryEntry,
+            Element::Directory,
+            Element::TreeSelected,
+            Element::TreeSelectedUnfocused,
+            Element::ListSelected,
+            Element::Divider,
+            Element::MatchingBracket,
+        ];
+
+        pub fn name(self) -> &'static str {
+            match self {
+                Element::Title => "title",
+                Element::TabActive => "tab_active",
+                Element::TabInactive => "tab_inactive",
+                Element::Gutter => "gutter",
+                Element::Selection => "selection",
+                Element::SearchMatch => "search_match",
+                Element::StatusBar => "status_bar",
+                Element::DirectoryEntry => "directory_entry",
+                Element::Directory => "directory",
+                Element::TreeSelected => "tree_selected",
+                Element::TreeSelectedUnfocused => "tree_selected_unfocused",
+                Element::ListSelected => "list_selected",
+                Element::Divider => "divider",
+                Element::MatchingBracket => "matching_bracket",
             }
+        }
+    }
 
-            if self.mode == Mode::PromptSave || self.mode == Mode::PromptSaveAndQuit {
-                self.handle_prompt_event(event.code);
-                return;
+    /// How many colours the terminal can show.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum ColorSupport {
+        /// `NO_COLOR` is set: bold, reverse and the like, but no colour.
+        None,
+        Ansi16,
+        Ansi256,
+        TrueColor,
+    }
+
+    impl ColorSupport {
+        /// Reads `NO_COLOR`, `COLORTERM` and `TERM`.
+        pub fn detect() -> Self {
+            let var = |name| env::var(name).unwrap_or_default();
+            if !var("NO_COLOR").is_empty() || var("TERM") == "dumb" {
+                ColorSupport::None
+            } else if matches!(var("COLORTERM").as_str(), "truecolor" | "24bit") {
+                ColorSupport::TrueColor
+            } else if var("TERM").contains("256color") {
+                ColorSupport::Ansi256
+            } else {
+                ColorSupport::Ansi16
             }
+        }
+    }
 
-            match event.code {
-                KeyCode::Esc => match self.mode {
-                    Mode::Edit => self.mode = Mode::Command,
-                    Mode::Command => {
-                        if !self.tabs.is_empty() {
-                            self.mode = Mode::Edit;
-                            self.command_buffer.clear();
-                        }
-                    }
-                    _ => {}
-                },
-                KeyCode::Char(c) => match self.mode {
-                    Mode::Edit => {
-                        if let Some(page) = self.get_active_page() {
-                            page.current.insert(c);
-                        }
-                    }
-                    Mode::Command => self.command_buffer.push(c),
-                    _ => {}
-                },
-                KeyCode::Backspace => match self.mode {
-                    Mode::Edit => {
-                        if let Some(page) = self.get_active_page() {
-                            page.delete();
-                        }
-                    }
-                    Mode::Command => {
-                        self.command_buffer.pop();
-                    }
-                    _ => {}
-                },
-                KeyCode::Enter => match self.mode {
-                    Mode::Edit => {
-                        if let Some(page) = self.get_active_page() {
-                            page.insert_newline();
-                        }
-                    }
-                    Mode::Command => {
-                        self.execute_command();
-                    }
-                    _ => {}
-                },
-                KeyCode::Left => {
-                    if self.mode == Mode::Command {
-                        if self.tabs.len() > 1 {
-                            self.active_tab_index =
-                                (self.active_tab_index + self.tabs.len() - 1) % self.tabs.len();
-                        }
-                    } else if self.mode == Mode::Edit {
-                        if let Some(p) = self.get_active_page() {
-                            p.current.move_left()
-                        }
-                    }
-                }
-                KeyCode::Right => {
-                    if self.mode == Mode::Command {
-                        if self.tabs.len() > 1 {
-                            self.active_tab_index = (self.active_tab_index + 1) % self.tabs.len();
-                        }
-                    } else if self.mode == Mode::Edit {
-                        if let Some(p) = self.get_active_page() {
-                            p.current.move_right()
-                        }
-                    }
-                }
-                KeyCode::Up => {
-                    if self.mode == Mode::Edit {
-                        if let Some(p) = self.get_active_page() {
-                            p.move_up()
-                        }
-                    }
-                }
-                KeyCode::Down => {
-                    if self.mode == Mode::Edit {
-                        if let Some(p) = self.get_active_page() {
-                            p.move_down()
-                        }
-                    }
-                }
-                KeyCode::Tab => {
-                    self.active_pane = ActivePane::FileTree;
-                    self.mode = Mode::FileTree;
-                    self.command_buffer.clear();
-                }
-                _ => {}
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Color {
+        /// One of the 16 standard colours, 8 and up being the bright ones.
+        Ansi(u8),
+        /// An entry of the 256-colour palette.
+        Indexed(u8),
+        Rgb(u8, u8, u8),
+    }
+
+    const COLOR_NAMES: [&str; 8] =
+        ["black", "red", "green", "yellow", "blue", "magenta", "cyan", "white"];
+
+    /// Typical values of the 16 standard colours.
+    const ANSI_RGB: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (205, 0, 0),
+        (0, 205, 0),
+        (205, 205, 0),
+        (0, 0, 238),
+        (205, 0, 205),
+        (0, 205, 205),
+        (229, 229, 229),
+        (127, 127, 127),
+        (255, 0, 0),
+        (0, 255, 0),
+        (255, 255, 0),
+        (92, 92, 255),
+        (255, 0, 255),
+        (0, 255, 255),
+        (255, 255, 255),
+    ];
+
+    /// The levels of each channel in the 6x6x6 part of the 256-colour palette.
+    const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+    impl Color {
+        /// A name such as `red` or `bright-red`, `#rrggbb`, or a palette
+        /// index from 0 to 255.
+        pub fn parse(s: &str) -> Option<Self> {
+            if let Some(hex) = s.strip_prefix('#') {
+                let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+                return (hex.len() == 6)
+                    .then(|| Some(Color::Rgb(channel(0)?, channel(2)?, channel(4)?)))?;
+            }
+            if let Ok(index) = s.parse() {
+                return Some(Color::Indexed(index));
             }
+            let (bright, name) = match s.strip_prefix("bright-") {
+                Some(name) => (8, name),
+                None if s == "gray" || s == "grey" => (8, "black"),
+                None => (0, s),
+            };
+            let base = COLOR_NAMES.iter().position(|&n| n == name)? as u8;
+            Some(Color::Ansi(base + bright))
         }
 
-        fn handle_find_event(&mut self, event: KeyEvent) {
-            match event.code {
-                KeyCode::Esc => {
-                    self.mode = Mode::Command;
-                    self.find_query.clear();
-                    self.find_matches.clear();
-                    self.find_navigation_active = false;
-                }
-                KeyCode::Enter => {
-                    if !self.find_query.is_empty() {
-                        self.find_navigation_active = true;
-                        self.jump_to_match();
-                    }
-                }
-                KeyCode::Char('n') if self.find_navigation_active && event.modifiers == KeyModifiers::NONE => {
-                    self.jump_to_next_match();
+        fn rgb(self) -> (u8, u8, u8) {
+            match self {
+                Color::Ansi(n) => ANSI_RGB[n as usize % 16],
+                Color::Indexed(n @ 0..=15) => ANSI_RGB[n as usize],
+                Color::Indexed(n @ 16..=231) => {
+                    let n = n as usize - 16;
+                    (CUBE_LEVELS[n / 36], CUBE_LEVELS[n / 6 % 6], CUBE_LEVELS[n % 6])
                 }
-                KeyCode::Char('N') | KeyCode::Char('n') if self.find_navigation_active && event.modifiers == KeyModifiers::SHIFT => {
-                    self.jump_to_prev_match();
+                Color::Indexed(n) => {
+                    let level = 8 + (n - 232) * 10;
+                    (level, level, level)
                 }
-                KeyCode::Char(c) => {
-                    if self.find_navigation_active {
-                        self.find_query.clear();
-                        self.find_navigation_active = false;
-                    }
-                    self.find_query.push(c);
-                    self.update_search_matches();
+                Color::Rgb(r, g, b) => (r, g, b),
+            }
+        }
+
+        /// The nearest colour the terminal can show, if it shows colour.
+        fn degrade(self, support: ColorSupport) -> Option<Color> {
+            match (support, self) {
+                (ColorSupport::None, _) => None,
+                (ColorSupport::TrueColor, _) | (_, Color::Ansi(_)) => Some(self),
+                (ColorSupport::Ansi256, Color::Indexed(_)) => Some(self),
+                (ColorSupport::Ansi256, Color::Rgb(r, g, b)) => {
+                    let level = |c: u8| {
+                        CUBE_LEVELS
+                            .iter()
+                            .enumerate()
+                            .min_by_key(|&(_, &l)| l.abs_diff(c))
+                            .map_or(0, |(i, _)| i as u8)
+                    };
+                    let cube = Color::Indexed(16 + 36 * level(r) + 6 * level(g) + level(b));
+                    let average = (r as u16 + g as u16 + b as u16) / 3;
+                    let gray = Color::Indexed(232 + (average.saturating_sub(8) / 10).min(23) as u8);
+                    Some([cube, gray].into_iter().min_by_key(|c| distance(c.rgb(), (r, g, b)))?)
                 }
-                KeyCode::Backspace => {
-                    if !self.find_query.is_empty() {
-                        self.find_navigation_active = false;
-                        self.find_query.pop();
-                        self.update_search_matches();
+                (ColorSupport::Ansi16, _) => {
+                    // By hue rather than distance, which would turn most
+                    // muted colours gray.
+                    let (r, g, b) = self.rgb();
+                    let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+                    if max - min < 40 {
+                        return Some(Color::Ansi(match max {
+                            0..64 => 0,
+                            64..160 => 8,
+                            160..224 => 7,
+                            _ => 15,
+                        }));
                     }
+                    let strong = |c: u8| (c as u16 * 4 >= max as u16 * 3) as u8;
+                    let base = strong(r) | strong(g) << 1 | strong(b) << 2;
+                    Some(Color::Ansi(base + if max > 200 { 8 } else { 0 }))
                 }
-                _ => {}
             }
         }
-        
-        fn update_search_matches(&mut self) {
-            self.find_matches.clear();
-            if self.find_query.is_empty() {
-                return;
+
+        fn sgr(self, background: bool) -> String {
+            let base = if background { 40 } else { 30 };
+            match self {
+                Color::Ansi(n @ 0..=7) => (base + n as u32).to_string(),
+                Color::Ansi(n) => (base + 60 + (n as u32 - 8)).to_string(),
+                Color::Indexed(n) => format!("{};5;{}", base + 8, n),
+                Color::Rgb(r, g, b) => format!("{};2;{};{};{}", base + 8, r, g, b),
             }
-            if let Some(page) = self.tabs.get(self.active_tab_index) {
-                for (row, line) in page.get_all_lines().iter().enumerate() {
-                    for (col, _) in line.match_indices(&self.find_query) {
-                        self.find_matches.push((row, col));
-                    }
+        }
+    }
+
+    fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+        let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+        d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+    }
+
+    /// A style as written in a theme file.
+    #[derive(Deserialize, Default, Clone)]
+    #[serde(default, deny_unknown_fields)]
+    pub struct Style {
+        pub fg: Option<String>,
+        pub bg: Option<String>,
+        pub bold: bool,
+        pub dim: bool,
+        pub italic: bool,
+        pub underline: bool,
+        pub reverse: bool,
+    }
+
+    impl Style {
+        /// The escape sequence that starts this style, or nothing for the
+        /// terminal's own.
+        fn escape(&self, support: ColorSupport) -> Result<String, String> {
+            let mut codes = Vec::new();
+            for (on, code) in [
+                (self.bold, "1"),
+                (self.dim, "2"),
+                (self.italic, "3"),
+                (self.underline, "4"),
+                (self.reverse, "7"),
+            ] {
+                if on {
+                    codes.push(code.to_string());
                 }
             }
-            if !self.find_matches.is_empty() {
-                self.current_match_index = 0;
-                self.jump_to_match();
+            for (color, background) in [(&self.fg, false), (&self.bg, true)] {
+                let Some(color) = color else { continue };
+                let parsed =
+                    Color::parse(color).ok_or_else(|| format!("invalid colour \"{}\"", color))?;
+                if let Some(color) = parsed.degrade(support) {
+                    codes.push(color.sgr(background));
+                }
+            }
+            if codes.is_empty() {
+                return Ok(String::new());
             }
+            Ok(format!("\x1b[{}m", codes.join(";")))
         }
+    }
 
-        fn jump_to_next_match(&mut self) {
-            if !self.find_matches.is_empty() {
-                self.current_match_index = (self.current_match_index + 1) % self.find_matches.len();
-                self.jump_to_match();
+    #[derive(Deserialize, Default)]
+    #[serde(default, deny_unknown_fields)]
+    struct ThemeFile {
+        ui: BTreeMap<String, Style>,
+        syntax: BTreeMap<String, Style>,
+    }
+
+    /// Escape sequences for every element and role, ready to print.
+    pub struct Theme {
+        pub name: String,
+        ui: Vec<String>,
+        syntax: Vec<String>,
+    }
+
+    impl Default for Theme {
+        fn default() -> Self {
+            Theme::load("default", None, ColorSupport::Ansi16).expect("builtin theme")
+        }
+    }
+
+    impl Theme {
+        /// The theme `name`: the builtin one, overlaid with
+        /// `<user_dir>/<name>.toml` when that exists. Colours are degraded
+        /// to what `support` allows.
+        pub fn load(name: &str, user_dir: Option<&Path>, support: ColorSupport) -> Result<Theme, String> {
+            let parse = |text: &str| toml::from_str::<ThemeFile>(text).map_err(|e| e.message().trim().to_string());
+            let mut file = parse(DEFAULT)?;
+            let path = user_dir.map(|dir| dir.join(format!("{}.toml", name)));
+            match path.as_ref().map(fs::read_to_string) {
+                Some(Ok(text)) => {
+                    let path = path.as_ref().map_or_else(String::new, |p| p.display().to_string());
+                    let user = parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+                    file.ui.extend(user.ui);
+                    file.syntax.extend(user.syntax);
+                }
+                _ if name == "default" => {}
+                _ => return Err(format!("unknown theme \"{}\"", name)),
             }
+
+            let resolve = |table: &BTreeMap<String, Style>, names: Vec<&str>, section: &str| {
+                if let Some(unknown) = table.keys().find(|key| !names.contains(&key.as_str())) {
+                    return Err(format!("unknown [{}] entry \"{}\"", section, unknown));
+                }
+                names
+                    .iter()
+                    .map(|name| {
+                        let style = table.get(*name).cloned().unwrap_or_default();
+                        style.escape(support).map_err(|e| format!("[{}] {}: {}", section, name, e))
+                    })
+                    .collect::<Result<Vec<_>, _>>()
+            };
+            Ok(Theme {
+                name: name.to_string(),
+                ui: resolve(&file.ui, Element::ALL.iter().map(|e| e.name()).collect(), "ui")?,
+                syntax: resolve(&file.syntax, Role::ALL.iter().map(|r| r.name()).collect(), "syntax")?,
+            })
         }
 
-        fn jump_to_prev_match(&mut self) {
-            if !self.find_matches.is_empty() {
-                self.current_match_index = (self.current_match_index + self.find_matches.len() - 1) % self.find_matches.len();
-                self.jump_to_match();
+        /// The escape sequence that starts `element`'s style.
+        pub fn ui(&self, element: Element) -> &str {
+            &self.ui[element as usize]
+        }
+
+        /// The escape sequence that starts the style of text with `role`.
+        pub fn syntax(&self, role: Role) -> &str {
+            &self.syntax[role as usize]
+        }
+
+        /// `text` in `element`'s style.
+        pub fn paint(&self, element: Element, text: &str) -> String {
+            format!("{}{}{}", self.ui(element), text, RESET)
+        }
+    }
+}
+
+/// Key bindings: which named action each key, or chord of keys, runs in
+/// each mode.
+///
+/// Bindings live in scopes that follow the modes: `edit`, `command`,
+/// `normal`, `tree` and `find`, plus `global`, which applies wherever the
+/// mode's own scope has no binding for the keys (except in Find, where
+/// keys are mostly typed into the query). Keys without a binding keep
+/// their built-in meaning, such as inserting text.
+pub mod keymap {
+    use super::config::{Bindings, KeysConfig};
+    use super::core::{Mode, Motion};
+    use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+    use std::time::Duration;
+
+    /// How long a chord waits for its next key unless configured otherwise.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(1000);
+
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Scope {
+        Global,
+        Edit,
+        Command,
+        Normal,
+        Tree,
+        Find,
+    }
+
+    impl Scope {
+        /// The scope for keys typed in `mode`, if that mode's keys can be
+        /// rebound.
+        pub fn of(mode: Mode) -> Option<Self> {
+            Some(match mode {
+                Mode::Edit => Scope::Edit,
+                Mode::Command => Scope::Command,
+                Mode::Normal => Scope::Normal,
+                Mode::FileTree => Scope::Tree,
+                Mode::Find => Scope::Find,
+                _ => return None,
+            })
+        }
+
+        pub fn name(self) -> &'static str {
+            match self {
+                Scope::Global => "global",
+                Scope::Edit => "edit",
+                Scope::Command => "command",
+                Scope::Normal => "normal",
+                Scope::Tree => "tree",
+                Scope::Find => "find",
+            }
+        }
+    }
+
+    /// Something a key can be bound to.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Action {
+        SwitchPane,
+        NextTab,
+        PrevTab,
+        FilePicker,
+        Find,
+        Save,
+        Quit,
+        Undo,
+        Redo,
+        Copy,
+        Cut,
+        Paste,
+        /// Moves the cursor; with Shift added to the key, extends the
+        /// selection. Page motions are sized to the view when run.
+        Move(Motion),
+        TreeUp,
+        TreeDown,
+        TreeOpen,
+        TreeParent,
+        FindNext,
+        FindPrev,
+        ToggleRegex,
+        CycleCase,
+        ToggleWholeWord,
+        Replace,
+        /// Indents the selected lines, or inserts one level at the cursor.
+        Indent,
+        /// Removes one level from the selected lines or the cursor line.
+        Dedent,
+        /// Removes a default binding, giving the key its built-in meaning.
+        Unbound,
+    }
+
+    /// Every action by the name used in the config file.
+    const ACTIONS: &[(&str, Action)] = &[
+        ("pane.switch", Action::SwitchPane),
+        ("tab.next", Action::NextTab),
+        ("tab.prev", Action::PrevTab),
+        ("file_picker", Action::FilePicker),
+        ("find", Action::Find),
+        ("save", Action::Save),
+        ("quit", Action::Quit),
+        ("undo", Action::Undo),
+        ("redo", Action::Redo),
+        ("copy", Action::Copy),
+        ("cut", Action::Cut),
+        ("paste", Action::Paste),
+        ("move.left", Action::Move(Motion::Left)),
+        ("move.right", Action::Move(Motion::Right)),
+        ("move.up", Action::Move(Motion::Up)),
+        ("move.down", Action::Move(Motion::Down)),
+        ("move.word_forward", Action::Move(Motion::WordForward)),
+        ("move.word_backward", Action::Move(Motion::WordBackward)),
+        ("move.word_end", Action::Move(Motion::WordEnd)),
+        ("move.line_start", Action::Move(Motion::LineStart)),
+        ("move.line_end", Action::Move(Motion::LineEnd)),
+        ("move.first_non_blank", Action::Move(Motion::FirstNonBlank)),
+        ("move.home", Action::Move(Motion::Home)),
+        ("move.paragraph_forward", Action::Move(Motion::ParagraphForward)),
+        ("move.paragraph_backward", Action::Move(Motion::ParagraphBackward)),
+        ("move.page_up", Action::Move(Motion::PageUp(0))),
+        ("move.page_down", Action::Move(Motion::PageDown(0))),
+        ("move.document_start", Action::Move(Motion::DocumentStart)),
+        ("move.document_end", Action::Move(Motion::DocumentEnd)),
+        ("move.matching_bracket", Action::Move(Motion::MatchingBracket)),
+        ("tree.up", Action::TreeUp),
+        ("tree.down", Action::TreeDown),
+        ("tree.open", Action::TreeOpen),
+        ("tree.parent", Action::TreeParent),
+        ("find.next", Action::FindNext),
+        ("find.prev", Action::FindPrev),
+        ("find.toggle_regex", Action::ToggleRegex),
+        ("find.cycle_case", Action::CycleCase),
+        ("find.toggle_word", Action::ToggleWholeWord),
+        ("find.replace", Action::Replace),
+        ("indent", Action::Indent),
+        ("dedent", Action::Dedent),
+        ("none", Action::Unbound),
+    ];
+
+    /// The built-in bindings, as they would be written in the config file.
+    const DEFAULTS: &[(Scope, &str, &str)] = &[
+        (Scope::Global, "ctrl+p", "file_picker"),
+        (Scope::Global, "ctrl+w", "pane.switch"),
+        (Scope::Edit, "tab", "indent"),
+        (Scope::Edit, "shift+tab", "dedent"),
+        (Scope::Edit, "ctrl+c", "copy"),
+        (Scope::Edit, "ctrl+x", "cut"),
+        (Scope::Edit, "ctrl+v", "paste"),
+        (Scope::Edit, "ctrl+z", "undo"),
+        (Scope::Edit, "ctrl+y", "redo"),
+        (Scope::Edit, "left", "move.left"),
+        (Scope::Edit, "right", "move.right"),
+        (Scope::Edit, "up", "move.up"),
+        (Scope::Edit, "down", "move.down"),
+        (Scope::Edit, "ctrl+left", "move.word_backward"),
+        (Scope::Edit, "ctrl+right", "move.word_forward"),
+        (Scope::Edit, "ctrl+up", "move.paragraph_backward"),
+        (Scope::Edit, "ctrl+down", "move.paragraph_forward"),
+        (Scope::Edit, "home", "move.home"),
+        (Scope::Edit, "end", "move.line_end"),
+        (Scope::Edit, "ctrl+home", "move.document_start"),
+        (Scope::Edit, "ctrl+end", "move.document_end"),
+        (Scope::Edit, "pageup", "move.page_up"),
+        (Scope::Edit, "pagedown", "move.page_down"),
+        (Scope::Edit, "ctrl+b", "move.matching_bracket"),
+        (Scope::Command, "tab", "pane.switch"),
+        (Scope::Command, "left", "tab.prev"),
+        (Scope::Command, "right", "tab.next"),
+        (Scope::Normal, "tab", "pane.switch"),
+        (Scope::Tree, "tab", "pane.switch"),
+        (Scope::Tree, "up", "tree.up"),
+        (Scope::Tree, "k", "tree.up"),
+        (Scope::Tree, "down", "tree.down"),
+        (Scope::Tree, "j", "tree.down"),
+        (Scope::Tree, "right", "tree.open"),
+        (Scope::Tree, "l", "tree.open"),
+        (Scope::Tree, "left", "tree.parent"),
+        (Scope::Find, "n", "find.next"),
+        (Scope::Find, "N", "find.prev"),
+        (Scope::Find, "alt+r", "find.toggle_regex"),
+        (Scope::Find, "alt+c", "find.cycle_case"),
+        (Scope::Find, "alt+w", "find.toggle_word"),
+        (Scope::Find, "ctrl+r", "find.replace"),
+    ];
+
+    impl Action {
+        pub fn parse(name: &str) -> Option<Self> {
+            ACTIONS.iter().find(|(n, _)| *n == name).map(|&(_, action)| action)
+        }
+    }
+
+    /// A key and its modifiers. Shift is folded into chars, so `G` and
+    /// `shift+g` are the same key.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub struct Key {
+        pub code: KeyCode,
+        pub modifiers: KeyModifiers,
+    }
+
+    impl Key {
+        fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+            let mut modifiers =
+                modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT | KeyModifiers::SHIFT);
+            let code = match code {
+                KeyCode::Char(c) if modifiers.contains(KeyModifiers::SHIFT) => {
+                    modifiers.remove(KeyModifiers::SHIFT);
+                    KeyCode::Char(c.to_ascii_uppercase())
+                }
+                KeyCode::Tab if modifiers.contains(KeyModifiers::SHIFT) => {
+                    modifiers.remove(KeyModifiers::SHIFT);
+                    KeyCode::BackTab
+                }
+                KeyCode::BackTab => {
+                    modifiers.remove(KeyModifiers::SHIFT);
+                    KeyCode::BackTab
+                }
+                code => code,
+            };
+            Self { code, modifiers }
+        }
+
+        pub fn from_event(event: &KeyEvent) -> Self {
+            Self::new(event.code, event.modifiers)
+        }
+
+        pub fn to_event(self) -> KeyEvent {
+            KeyEvent::new(self.code, self.modifiers)
+        }
+
+        /// The same key without Shift, if it was held.
+        pub fn without_shift(self) -> Option<Self> {
+            self.modifiers.contains(KeyModifiers::SHIFT).then(|| Self {
+                code: self.code,
+                modifiers: self.modifiers - KeyModifiers::SHIFT,
+            })
+        }
+
+        /// Parses a key like `ctrl+s`, `alt+shift+left`, `tab`, `space` or `G`.
+        pub fn parse(spec: &str) -> Result<Self, String> {
+            let mut modifiers = KeyModifiers::NONE;
+            let mut name = spec;
+            // Split off modifiers, allowing `+` itself as the key, as in `ctrl++`.
+            while let Some((modifier, rest)) = name.split_once('+')
+                && !modifier.is_empty()
+                && !rest.is_empty()
+            {
+                modifiers |= match modifier.to_ascii_lowercase().as_str() {
+                    "ctrl" | "control" => KeyModifiers::CONTROL,
+                    "alt" | "meta" => KeyModifiers::ALT,
+                    "shift" => KeyModifiers::SHIFT,
+                    _ => return Err(format!("unknown modifier \"{}\" in \"{}\"", modifier, spec)),
+                };
+                name = rest;
             }
+            let mut chars = name.chars();
+            let code = match (chars.next(), chars.next()) {
+                (Some(c), None) => KeyCode::Char(c),
+                _ => match name.to_ascii_lowercase().as_str() {
+                    "tab" => KeyCode::Tab,
+                    "backtab" => KeyCode::BackTab,
+                    "enter" | "return" => KeyCode::Enter,
+                    "esc" | "escape" => KeyCode::Esc,
+                    "backspace" => KeyCode::Backspace,
+                    "delete" | "del" => KeyCode::Delete,
+                    "insert" => KeyCode::Insert,
+                    "left" => KeyCode::Left,
+                    "right" => KeyCode::Right,
+                    "up" => KeyCode::Up,
+                    "down" => KeyCode::Down,
+                    "home" => KeyCode::Home,
+                    "end" => KeyCode::End,
+                    "pageup" => KeyCode::PageUp,
+                    "pagedown" => KeyCode::PageDown,
+                    "space" => KeyCode::Char(' '),
+                    lower => match lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+                        Some(n) if (1..=12).contains(&n) => KeyCode::F(n),
+                        _ => return Err(format!("unknown key \"{}\"", spec)),
+                    },
+                },
+            };
+            Ok(Self::new(code, modifiers))
         }
-        
-        fn jump_to_match(&mut self) {
-            let match_coords = self.find_matches.get(self.current_match_index).copied();
-            if let Some((row, col)) = match_coords {
-                if let Some(page) = self.get_active_page() {
-                    page.move_cursor_to(row, col);
-                }
+    }
+
+    /// Parses a chord of space-separated keys, like `ctrl+k ctrl+d`.
+    pub fn parse_keys(spec: &str) -> Result<Vec<Key>, String> {
+        let keys = spec.split_whitespace().map(Key::parse).collect::<Result<Vec<_>, _>>()?;
+        if keys.is_empty() {
+            return Err("empty key binding".to_string());
+        }
+        Ok(keys)
+    }
+
+    /// What the keys typed so far are bound to.
+    #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+    pub enum Lookup {
+        Action(Action),
+        /// The keys begin a longer chord. If they are also bound on their
+        /// own, that action runs should the chord time out.
+        Prefix(Option<Action>),
+        /// Nothing is bound to the keys.
+        Unbound,
+    }
+
+    pub struct Keymap {
+        bindings: Vec<(Scope, Vec<Key>, Action)>,
+        pub timeout: Duration,
+    }
+
+    impl Default for Keymap {
+        fn default() -> Self {
+            let mut keymap = Self {
+                bindings: Vec::new(),
+                timeout: DEFAULT_TIMEOUT,
+            };
+            for &(scope, keys, action) in DEFAULTS {
+                let keys = parse_keys(keys).expect("default key binding parses");
+                let action = Action::parse(action).expect("default key binding names an action");
+                keymap.bind(scope, keys, action);
             }
+            keymap
         }
+    }
 
-        fn handle_prompt_event(&mut self, key_code: KeyCode) {
-            match key_code {
-                KeyCode::Esc => {
-                    self.status_message = "Save cancelled.".to_string();
-                    self.command_buffer.clear();
-                    self.mode = Mode::Command;
-                }
-                KeyCode::Char(c) => self.command_buffer.push(c),
-                KeyCode::Backspace => {
-                    self.command_buffer.pop();
-                }
-                KeyCode::Enter => {
-                    if !self.command_buffer.is_empty() {
-                        let file_name = self.command_buffer.clone();
-                        let mut path = self.directory_view.path.clone();
-                        path.push(file_name);
-
-                        let should_quit_after = self.mode == Mode::PromptSaveAndQuit;
-                        let content = self
-                            .get_active_page()
-                            .map(|p| p.get_all_lines().join("\n"))
-                            .unwrap_or_default();
+    impl Keymap {
+        /// Binds `keys` to `action` in `scope`, replacing any binding of the
+        /// same keys there.
+        pub fn bind(&mut self, scope: Scope, keys: Vec<Key>, action: Action) {
+            self.bindings.retain(|(s, k, _)| !(*s == scope && *k == keys));
+            self.bindings.push((scope, keys, action));
+        }
 
-                        match fs::write(&path, content) {
-                            Ok(_) => {
-                                if let Some(page) = self.get_active_page() {
-                                    page.file_path = Some(path.clone());
-                                }
-                                self.status_message = format!("Saved to {}", path.display());
-                                self.mode = Mode::Command;
-                                if should_quit_after {
-                                    self.should_quit = true;
-                                }
-                                // Refresh the directory view to show the new file.
-                                let current_dir_path = self.directory_view.path.clone();
-                                if let Ok(new_view) = DirectoryView::new(current_dir_path) {
-                                    self.directory_view = new_view;
-                                }
-                            }
-                            Err(e) => {
-                                self.status_message = format!("Error: {}", e);
-                                self.mode = Mode::Command;
-                            }
-                        }
-                        self.command_buffer.clear();
+        /// Applies the overrides in `config`, returning a message for each
+        /// binding that could not be understood.
+        pub fn configure(&mut self, config: &KeysConfig) -> Vec<String> {
+            if let Some(ms) = config.timeout_ms {
+                self.timeout = Duration::from_millis(ms);
+            }
+            let scopes: [(Scope, &Bindings); 6] = [
+                (Scope::Global, &config.global),
+                (Scope::Edit, &config.edit),
+                (Scope::Command, &config.command),
+                (Scope::Normal, &config.normal),
+                (Scope::Tree, &config.tree),
+                (Scope::Find, &config.find),
+            ];
+            let mut errors = Vec::new();
+            for (scope, bindings) in scopes {
+                for (keys, action) in bindings {
+                    let parsed = parse_keys(keys).and_then(|keys| {
+                        let action = Action::parse(action)
+                            .ok_or_else(|| format!("unknown action \"{}\"", action))?;
+                        Ok((keys, action))
+                    });
+                    match parsed {
+                        Ok((keys, action)) => self.bind(scope, keys, action),
+                        Err(e) => errors.push(format!("[keys.{}] \"{}\": {}", scope.name(), keys, e)),
                     }
                 }
-                _ => {}
             }
+            errors
         }
 
-        fn open_selected_entry(&mut self) {
-            if let Some(entry) = self.directory_view.entries.get(self.directory_view.selected_index)
-            {
-                let path = entry.path();
-                if path.is_dir() {
-                    self.directory_view = DirectoryView::new(path).unwrap_or_else(|e| {
-                        self.status_message = format!("Error: {}", e);
-                        DirectoryView::new(self.directory_view.path.clone()).unwrap()
-                    });
-                } else {
-                    // Check if the file is already open in a tab
-                    if let Some(index) = self
-                        .tabs
-                        .iter()
-                        .position(|p| p.file_path.as_ref() == Some(&path))
-                    {
-                        self.active_tab_index = index;
-                    } else {
-                        // If no tabs are open, replace the empty state.
-                        if self.tabs.is_empty() {
-                            self.tabs.push(Page::from_file(Some(path)));
-                            self.active_tab_index = 0;
-                        } else {
-                            // Otherwise, add a new tab.
-                            self.tabs.push(Page::from_file(Some(path)));
-                            self.active_tab_index = self.tabs.len() - 1;
-                        }
-                    }
-                    self.active_pane = ActivePane::Editor;
-                    self.mode = Mode::Edit;
+        /// Looks up `keys` in `scope`, falling back to the global scope.
+        pub fn lookup(&self, scope: Scope, keys: &[Key]) -> Lookup {
+            let mut scopes = vec![scope];
+            if scope != Scope::Find && scope != Scope::Global {
+                scopes.push(Scope::Global);
+            }
+            for scope in scopes {
+                let in_scope = || self.bindings.iter().filter(move |(s, _, _)| *s == scope);
+                let exact = in_scope().find(|(_, k, _)| k == keys).map(|(_, _, action)| *action);
+                let longer = in_scope().any(|(_, k, action)| {
+                    k.len() > keys.len() && k.starts_with(keys) && *action != Action::Unbound
+                });
+                match exact {
+                    _ if longer => return Lookup::Prefix(exact.filter(|a| *a != Action::Unbound)),
+                    Some(Action::Unbound) => return Lookup::Unbound,
+                    Some(action) => return Lookup::Action(action),
+                    None => {}
                 }
             }
+            Lookup::Unbound
         }
+    }
 
-        fn execute_command(&mut self) {
-            let cmd_line = self.command_buffer.clone();
-            let parts: Vec<&str> = cmd_line.split_whitespace().collect();
-            let command = parts.get(0).cloned().unwrap_or("");
-            let arg = parts.get(1).cloned();
+    #[cfg(test)]
+    mod tests {
+        use super::*;
 
-            match command {
-                "f" | "find" => {
-                    self.mode = Mode::Find;
-                    self.find_query.clear();
-                }
-                "q" | "quit" => {
-                    if !self.tabs.is_empty() {
-                        self.tabs.remove(self.active_tab_index);
-                    }
-                    if self.tabs.is_empty() {
-                        self.mode = Mode::Command;
-                        self.active_tab_index = 0;
-                    } else if self.active_tab_index >= self.tabs.len() {
-                        self.active_tab_index = self.tabs.len() - 1;
-                    }
-                }
-                "x" | "exit" => {
-                    self.should_quit = true;
-                }
-                "wx" => {
-                    let mut errors = Vec::new();
-                    for page in &self.tabs {
-                        if let Some(path) = &page.file_path {
-                            let content = page.get_all_lines().join("\n");
-                            if let Err(e) = fs::write(path, content) {
-                                errors.push(format!("{}: {}", path.display(), e));
-                            }
-                        }
-                    }
+        fn key(code: KeyCode, modifiers: KeyModifiers) -> Key {
+            Key { code, modifiers }
+        }
 
-                    if !errors.is_empty() {
-                        self.status_message = format!("Errors saving files: {}", errors.join(", "));
-                    } else {
-                        self.status_message = "All files saved.".to_string();
-                    }
-                    self.should_quit = true;
-                }
-                "h" | "help" => {
-                    self.status_message =
-                        "Help | Modes: Esc (Cmd/Edit), Tab (Dir) | Cmds: f, q, w, wq, x, wx, r | Dir Cmds: nf, nd, rn, d"
-                            .to_string();
-                }
-                "r" | "revert" => self.revert_active_file(),
-                "w" | "write" => { self.save_active_file(arg, false); },
-                "wq" => {
-                    if self.save_active_file(arg, false) {
-                        if !self.tabs.is_empty() {
-                            self.tabs.remove(self.active_tab_index);
-                        }
-                        if self.tabs.is_empty() {
-                            self.mode = Mode::Command;
-                            self.active_tab_index = 0;
-                        } else if self.active_tab_index >= self.tabs.len() {
-                            self.active_tab_index = self.tabs.len() - 1;
-                        }
-                    }
-                },
-                _ => self.status_message = format!("Unknown command: {}", cmd_line),
-            }
-            self.command_buffer.clear();
+        #[test]
+        fn parses_keys() {
+            assert_eq!(Key::parse("ctrl+s"), Ok(key(KeyCode::Char('s'), KeyModifiers::CONTROL)));
+            assert_eq!(
+                Key::parse("Alt+Shift+Left"),
+                Ok(key(KeyCode::Left, KeyModifiers::ALT | KeyModifiers::SHIFT))
+            );
+            assert_eq!(Key::parse("ctrl++"), Ok(key(KeyCode::Char('+'), KeyModifiers::CONTROL)));
+            assert_eq!(Key::parse("+"), Ok(key(KeyCode::Char('+'), KeyModifiers::NONE)));
+            assert_eq!(Key::parse("space"), Ok(key(KeyCode::Char(' '), KeyModifiers::NONE)));
+            assert_eq!(Key::parse("f12"), Ok(key(KeyCode::F(12), KeyModifiers::NONE)));
         }
 
-        fn revert_active_file(&mut self) {
-            let file_path = self.get_active_page().and_then(|p| p.file_path.clone());
-            if let Some(path) = file_path {
-                if let Ok(contents) = fs::read_to_string(&path) {
-                    if let Some(page) = self.get_active_page() {
-                        page.load_from_string(&contents);
-                        self.status_message = "Reverted to saved version.".to_string();
-                    }
-                } else {
-                    self.status_message = format!("Error reading file: {}", path.display());
-                }
-            } else {
-                self.status_message = "No file to revert from.".to_string();
-            }
+        #[test]
+        fn folds_shift() {
+            let g = key(KeyCode::Char('G'), KeyModifiers::NONE);
+            assert_eq!(Key::parse("G"), Ok(g));
+            assert_eq!(Key::parse("shift+g"), Ok(g));
+            let event = KeyEvent::new(KeyCode::Char('g'), KeyModifiers::SHIFT);
+            assert_eq!(Key::from_event(&event), g);
+            let backtab = key(KeyCode::BackTab, KeyModifiers::NONE);
+            assert_eq!(Key::parse("shift+tab"), Ok(backtab));
+            let event = KeyEvent::new(KeyCode::BackTab, KeyModifiers::SHIFT);
+            assert_eq!(Key::from_event(&event), backtab);
         }
 
-        fn save_active_file(&mut self, arg: Option<&str>, quit_after_app: bool) -> bool {
-            let path_from_arg = arg.map(PathBuf::from);
+        #[test]
+        fn rejects_bad_keys() {
+            let error = r#"unknown modifier "hyper" in "hyper+x""#;
+            assert_eq!(Key::parse("hyper+x"), Err(error.to_string()));
+            assert_eq!(Key::parse("ctrl+f13"), Err("unknown key \"ctrl+f13\"".to_string()));
+            assert_eq!(parse_keys("  "), Err("empty key binding".to_string()));
+        }
 
-            let path_from_page = self
-                .tabs
-                .get(self.active_tab_index)
-                .and_then(|p| p.file_path.clone());
+        #[test]
+        fn parses_chords() {
+            let chord = parse_keys("ctrl+k  ctrl+d").unwrap();
+            assert_eq!(
+                chord,
+                vec![
+                    key(KeyCode::Char('k'), KeyModifiers::CONTROL),
+                    key(KeyCode::Char('d'), KeyModifiers::CONTROL),
+                ]
+            );
+            assert!(parse_keys("ctrl+k nope").is_err());
+        }
 
-            let path_to_write = path_from_arg.or(path_from_page);
+        #[test]
+        fn looks_up_chords() {
+            let mut keymap = Keymap::default();
+            let chord = parse_keys("ctrl+k ctrl+d").unwrap();
+            keymap.bind(Scope::Edit, chord.clone(), Action::Cut);
+            assert_eq!(keymap.lookup(Scope::Edit, &chord), Lookup::Action(Action::Cut));
+            assert_eq!(keymap.lookup(Scope::Edit, &chord[..1]), Lookup::Prefix(None));
+            assert_eq!(keymap.lookup(Scope::Normal, &chord), Lookup::Unbound);
 
-            if let Some(path) = path_to_write {
-                let content = self
-                    .get_active_page()
-                    .map(|p| p.get_all_lines().join("\n"))
-                    .unwrap_or_default();
+            // A key bound on its own and as a prefix waits, then falls back.
+            let ctrl_c = parse_keys("ctrl+c").unwrap();
+            keymap.bind(Scope::Edit, parse_keys("ctrl+c ctrl+c").unwrap(), Action::Cut);
+            assert_eq!(keymap.lookup(Scope::Edit, &ctrl_c), Lookup::Prefix(Some(Action::Copy)));
 
-                match fs::write(&path, content) {
-                    Ok(_) => {
-                        self.status_message = format!("Saved to {}", path.display());
-                        if let Some(page) = self.get_active_page() {
-                            page.file_path = Some(path);
-                        }
-                        if quit_after_app {
-                            self.should_quit = true;
-                        }
-                        true
-                    }
-                    Err(e) => {
-                        self.status_message = format!("Error: {}", e);
-                        false
-                    }
-                }
-            } else {
-                self.mode = if quit_after_app {
-                    Mode::PromptSaveAndQuit
-                } else {
-                    Mode::PromptSave
-                };
-                self.command_buffer.clear();
-                false
-            }
+            // Global bindings apply in other scopes unless overridden there.
+            let ctrl_p = parse_keys("ctrl+p").unwrap();
+            assert_eq!(keymap.lookup(Scope::Normal, &ctrl_p), Lookup::Action(Action::FilePicker));
+            keymap.bind(Scope::Normal, ctrl_p.clone(), Action::Unbound);
+            assert_eq!(keymap.lookup(Scope::Normal, &ctrl_p), Lookup::Unbound);
         }
 
-        pub fn get_active_page(&mut self) -> Option<&mut Page> {
-            self.tabs.get_mut(self.active_tab_index)
+        #[test]
+        fn configures_bindings() {
+            let mut config = KeysConfig { timeout_ms: Some(250), ..KeysConfig::default() };
+            config.edit.insert("ctrl+k ctrl+d".to_string(), "cut".to_string());
+            config.edit.insert("tab".to_string(), "none".to_string());
+            config.find.insert("ctrl+q".to_string(), "fly".to_string());
+            config.tree.insert("hyper+x".to_string(), "quit".to_string());
+
+            let mut keymap = Keymap::default();
+            let errors = keymap.configure(&config);
+            assert_eq!(
+                errors,
+                vec![
+                    r#"[keys.tree] "hyper+x": unknown modifier "hyper" in "hyper+x""#.to_string(),
+                    r#"[keys.find] "ctrl+q": unknown action "fly""#.to_string(),
+                ]
+            );
+            assert_eq!(keymap.timeout, Duration::from_millis(250));
+            let chord = parse_keys("ctrl+k ctrl+d").unwrap();
+            assert_eq!(keymap.lookup(Scope::Edit, &chord), Lookup::Action(Action::Cut));
+            assert_eq!(keymap.lookup(Scope::Edit, &parse_keys("tab").unwrap()), Lookup::Unbound);
         }
     }
 }
@@ -1086,7 +8091,13 @@ pub mod core {
 /// All UI drawing and rendering logic.
 pub mod ui {
     use super::*;
-    use self::core::{ActivePane, App, Mode};
+    use self::core::{ActivePane, App, FilePicker, ListView, Mode, PendingAction};
+    use self::settings::LineNumbers;
+    use self::syntax::Span;
+    use self::theme::{Element, RESET, Theme};
+
+    /// Drawn in the gutter of the rows a wrapped line continues on.
+    const WRAP_MARKER: &str = "↪";
 
     const LOGO: &[&str] = &[
         "JJJJJJJ   OOOOO   TTTTTTT",
@@ -1101,12 +8112,12 @@ pub mod ui {
         queue!(stdout, Clear(ClearType::All))?;
 
         let view_height = height.saturating_sub(1);
-        let file_tree_width = (width as f32 * 0.25).round() as u16;
+        let file_tree_width = app.settings.tree_width(width);
         let editor_width = width.saturating_sub(file_tree_width);
         let divider_col = file_tree_width;
 
         draw_file_tree(stdout, app, file_tree_width, view_height)?;
-        draw_divider(stdout, divider_col, view_height)?;
+        draw_divider(stdout, &app.theme, divider_col, view_height)?;
         draw_editor(
             stdout,
             app,
@@ -1114,12 +8125,123 @@ pub mod ui {
             editor_width.saturating_sub(1),
             view_height,
         )?;
+        if app.mode == Mode::GrepResults
+            && let Some(grep) = &app.grep
+        {
+            let title = format!(
+                "grep {}: {} of {}{}",
+                grep.query,
+                grep.list.selected_index + 1,
+                grep.hits.len(),
+                if grep.truncated { "+" } else { "" }
+            );
+            draw_list_overlay(
+                stdout,
+                &app.theme,
+                &title,
+                &grep.list,
+                (divider_col + 1, 1),
+                editor_width.saturating_sub(1),
+                view_height.saturating_sub(1),
+            )?;
+        }
+        if app.mode == Mode::FilePicker
+            && let Some(picker) = &app.file_picker
+        {
+            draw_file_picker(
+                stdout,
+                &app.theme,
+                picker,
+                divider_col + 1,
+                editor_width.saturating_sub(1),
+                height,
+                app.settings.tab_width,
+            )?;
+        }
         draw_status_bar(stdout, app, width, height)?;
         place_cursor(stdout, app, divider_col + 1, height)?;
 
         stdout.flush()
     }
 
+    /// Draws `list` in the `width` x `height` box at `(x, y)`, covering
+    /// whatever was drawn there, with `title` on the first row.
+    fn draw_list_overlay(
+        stdout: &mut io::Stdout,
+        theme: &Theme,
+        title: &str,
+        list: &ListView,
+        (x, y): (u16, u16),
+        width: u16,
+        height: u16,
+    ) -> io::Result<()> {
+        let width = width as usize;
+        queue!(stdout, MoveTo(x, y))?;
+        queue!(
+            stdout,
+            crossterm::style::Print(
+                theme.paint(Element::Title, &text::fit_width(&format!(" {}", title), width))
+            )
+        )?;
+        for row in 0..height.saturating_sub(1) as usize {
+            let index = list.scroll_offset + row;
+            let line = list.items.get(index).map_or(String::new(), |item| format!(" {}", item));
+            let line = text::fit_width(&line, width);
+            queue!(stdout, MoveTo(x, y + 1 + row as u16))?;
+            if index == list.selected_index {
+                queue!(stdout, crossterm::style::Print(theme.paint(Element::ListSelected, &line)))?;
+            } else {
+                queue!(stdout, crossterm::style::Print(line))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Draws the file picker over the editor area: the ranked matches on
+    /// top and a preview of the highlighted file below.
+    fn draw_file_picker(
+        stdout: &mut io::Stdout,
+        theme: &Theme,
+        picker: &FilePicker,
+        x: u16,
+        width: u16,
+        term_height: u16,
+        tab_width: usize,
+    ) -> io::Result<()> {
+        let list_height = core::picker_list_height(term_height);
+        let title = format!(
+            "Open: {}  ({} of {})",
+            picker.query,
+            picker.matches.len(),
+            picker.files.len()
+        );
+        draw_list_overlay(stdout, theme, &title, &picker.list, (x, 1), width, list_height)?;
+
+        let preview_top = 1 + list_height;
+        let preview_height = term_height.saturating_sub(1 + preview_top);
+        let name = picker.list.items.get(picker.list.selected_index).cloned().unwrap_or_default();
+        queue!(stdout, MoveTo(x, preview_top))?;
+        queue!(
+            stdout,
+            crossterm::style::Print(
+                theme.paint(Element::Title, &text::fit_width(&format!(" {}", name), width as usize))
+            )
+        )?;
+        for row in 0..preview_height.saturating_sub(1) {
+            let line = picker.preview.get(row as usize).map_or("", String::as_str);
+            queue!(stdout, MoveTo(x, preview_top + 1 + row))?;
+            queue!(
+                stdout,
+                crossterm::style::Print(format!(
+                    " {}",
+                    render_line(theme, line, 0, (width as usize).saturating_sub(1), &[], &[], tab_width)
+                )),
+                Clear(ClearType::UntilNewLine)
+            )?;
+        }
+        Ok(())
+    }
+
     fn wrap_text(text: &str, width: usize) -> Vec<String> {
         if width == 0 {
             return vec![text.to_string()];
@@ -1131,6 +8253,62 @@ pub mod ui {
             .collect()
     }
 
+    /// Renders the part of `line` visible between display columns `offset`
+    /// and `offset + width`, expanding tabs to stops every `tab_width`
+    /// cells, styling the char ranges of `syntax` by role and drawing those
+    /// in `highlights` over them in the given element's style. Wide
+    /// graphemes cut by either edge are replaced by spaces so the columns
+    /// stay aligned.
+    fn render_line(
+        theme: &Theme,
+        line: &str,
+        offset: usize,
+        width: usize,
+        highlights: &[(usize, usize, Element)],
+        syntax: &[Span],
+        tab_width: usize,
+    ) -> String {
+        let mut out = String::new();
+        let mut display = 0;
+        let mut style = (None, None);
+        let mut spans = syntax.iter().peekable();
+        let end = offset + width;
+        for (col, g) in text::graphemes(line) {
+            if display >= end {
+                break;
+            }
+            let g_width = text::grapheme_width(g, display, tab_width);
+            let g_end = display + g_width;
+            if g_end <= offset {
+                display = g_end;
+                continue;
+            }
+            while spans.next_if(|span| span.end <= col).is_some() {}
+            let role = spans.peek().filter(|span| span.start <= col).map(|span| span.role);
+            let highlight = highlights
+                .iter()
+                .rfind(|&&(s, e, _)| col >= s && col < e)
+                .map(|&(_, _, element)| element);
+            if (role, highlight) != style {
+                out.push_str(RESET);
+                out.push_str(role.map_or("", |role| theme.syntax(role)));
+                out.push_str(highlight.map_or("", |element| theme.ui(element)));
+                style = (role, highlight);
+            }
+            if display < offset || g_end > end {
+                let visible = g_end.min(end) - display.max(offset);
+                out.push_str(&" ".repeat(visible));
+            } else {
+                out.push_str(&text::grapheme_display(g, g_width));
+            }
+            display = g_end;
+        }
+        if style != (None, None) {
+            out.push_str(RESET);
+        }
+        out
+    }
+
     fn draw_file_tree(
         stdout: &mut io::Stdout,
         app: &App,
@@ -1144,10 +8322,9 @@ pub mod ui {
             queue!(stdout, MoveTo(0, i as u16))?;
             queue!(
                 stdout,
-                crossterm::style::Print(format!(
-                    "\x1b[4m\x1b[1m{:width$}\x1b[0m",
-                    line,
-                    width = width as usize
+                crossterm::style::Print(app.theme.paint(
+                    Element::Title,
+                    &format!("{:width$}", line, width = width as usize)
                 ))
             )?;
         }
@@ -1167,44 +8344,28 @@ pub mod ui {
             let screen_row = (i - app.directory_view.scroll_offset + title_height) as u16;
             queue!(stdout, MoveTo(0, screen_row))?;
             let mut name = entry.file_name().to_string_lossy().to_string();
-            if entry.path().is_dir() {
+            let is_dir = entry.path().is_dir();
+            if is_dir {
                 name.push('/');
             }
-            let line = format!(" {}", name);
+            let line = text::fit_width(&format!(" {}", name), width as usize);
 
-            if i == app.directory_view.selected_index {
-                let style = if app.active_pane == ActivePane::FileTree {
-                    "\x1b[7m"
-                } else {
-                    "\x1b[2m"
-                }; // Inverse or Dim
-                queue!(
-                    stdout,
-                    crossterm::style::Print(format!(
-                        "{}{:width$}\x1b[0m",
-                        style,
-                        line.chars().take(width as usize).collect::<String>(),
-                        width = width as usize
-                    ))
-                )?;
+            let element = if i != app.directory_view.selected_index {
+                if is_dir { Element::Directory } else { Element::DirectoryEntry }
+            } else if app.active_pane == ActivePane::FileTree {
+                Element::TreeSelected
             } else {
-                queue!(
-                    stdout,
-                    crossterm::style::Print(format!(
-                        "{:width$}",
-                        line.chars().take(width as usize).collect::<String>(),
-                        width = width as usize
-                    ))
-                )?;
-            }
+                Element::TreeSelectedUnfocused
+            };
+            queue!(stdout, crossterm::style::Print(app.theme.paint(element, &line)))?;
         }
         Ok(())
     }
 
-    fn draw_divider(stdout: &mut io::Stdout, col: u16, height: u16) -> io::Result<()> {
+    fn draw_divider(stdout: &mut io::Stdout, theme: &Theme, col: u16, height: u16) -> io::Result<()> {
         for row in 0..height {
             queue!(stdout, MoveTo(col, row))?;
-            queue!(stdout, crossterm::style::Print("│"))?;
+            queue!(stdout, crossterm::style::Print(theme.paint(Element::Divider, "│")))?;
         }
         Ok(())
     }
@@ -1233,81 +8394,107 @@ pub mod ui {
             // Draw tab bar at the top of the editor pane
             queue!(stdout, MoveTo(start_col, 0))?;
             for (i, page) in app.tabs.iter().enumerate() {
-                let file_name = page
-                    .file_path
-                    .as_ref()
-                    .and_then(|p| p.file_name())
-                    .and_then(|f| f.to_str())
-                    .unwrap_or("[No Name]");
-                let tab_text = format!(" {} ", file_name);
-                if i == app.active_tab_index {
-                    queue!(
-                        stdout,
-                        crossterm::style::Print(format!("\x1b[7m{}\x1b[0m", tab_text))
-                    )?;
+                let tab_text = page.tab_title();
+                let element = if i == app.active_tab_index {
+                    Element::TabActive
                 } else {
-                    queue!(
-                        stdout,
-                        crossterm::style::Print(format!("\x1b[2m{}\x1b[0m", tab_text))
-                    )?;
-                }
+                    Element::TabInactive
+                };
+                queue!(stdout, crossterm::style::Print(app.theme.paint(element, &tab_text)))?;
             }
 
             // Draw active page content below the tab bar
             if let Some(page) = app.tabs.get(app.active_tab_index) {
                 let view_height = height.saturating_sub(1) as usize;
-                let line_gutter_width = page.get_all_lines().len().to_string().len() + 1;
+                let line_gutter_width = app.settings.gutter_width(page.line_count()) as usize;
+                let cursor_row = page.cursor_row();
 
-                let visible_lines = page
-                    .get_all_lines()
-                    .into_iter()
-                    .enumerate()
-                    .skip(page.scroll_offset)
-                    .take(view_height);
-                
-                let matches_on_screen: Vec<_> = app.find_matches.iter().filter(|(r, _)| *r >= page.scroll_offset && *r < page.scroll_offset + view_height).collect();
+                let matching_bracket = matches!(app.mode, Mode::Edit | Mode::Normal | Mode::Visual)
+                    .then(|| page.matching_bracket())
+                    .flatten()
+                    .map(|(_, partner)| page.char_to_pos(partner));
+
+                let matches_on_screen: Vec<_> = app.find_matches.iter().filter(|m| m.row >= page.scroll_offset && m.row < page.scroll_offset + view_height).collect();
 
-                for (i, line) in visible_lines {
-                    let screen_row = (i - page.scroll_offset) as u16 + 1;
-                    queue!(stdout, MoveTo(start_col, screen_row))?;
-                    let line_num_str = format!("{:>width$}", i + 1, width = line_gutter_width);
-                    
+                let mut screen_row = 1;
+                let mut i = page.scroll_offset;
+                while screen_row <= view_height && i < page.line_count() {
+                    let line = page.line(i);
                     let h_scroll_offset = page.horizontal_scroll_offset;
 
-                    queue!(
-                        stdout,
-                        crossterm::style::Print(format!("\x1b[34m{} \x1b[0m", line_num_str))
-                    )?;
+                    let mut highlights: Vec<_> = page
+                        .selection_on_row(i)
+                        .map(|(start, end)| (start, end, Element::Selection))
+                        .into_iter()
+                        .collect();
+                    if let Some((row, col)) = matching_bracket
+                        && row == i
+                    {
+                        highlights.push((col, col + 1, Element::MatchingBracket));
+                    }
+                    if matches!(app.mode, Mode::Find | Mode::ConfirmReplace) {
+                        highlights.extend(
+                            matches_on_screen
+                                .iter()
+                                .filter(|m| m.row == i)
+                                .map(|m| (m.start, m.end, Element::SearchMatch)),
+                        );
+                    }
+                    let spans = page.highlights(i);
+                    let text_width = (width as usize).saturating_sub(line_gutter_width);
 
-                    if app.mode == Mode::Find && !app.find_query.is_empty() {
-                        let line_matches: Vec<_> = matches_on_screen.iter().filter(|(r, _)| *r == i).collect();
-                        let mut last_end = 0;
-                        let mut highlighted_line = String::new();
+                    // Without wrapping there is a single row starting at column 0.
+                    let starts = page.wrap_starts(i);
+                    for (sub_row, &start) in starts.iter().enumerate() {
+                        if screen_row > view_height {
+                            break;
+                        }
+                        queue!(stdout, MoveTo(start_col, screen_row as u16))?;
 
-                        for (_, col) in line_matches {
-                            if *col >= last_end {
-                                if let Some(substring) = line.get(last_end..*col) {
-                                    highlighted_line.push_str(substring);
+                        if line_gutter_width > 0 {
+                            let label = if sub_row > 0 {
+                                WRAP_MARKER.to_string()
+                            } else {
+                                match app.settings.line_numbers {
+                                    LineNumbers::Relative if i != cursor_row => i.abs_diff(cursor_row),
+                                    _ => i + 1,
                                 }
-                                highlighted_line.push_str("\x1b[7m");
-                                if let Some(match_str) = line.get(*col..*col + app.find_query.len()) {
-                                    highlighted_line.push_str(match_str);
-                                }
-                                highlighted_line.push_str("\x1b[0m");
-                                last_end = *col + app.find_query.len();
-                            }
+                                .to_string()
+                            };
+                            let line_num_str =
+                                format!("{:>width$}", label, width = line_gutter_width - 1);
+                            queue!(
+                                stdout,
+                                crossterm::style::Print(
+                                    app.theme.paint(Element::Gutter, &format!("{} ", line_num_str))
+                                )
+                            )?;
                         }
-                        if let Some(remaining) = line.get(last_end..) {
-                            highlighted_line.push_str(remaining);
-                        }
-                        
-                        let visible_highlighted: String = highlighted_line.chars().skip(h_scroll_offset).collect();
-                        queue!(stdout, crossterm::style::Print(visible_highlighted))?;
 
-                    } else {
-                        let visible_line: String = line.chars().skip(h_scroll_offset).collect();
-                         queue!(stdout, crossterm::style::Print(visible_line))?;
+                        let rendered = if page.wrap_width.is_some() {
+                            let end = starts.get(sub_row + 1).copied().unwrap_or(usize::MAX);
+                            let clip = |s: usize, e: usize| {
+                                (e > start && s < end).then(|| (s.max(start) - start, e.min(end) - start))
+                            };
+                            let highlights: Vec<_> = highlights
+                                .iter()
+                                .filter_map(|&(s, e, element)| clip(s, e).map(|(s, e)| (s, e, element)))
+                                .collect();
+                            let spans: Vec<_> = spans
+                                .iter()
+                                .filter_map(|span| {
+                                    clip(span.start, span.end).map(|(start, end)| Span { start, end, ..*span })
+                                })
+                                .collect();
+                            let segment = text::slice_cols(&line, start, end);
+                            render_line(&app.theme, segment, 0, text_width, &highlights, &spans, page.tab_width)
+                        } else {
+                            render_line(&app.theme, &line, h_scroll_offset, text_width, &highlights, &spans, page.tab_width)
+                        };
+                        queue!(stdout, crossterm::style::Print(rendered))?;
+                        screen_row += 1;
                     }
+                    i += 1;
                 }
             }
         }
@@ -1342,9 +8529,84 @@ pub mod ui {
                     let file_name = app.path_to_delete.as_ref().and_then(|p| p.file_name()).and_then(|f| f.to_str()).unwrap_or_default();
                     format!("Delete {}? (y/n)", file_name)
                 },
+                Mode::ConfirmReload => {
+                    let conflict = app.disk_conflict.as_ref();
+                    let name = conflict
+                        .and_then(|c| app.tabs.get(c.tab_index))
+                        .map(|p| p.display_name())
+                        .unwrap_or_default();
+                    let what = if conflict.is_some_and(|c| c.deleted) { "was deleted" } else { "changed" };
+                    format!(
+                        "{} {} on disk: (r)eload, (o)verwrite, (d)iff, (k)eep buffer",
+                        name, what
+                    )
+                },
+                Mode::FilePicker => {
+                    "Open: type to filter, arrows to move, Enter to open, Esc to cancel".to_string()
+                },
+                Mode::GrepResults => {
+                    "Grep: j/k or arrows to move, Enter to open, Esc to close".to_string()
+                },
+                Mode::PromptReplace => {
+                    format!("Replace \"{}\" with: {}", app.find_query, app.command_buffer)
+                },
+                Mode::ConfirmReplace => {
+                    let replacement = app
+                        .replace_session
+                        .as_ref()
+                        .and_then(|s| s.pending.get(app.current_match_index))
+                        .map(|(_, _, text)| text.as_str())
+                        .unwrap_or_default();
+                    format!(
+                        "Replace with \"{}\"? (y)es, (n)o, (a)ll, (q)uit  {} of {}",
+                        replacement,
+                        app.current_match_index + 1,
+                        app.find_matches.len()
+                    )
+                },
+                Mode::ConfirmRecover => {
+                    let name = app
+                        .recovery
+                        .as_ref()
+                        .and_then(|r| app.tabs.get(r.tab_index))
+                        .map(|p| p.display_name())
+                        .unwrap_or_default();
+                    format!(
+                        "Swap file found for {}: (r)ecover, (d)iff, (D)iscard, Esc to decide later",
+                        name
+                    )
+                },
+                Mode::ConfirmDiscard => match app.pending_action {
+                    Some(PendingAction::Quit) => format!(
+                        "{} tab(s) have unsaved changes. Quit anyway? (y/n)",
+                        app.modified_tab_count()
+                    ),
+                    Some(PendingAction::Delete) => format!(
+                        "{} open tab(s) have unsaved changes that deleting will discard. Delete anyway? (y/n)",
+                        app.path_to_delete.as_ref().map_or(0, |p| app.modified_tabs_under(p))
+                    ),
+                    _ => {
+                        let name = app
+                            .tabs
+                            .get(app.active_tab_index)
+                            .map(|p| p.display_name())
+                            .unwrap_or_default();
+                        format!("Discard unsaved changes to {}? (y/n)", name)
+                    }
+                },
                 Mode::Command => {
                     format!("-- COMMAND -- :{}", app.command_buffer)
                 },
+                Mode::Visual => {
+                    let linewise = app
+                        .tabs
+                        .get(app.active_tab_index)
+                        .is_some_and(|p| p.is_linewise_selection());
+                    format!(
+                        "-- VISUAL{} -- y copy, d cut, p paste, : command, Esc cancel",
+                        if linewise { " LINE" } else { "" }
+                    )
+                },
                 Mode::FileTree => {
                     if !app.command_buffer.is_empty() {
                         format!("-- DIR COMMAND -- :{}", app.command_buffer)
@@ -1353,65 +8615,106 @@ pub mod ui {
                     }
                 },
                 Mode::Find => {
+                    let mut label = if app.find_navigation_active {
+                        "Find (Nav)"
+                    } else if app.find_then_replace {
+                        "Find (to replace)"
+                    } else {
+                        "Find"
+                    }
+                    .to_string();
+                    let options = app.find_options.describe();
+                    if !options.is_empty() {
+                        label = format!("{} {}", label, options);
+                    }
+                    let counter = if let Some(error) = &app.find_error {
+                        error.clone()
+                    } else if app.find_query.is_empty() {
+                        "Alt+r regex, Alt+c case, Alt+w word".to_string()
+                    } else if app.find_matches.is_empty() {
+                        "no matches".to_string()
+                    } else {
+                        format!("{} of {}", app.current_match_index + 1, app.find_matches.len())
+                    };
                     if app.find_navigation_active {
-                        format!("Find (Nav): {} (n/N)", app.find_query)
+                        format!("{}: {} (n/N)  {}", label, app.find_query, counter)
                     } else {
-                        format!("Find: {}", app.find_query)
+                        format!("{}: {}  {}", label, app.find_query, counter)
                     }
                 },
-                Mode::Edit => {
-                    let file_info = app
-                        .tabs
-                        .get(app.active_tab_index)
-                        .map(|p| {
-                            p.file_path
-                                .as_ref()
-                                .map(|path| path.to_string_lossy().to_string())
-                                .unwrap_or_else(|| "[No Name]".to_string())
-                        })
-                        .unwrap_or_else(|| "".to_string());
-                    format!("-- INSERT -- {}", file_info)
-                }
+                Mode::Edit => format!("-- INSERT -- {}", file_info(app)),
+                Mode::Normal => format!("-- NORMAL -- {}  {}", file_info(app), app.normal_keys),
             }
         };
 
         queue!(
             stdout,
-            crossterm::style::Print(format!(
-                "\x1b[7m{:width$}\x1b[0m",
-                status_text.chars().take(width as usize).collect::<String>(),
-                width = width as usize
-            ))
+            crossterm::style::Print(
+                app.theme.paint(Element::StatusBar, &text::fit_width(&status_text, width as usize))
+            )
         )?;
         Ok(())
     }
 
+    /// The active file's name and state, for the status bar.
+    fn file_info(app: &App) -> String {
+        app.tabs
+            .get(app.active_tab_index)
+            .map(|p| {
+                let name = p
+                    .file_path
+                    .as_ref()
+                    .map(|path| path.to_string_lossy().to_string())
+                    .unwrap_or_else(|| "[No Name]".to_string());
+                let modified = if p.is_modified() { " [+]" } else { "" };
+                let read_only = if p.read_only { " [read-only]" } else { "" };
+                format!("{}{} [{}]{}", name, modified, p.format.describe(), read_only)
+            })
+            .unwrap_or_default()
+    }
+
     fn place_cursor(
         stdout: &mut io::Stdout,
         app: &App,
         editor_start_col: u16,
         term_height: u16,
     ) -> io::Result<()> {
-        if app.active_pane == ActivePane::Editor && app.mode == Mode::Edit {
-            if let Some(page) = app.tabs.get(app.active_tab_index) {
-                let cursor_row = page.cursor_row();
-                let scroll_offset = page.scroll_offset;
-                let view_height = term_height.saturating_sub(2) as usize; // for tabs and status bar
-
-                // Only place cursor if it's within the visible part of the editor view
-                if cursor_row >= scroll_offset && cursor_row < scroll_offset + view_height {
-                    let line_gutter_width = page.get_all_lines().len().to_string().len() + 2;
-                    let cursor_col_in_string = page.current.cursor_position();
-                    let h_scroll_offset = page.horizontal_scroll_offset;
-                    
-                    let screen_cursor_col = editor_start_col
-                        + (cursor_col_in_string - h_scroll_offset) as u16
-                        + line_gutter_width as u16;
+        if app.active_pane == ActivePane::Editor
+            && matches!(app.mode, Mode::Edit | Mode::Visual | Mode::Normal)
+            && let Some(page) = app.tabs.get(app.active_tab_index)
+        {
+            let cursor_row = page.cursor_row();
+            let scroll_offset = page.scroll_offset;
+            let view_height = term_height.saturating_sub(2) as usize; // for tabs and status bar
 
-                    // Calculate screen row relative to scroll offset
-                    let screen_row = (cursor_row - scroll_offset) as u16 + 1; // +1 for tab bar
-                    queue!(stdout, MoveTo(screen_cursor_col, screen_row))?;
+            if page.wrap_width.is_some() {
+                if cursor_row < scroll_offset {
+                    return Ok(());
+                }
+                let (sub_row, display_col) = page.cursor_visual();
+                let rows_above: usize = (scroll_offset..cursor_row).map(|r| page.visual_rows(r)).sum();
+                if rows_above + sub_row < view_height {
+                    let screen_col = editor_start_col
+                        + app.settings.gutter_width(page.line_count())
+                        + display_col as u16;
+                    queue!(stdout, MoveTo(screen_col, (rows_above + sub_row) as u16 + 1))?;
                 }
+                return Ok(());
+            }
+
+            // Only place cursor if it's within the visible part of the editor view
+            if cursor_row >= scroll_offset && cursor_row < scroll_offset + view_height {
+                let line_gutter_width = app.settings.gutter_width(page.line_count());
+                let cursor_display_col = page.cursor_display_col();
+                let h_scroll_offset = page.horizontal_scroll_offset;
+                
+                let screen_cursor_col = editor_start_col
+                    + cursor_display_col.saturating_sub(h_scroll_offset) as u16
+                    + line_gutter_width;
+
+                // Calculate screen row relative to scroll offset
+                let screen_row = (cursor_row - scroll_offset) as u16 + 1; // +1 for tab bar
+                queue!(stdout, MoveTo(screen_cursor_col, screen_row))?;
             }
         }
         // In FileTree and Command panes, the "cursor" is not shown.
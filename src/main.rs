@@ -1,17 +1,54 @@
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture},
+    event::{
+        self, DisableBracketedPaste, DisableFocusChange, DisableMouseCapture, EnableBracketedPaste,
+        EnableFocusChange, EnableMouseCapture,
+    },
     execute,
     terminal::{
         disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen,
     },
 };
 use jot::{core::App, ui};
-use std::{env, io, path::PathBuf};
+use std::{
+    env, io,
+    panic::{self, AssertUnwindSafe},
+    path::PathBuf,
+    time::Duration,
+};
+
+/// How long to wait for input before giving the app a chance to write swap
+/// files and time out chords.
+const TICK: Duration = Duration::from_millis(100);
+
+fn restore_terminal() {
+    let _ = execute!(
+        io::stdout(),
+        LeaveAlternateScreen,
+        DisableMouseCapture,
+        DisableFocusChange,
+        DisableBracketedPaste
+    );
+    let _ = disable_raw_mode();
+}
 
 fn main() -> io::Result<()> {
+    // Leave the alternate screen before the panic message is printed, so it
+    // stays readable.
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        default_hook(info);
+    }));
+
     enable_raw_mode()?;
     let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
+    execute!(
+        stdout,
+        EnterAlternateScreen,
+        EnableMouseCapture,
+        EnableFocusChange,
+        EnableBracketedPaste
+    )?;
 
     let args: Vec<String> = env::args().collect();
     let file_path = args.get(1).map(PathBuf::from);
@@ -20,17 +57,29 @@ fn main() -> io::Result<()> {
     loop {
         ui::draw_ui(&mut stdout, &app)?;
 
+        let mut redraw = false;
+        while !redraw && !event::poll(TICK)? {
+            redraw = app.tick();
+        }
+        if redraw {
+            continue;
+        }
         let event = event::read()?;
         let (width, height) = crossterm::terminal::size()?;
-        app.handle_event(event, width, height);
+        // On a crash, get unsaved changes into swap files before going down.
+        if let Err(payload) =
+            panic::catch_unwind(AssertUnwindSafe(|| app.handle_event(event, width, height)))
+        {
+            app.write_swap_files();
+            panic::resume_unwind(payload);
+        }
 
         if app.should_quit {
             break;
         }
+        app.tick();
     }
 
-    execute!(stdout, LeaveAlternateScreen, DisableMouseCapture)?;
-    disable_raw_mode()?;
+    restore_terminal();
     Ok(())
 }
-